@@ -0,0 +1,75 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Exponential backoff with full jitter: after the nth consecutive failure,
+/// wait a random time between zero and `min(cap, base * 2^n)`. The jitter
+/// keeps a fleet of clients that failed at the same time from retrying in
+/// lockstep.
+pub struct Backoff {
+    base: u64,
+    cap: u64,
+    failures: u32,
+    rng: u64,
+}
+
+impl Backoff {
+    pub fn new(base: u64, cap: u64) -> Self {
+        // Seeded from the randomly keyed std hasher, to avoid pulling in a
+        // dependency just for this
+        let seed = RandomState::new().build_hasher().finish();
+        Self {
+            base,
+            cap,
+            failures: 0,
+            rng: seed | 1,
+        }
+    }
+
+    /// The upper bound of the next delay
+    pub fn ceiling(&self) -> u64 {
+        let factor = 1u64.checked_shl(self.failures).unwrap_or(u64::MAX);
+        self.base.saturating_mul(factor).min(self.cap)
+    }
+
+    /// Number of consecutive failures so far
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Registers a failure and returns the number of seconds to wait before
+    /// retrying. The delay is always at least one second.
+    pub fn next_delay(&mut self) -> u64 {
+        let ceiling = self.ceiling();
+        self.failures = self.failures.saturating_add(1);
+        (self.next_random() % ceiling.saturating_add(1)).max(1)
+    }
+
+    /// Registers a success, so that the next failure starts from `base`
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    // xorshift64
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
@@ -0,0 +1,133 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::types::Hostname;
+
+pub const USAGE: &str = "\
+Usage: dyfi-client [OPTIONS]
+
+Configuration is read from environment variables and an optional .env file.
+
+Options:
+      --hostname <HOST>  Update HOST instead of the hostnames in
+                         DYFI_HOSTNAMES. May be given multiple times.
+      --set <KEY=VALUE>  Set the environment variable KEY to VALUE for
+                         the client only, overriding the environment and
+                         the .env file. May be given multiple times.
+      --query            Show the records dy.fi currently has and exit
+      --plan             Show which hostnames the next check would
+                         update and why, without sending anything, and
+                         exit
+      --dump-state       Print the client's state as JSON and exit. The
+                         password is never included.
+      --once             Check once, update if needed and exit
+      --test-notify      Send the configured notifiers a made-up update,
+                         report how each one fared and exit
+      --offline          Release the hostnames with an offline request
+                         and exit
+  -v, --verbose          Log more: -v for info, -vv for debug, -vvv for
+                         trace. Overrides RUST_LOG for the client's own
+                         messages.
+  -q, --quiet            Only log errors, overriding RUST_LOG
+  -h, --help             Show this help and exit";
+
+/// Options given on the command line. These take precedence over the
+/// environment.
+#[derive(Debug, Default, PartialEq)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Args {
+    pub hostnames: Vec<Hostname>,
+    /// Settings given with `--set`, by upper-case name
+    pub settings: Vec<(String, String)>,
+    pub query: bool,
+    pub plan: bool,
+    pub dump_state: bool,
+    pub once: bool,
+    pub test_notify: bool,
+    pub offline: bool,
+    /// Number of `-v` flags
+    pub verbose: u8,
+    pub quiet: bool,
+    pub help: bool,
+}
+
+impl Args {
+    /// Parses the arguments, not including the program name
+    pub fn parse(
+        mut args: impl Iterator<Item = String>,
+    ) -> Result<Self, String> {
+        let mut out = Self::default();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{flag} requires a value"))
+            };
+            match flag.as_str() {
+                "--hostname" => out.hostnames.push(value()?),
+                "--set" => {
+                    let setting = value()?;
+                    let (key, value) = setting
+                        .split_once('=')
+                        .filter(|(key, _)| !key.trim().is_empty())
+                        .ok_or_else(|| {
+                            format!("--set needs KEY=VALUE, got '{setting}'")
+                        })?;
+                    out.settings
+                        .push((key.trim().to_uppercase(), value.to_string()));
+                }
+                "--query" => out.query = true,
+                "--plan" => out.plan = true,
+                "--dump-state" => out.dump_state = true,
+                "--once" => out.once = true,
+                "--test-notify" => out.test_notify = true,
+                "--offline" => out.offline = true,
+                "-v" | "--verbose" => out.verbose += 1,
+                // -vv and -vvv
+                v if v.len() > 2 && v[1..].bytes().all(|b| b == b'v') => {
+                    out.verbose += u8::try_from(v.len() - 1).unwrap_or(u8::MAX);
+                }
+                "-q" | "--quiet" => out.quiet = true,
+                "-h" | "--help" => out.help = true,
+                _ => return Err(format!("Unknown option '{flag}'")),
+            }
+        }
+        if out.quiet && out.verbose > 0 {
+            return Err("--quiet can't be combined with --verbose".to_string());
+        }
+        Ok(out)
+    }
+
+    /// The log level of the client's own messages, if set on the command
+    /// line. It takes precedence over `RUST_LOG`, which still applies to
+    /// everything else.
+    pub fn log_level(&self) -> Option<log::LevelFilter> {
+        match (self.quiet, self.verbose) {
+            (true, _) => Some(log::LevelFilter::Error),
+            (false, 0) => None,
+            (false, 1) => Some(log::LevelFilter::Info),
+            (false, 2) => Some(log::LevelFilter::Debug),
+            (false, _) => Some(log::LevelFilter::Trace),
+        }
+    }
+}
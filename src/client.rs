@@ -14,71 +14,1001 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::types::{Config, DyfiError, DyfiResponse, Hostname};
-use reqwest::blocking::ClientBuilder;
-use std::collections::HashMap;
-use std::net::IpAddr;
-use std::time::Instant;
+use crate::backoff::Backoff;
+use crate::dns;
+use crate::if_inet6;
+use crate::state::{HostStats, State};
+use crate::telemetry;
+#[cfg(not(feature = "rustls-tls"))]
+use crate::types::PIN_NEEDS_RUSTLS;
+use crate::types::{
+    is_transient_status, Config, CurrentIps, DyfiError, DyfiResponse,
+    DyfiResponseCode, Hostname, HostnameStyle, IpFamily, LoopSignal,
+    RepeatedError, ShutdownHandle,
+};
+use crate::util::{
+    format_duration, normalize_url, parse_sha256_hex, sanitize_snippet,
+};
+use api_health::ApiHealth;
+use clock::{Clock, SystemClock};
+use notify::{Notifier, UpdateEvent};
+use reqwest::blocking::{ClientBuilder, RequestBuilder, Response};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE,
+    PROXY_AUTHORIZATION, USER_AGENT,
+};
+use reqwest::redirect::Policy;
+use resolver::{Resolver, SystemResolver, TimeoutResolver};
+use routes::Routes;
+#[cfg(not(test))]
+use routes::SystemRoutes;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
+/// Path of the update API on dy.fi
+const DYFI_UPDATE_PATH: &str = "/nic/update";
+
+/// Response headers that tell who answered and whether requests are being
+/// limited. Headers starting with `x-ratelimit-` or `ratelimit` are also
+/// included.
+const DIAGNOSTIC_HEADERS: [&str; 4] = ["server", "via", "retry-after", "date"];
+
+/// Number of requests in a redirect chain before it is given up on
+const MAX_REDIRECTS: usize = 5;
+
+mod abuse_lockout;
+pub mod api_health;
+pub mod clock;
+pub mod decide;
+mod hook;
+pub mod notify;
+#[cfg(feature = "rustls-tls")]
+pub mod pinning;
+pub mod plan;
+pub mod resolver;
+pub mod routes;
 mod run_loop;
+// Only `--dump-state` reads the snapshot, and it needs serde
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+pub mod snapshot;
 
+// The flags track unrelated things, not the states of one machine
+#[allow(clippy::struct_excessive_bools)]
 pub struct Dyfi {
     http_client: reqwest::blocking::Client,
+    /// Client for the public IP APIs, which may have the API's certificate
+    /// pinned
+    ip_client: reqwest::blocking::Client,
     previous_update_time: Option<Instant>,
+    /// The same by the wall clock, which also runs while suspended
+    previous_update_wall: Option<SystemTime>,
     previous_ips: HashMap<Hostname, Vec<IpAddr>>,
     config: Config,
-    my_ip: Option<IpAddr>,
+    my_ip: Option<CurrentIps>,
+    /// Set when an update has been deferred and the deferral logged, so that
+    /// it isn't logged again on every iteration
+    update_deferred: bool,
+    /// Set when an update has been forced through the loop signal, until it
+    /// has been sent
+    manual_update: bool,
+    /// Used by other threads to interrupt the loop
+    signal: LoopSignal,
+    /// Set if dy.fi has recently replied `abuse` and we must not send any
+    /// requests yet
+    abuse_locked: bool,
+    /// The most recently detected address and the number of consecutive
+    /// checks it has been seen on
+    ip_candidate: Option<(CurrentIps, u32)>,
+    /// Which public IP APIs have failed, for preferring the first healthy
+    /// one
+    ip_api_health: Mutex<ApiHealth>,
+    /// Told about detected addresses and the outcomes of updates
+    notifiers: Vec<Box<dyn Notifier>>,
+    /// When the last heartbeat was logged
+    last_heartbeat: Instant,
+    /// Set once the hostnames have been released on shutdown
+    released: bool,
+    /// Delay before retrying after errors
+    backoff: Backoff,
+    repeated_error: RepeatedError,
+    /// The addresses the hostnames were last updated to according to the
+    /// state file. Checked once at startup, since the address may have
+    /// changed while the client wasn't running.
+    restored_ips: HashMap<Hostname, Vec<IpAddr>>,
+    /// When update requests have been sent, for enforcing the daily limit
+    request_times: Vec<SystemTime>,
+    /// How the updates of each hostname have gone, including earlier runs
+    /// if there is a state file
+    host_stats: HashMap<Hostname, HostStats>,
+    /// A newly detected address and when it was first seen, while waiting
+    /// for the debounce window to pass
+    pending_change: Option<(CurrentIps, Instant)>,
+    /// Hostnames that dy.fi has answered `good` for during this run. Only
+    /// these are released on exit, so that a record set by another instance
+    /// isn't taken offline.
+    bound: HashSet<Hostname>,
+    /// Set once it has been logged that dy.fi may soon release the
+    /// hostnames, until the next successful update
+    release_warned: bool,
+    /// The address dy.fi last accepted for all hostnames
+    acknowledged_ip: Option<CurrentIps>,
+    /// For each hostname, the number of consecutive checks it has resolved
+    /// to an outdated address although dy.fi has accepted the current one
+    stale_dns: HashMap<Hostname, u32>,
+    /// Number of consecutive checks the current address couldn't be
+    /// detected on
+    ip_failures: u32,
+    /// Set while dy.fi is left to detect the address because the public IP
+    /// APIs have failed too many times in a row
+    ip_fallback: bool,
+    /// Set while the pause file exists, so that pausing and resuming are
+    /// only logged once
+    paused: bool,
+    /// Set for a single check with `--once`, which doesn't wait for a new
+    /// address to settle
+    one_shot: bool,
+    /// Where the time comes from
+    clock: Box<dyn Clock>,
+    /// Looks up the current records of the hostnames
+    resolver: Box<dyn Resolver>,
+    /// The family managed on the current check: the configured one, unless
+    /// this host can only reach the Internet over one family
+    ip_family: IpFamily,
+    /// Tells which families this host can reach the Internet over
+    routes: Box<dyn Routes>,
+    /// Returned by `get_current_ip()` instead of asking the public IP APIs
+    #[cfg(test)]
+    fake_ip: Option<CurrentIps>,
 }
 
 impl Dyfi {
-    fn do_update(&self) -> Result<DyfiResponse, DyfiError> {
-        let http_response = self
+    /// Points `hostnames` at `ips`. Only the families present in `ips` are
+    /// sent, so that the records of the other family are left alone.
+    fn send_update(
+        &self,
+        hostnames: &[Hostname],
+        ips: Option<CurrentIps>,
+    ) -> Result<DyfiResponse, DyfiError> {
+        self.send_request(hostnames, false, ips)
+    }
+
+    /// Sends an `offline` request, which releases the hostnames from their
+    /// current address
+    fn send_offline(
+        &self,
+        hostnames: &[Hostname],
+    ) -> Result<DyfiResponse, DyfiError> {
+        self.send_request(hostnames, true, None)
+    }
+
+    fn send_request(
+        &self,
+        hostnames: &[Hostname],
+        offline: bool,
+        ips: Option<CurrentIps>,
+    ) -> Result<DyfiResponse, DyfiError> {
+        let span = span!(
+            "dyfi_request",
+            hostname = %hostnames.join(","),
+            offline,
+            response_code = tracing::field::Empty,
+        )
+        .entered();
+        let mut request = self
             .http_client
             .get(&self.config.dyfi_api)
-            .basic_auth(&self.config.user, Some(&self.config.password))
-            .query(&[("hostname", &self.config.hostnames.join(","))])
-            .send();
-
-        Ok(DyfiResponse::from(http_response?.text()?))
-    }
-
-    fn get_current_ip(&self) -> Result<IpAddr, DyfiError> {
-        let response =
-            self.http_client.get(&self.config.public_ip_api).send()?;
-        if response.status().is_success() {
-            match response.text() {
-                Ok(text) => match text.trim().parse() {
-                    Ok(ip) => Ok(ip),
-                    Err(e) => {
-                        Err(DyfiError(format!("Error parsing current IP: {e}")))
+            .basic_auth(&self.config.user, Some(self.config.password.expose()));
+        request = match self.config.hostname_style {
+            HostnameStyle::Joined => {
+                request.query(&[("hostname", &hostnames.join(","))])
+            }
+            HostnameStyle::Repeated => request.query(
+                &hostnames
+                    .iter()
+                    .map(|h| ("hostname", h))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        if offline {
+            request = request.query(&[("offline", "YES")]);
+        } else if let Some(my_ip) = ips {
+            if let Some(ip) = my_ip.v4 {
+                request = request.query(&[("myip", ip)]);
+            }
+            if let Some(ip) = my_ip.v6 {
+                request = request.query(&[("myip6", ip)]);
+            }
+        }
+        let http_response = send_timed(
+            request,
+            &self.config.dyfi_api,
+            self.config.slow_request_warn_ms,
+        )?;
+        // The body consumes the response, so the headers are kept for
+        // telling whether dy.fi or something in between answered
+        let headers = diagnostic_headers(http_response.headers());
+        if !http_response.status().is_success() {
+            debug!("Headers of the error response: {headers}");
+        }
+        // An error page may well be plain text that looks like a keyword
+        check_status(&http_response, "Error sending update")?;
+        if self.config.strict_content_type {
+            check_plain_text(&http_response)?;
+        }
+
+        let response = DyfiResponse::parse_with(
+            &http_response.text()?,
+            &self.config.response_map,
+        )?;
+        if let DyfiResponse::Other(_) = response {
+            debug!("Headers of the unrecognized response: {headers}");
+        }
+        span.record("response_code", response.code() as i32);
+        Ok(response)
+    }
+
+    pub(crate) fn do_update(
+        &mut self,
+        ips: Option<CurrentIps>,
+    ) -> Result<DyfiResponse, DyfiError> {
+        // However the hostnames were configured, always send them in the
+        // same order
+        let mut hostnames = self.config.hostnames.clone();
+        hostnames.sort_unstable();
+        if !self.config.drop_rejected_hostnames {
+            let res = self.send_update(&hostnames, ips);
+            count_update(&mut self.host_stats, &hostnames, &res);
+            if let Ok(DyfiResponse::Good(_)) = res {
+                self.bound.extend(hostnames);
+            }
+            return res;
+        }
+        // Update the hostnames one at a time, so that a hostname rejected by
+        // dy.fi can be told apart from the rest.
+        let mut outcome = None;
+        let mut rejected = Vec::new();
+        let mut last_rejection = None;
+        for host in &hostnames {
+            let res = self.send_update(std::slice::from_ref(host), ips);
+            count_update(
+                &mut self.host_stats,
+                std::slice::from_ref(host),
+                &res,
+            );
+            match res {
+                Ok(res @ (DyfiResponse::NoHost | DyfiResponse::NotFQDN)) => {
+                    warn!(
+                        "dy.fi rejected hostname {host} ({res:?}), \
+                        dropping it from future updates"
+                    );
+                    rejected.push(host.clone());
+                    last_rejection = Some(res);
+                }
+                Ok(res @ DyfiResponse::Good(_)) => {
+                    self.bound.insert(host.clone());
+                    outcome = Some(Ok(res));
+                }
+                Ok(res @ DyfiResponse::NoChg) => {
+                    outcome = Some(Ok(res));
+                }
+                // Any other response or error concerns all hostnames alike
+                other => {
+                    outcome = Some(other);
+                    break;
+                }
+            }
+        }
+        self.config
+            .hostnames
+            .retain(|host| !rejected.contains(host));
+        for host in &rejected {
+            self.previous_ips.remove(host);
+        }
+        outcome
+            .or_else(|| last_rejection.map(Ok))
+            .unwrap_or_else(|| {
+                Err(DyfiError::Config(
+                    "No hostnames left to update".to_string(),
+                ))
+            })
+    }
+
+    /// Asks the configured public IP APIs for our current address. With a
+    /// quorum of one, the first API to answer wins and the rest are only
+    /// tried if that fails. Otherwise every API is asked and at least
+    /// `ip_quorum` of them must agree. A temporary IPv6 address of this host
+    /// is replaced by its stable one, unless configured otherwise.
+    pub(crate) fn get_current_ip(&self) -> Result<CurrentIps, DyfiError> {
+        #[cfg(test)]
+        if let Some(ips) = self.fake_ip {
+            return self.filter_family(ips);
+        }
+        let ips = if self.config.ip_quorum <= 1
+            || self.config.public_ip_apis.len() == 1
+        {
+            self.get_current_ip_failover()
+        } else {
+            self.get_current_ip_quorum()
+        };
+        let mut ips = self.with_ipv6_api(ips)?;
+        if !self.config.track_temporary_ipv6 {
+            ips.v6 = ips.v6.map(if_inet6::prefer_stable);
+        }
+        let ips = self.filter_family(ips)?;
+        telemetry::Span::current().record("ip", ips.to_string());
+        Ok(ips)
+    }
+
+    /// Leaves out the addresses of families that aren't sent to dy.fi. If
+    /// nothing is left, e.g. on an IPv4-only network when only IPv6 is
+    /// wanted, there is nothing to update on this check.
+    fn filter_family(&self, ips: CurrentIps) -> Result<CurrentIps, DyfiError> {
+        let family = self.ip_family;
+        let filtered = family.filter(ips);
+        if filtered == CurrentIps::default() {
+            return Err(DyfiError::NoAddress(format!(
+                "No {family} address available, skipping cycle"
+            )));
+        }
+        Ok(filtered)
+    }
+
+    /// With `PUBLIC_IP_API_V6`, adds the IPv6 address it reports to `ips`.
+    /// If only one family can be detected on this check, e.g. because one
+    /// of the services is down, only that family is updated and the records
+    /// of the other are left alone.
+    fn with_ipv6_api(
+        &self,
+        ips: Result<CurrentIps, DyfiError>,
+    ) -> Result<CurrentIps, DyfiError> {
+        if self.config.public_ip_apis_v6.is_empty()
+            || self.ip_family == IpFamily::V4
+        {
+            return ips;
+        }
+        match (ips, self.get_current_ipv6()) {
+            (Ok(ips), Ok(v6)) => {
+                if ips.v6.is_some_and(|ip| ip != v6) {
+                    debug!(
+                        "Using IPv6 address {v6} from PUBLIC_IP_API_V6 \
+                        instead of {}",
+                        ips.v6.unwrap_or(v6)
+                    );
+                }
+                Ok(CurrentIps {
+                    v6: Some(v6),
+                    ..ips
+                })
+            }
+            (Ok(ips), Err(e)) if e.is_transient() => {
+                if ips.v6.is_none() {
+                    info!(
+                        "No IPv6 address detected on this check, leaving \
+                        the IPv6 records alone: {e}"
+                    );
+                }
+                Ok(ips)
+            }
+            (Err(e), Ok(v6)) if e.is_transient() => {
+                info!(
+                    "No IPv4 address detected on this check, leaving the \
+                    IPv4 records alone: {e}"
+                );
+                Ok(CurrentIps {
+                    v4: None,
+                    v6: Some(v6),
+                })
+            }
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        }
+    }
+
+    /// Asks the IPv6 APIs in order until one reports an IPv6 address
+    fn get_current_ipv6(&self) -> Result<Ipv6Addr, DyfiError> {
+        let mut last_error = None;
+        for url in &self.config.public_ip_apis_v6 {
+            match self.fetch_current_ip(url).and_then(|ips| {
+                ips.v6.ok_or_else(|| {
+                    DyfiError::Transient(format!(
+                        "{url} reported no IPv6 address, only {ips}"
+                    ))
+                })
+            }) {
+                Ok(ip) => return Ok(ip),
+                Err(e) => {
+                    info!("{url}: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            DyfiError::Config("No IPv6 APIs configured".to_string())
+        }))
+    }
+
+    /// Asks the public IP APIs in order until one answers. An API that has
+    /// failed is passed over for a while, and then tried first again.
+    fn get_current_ip_failover(&self) -> Result<CurrentIps, DyfiError> {
+        let mut health = self.ip_api_health.lock().unwrap();
+        let mut last_error = None;
+        for i in health.order(Instant::now()) {
+            let url = &self.config.public_ip_apis[i];
+            match self.fetch_current_ip(url) {
+                Ok(ip) => {
+                    if health.record_success(i) {
+                        info!("{url} has recovered, failing back to it");
+                    }
+                    return Ok(ip);
+                }
+                Err(e) => {
+                    info!("{url}: {e}");
+                    if health.record_failure(i, Instant::now())
+                        && self.config.public_ip_apis.len() > 1
+                    {
+                        info!("{url} failed, failing over to the next API");
                     }
-                },
-                Err(e) => Err(DyfiError(format!(
-                    "Error while fetching current IP: {e}"
-                ))),
+                    last_error = Some(e);
+                }
             }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            DyfiError::Config("No public IP APIs configured".to_string())
+        }))
+    }
+
+    fn get_current_ip_quorum(&self) -> Result<CurrentIps, DyfiError> {
+        let mut votes: Vec<(CurrentIps, usize)> = Vec::new();
+        for url in &self.config.public_ip_apis {
+            match self.fetch_current_ip(url) {
+                Ok(ip) => {
+                    debug!("{url} reports {ip}");
+                    match votes.iter_mut().find(|(x, _)| *x == ip) {
+                        Some((_, count)) => *count += 1,
+                        None => votes.push((ip, 1)),
+                    }
+                }
+                Err(e) => info!("{url}: {e}"),
+            }
+        }
+        if votes.len() > 1 {
+            let summary: Vec<String> = votes
+                .iter()
+                .map(|(ip, count)| format!("{ip} ({count})"))
+                .collect();
+            warn!("Public IP APIs disagree: {}", summary.join(", "));
+        }
+        votes
+            .into_iter()
+            .find(|(_, count)| *count >= self.config.ip_quorum)
+            .map(|(ip, _)| ip)
+            .ok_or_else(|| {
+                DyfiError::Transient(format!(
+                    "No IP address reported by at least {} of {} public IP APIs",
+                    self.config.ip_quorum,
+                    self.config.public_ip_apis.len()
+                ))
+            })
+    }
+
+    fn fetch_current_ip(&self, url: &str) -> Result<CurrentIps, DyfiError> {
+        let span =
+            span!("ip_api_request", url, ip = tracing::field::Empty).entered();
+        let response = send_timed(
+            self.ip_client.get(url),
+            url,
+            self.config.slow_request_warn_ms,
+        )?;
+        if self.config.strict_content_type {
+            check_plain_text(&response)?;
+        }
+        check_status(&response, "Error fetching current IP")?;
+        match response.text() {
+            // Likely a hiccup of the service rather than a malformed answer
+            Ok(text) if text.trim().is_empty() => Err(DyfiError::Transient(
+                "IP API returned an empty body".to_string(),
+            )),
+            Ok(text) => {
+                let ips: CurrentIps = text.parse()?;
+                span.record("ip", ips.to_string());
+                Ok(ips)
+            }
+            Err(e) => Err(DyfiError::Transient(format!(
+                "Error while fetching current IP: {e}"
+            ))),
+        }
+    }
+
+    /// Prints the addresses dy.fi's own nameserver has for each configured
+    /// hostname, next to what the system resolver returns. A difference
+    /// between the two usually means a stale DNS cache.
+    pub fn report_records(&self) -> DyfiResponseCode {
+        let Some(server) = self
+            .resolver
+            .lookup(&self.config.authoritative_ns)
+            .ok()
+            .and_then(|ips| ips.first().map(|ip| SocketAddr::new(*ip, 53)))
+        else {
+            error!(
+                "Unable to resolve nameserver {}",
+                self.config.authoritative_ns
+            );
+            return DyfiResponseCode::DNSErr;
+        };
+        let mut code = DyfiResponseCode::Ok;
+        for host in &self.config.hostnames {
+            let mut authoritative = match dns::query_addresses(server, host) {
+                Ok(ips) => ips,
+                Err(e) => {
+                    error!("Error querying {server} for {host}: {e}");
+                    code = DyfiResponseCode::DNSErr;
+                    continue;
+                }
+            };
+            let mut public = self.resolver.lookup(host).unwrap_or_default();
+            authoritative.sort();
+            public.sort();
+            public.dedup();
+            println!("{host}: {}", format_ips(&authoritative));
+            if public != authoritative {
+                println!("  (system resolver returns {})", format_ips(&public));
+            }
+            if let Some(stats) = self.host_stats.get(host) {
+                print!(
+                    "  ({} of {} updates succeeded",
+                    stats.successes, stats.attempts
+                );
+                match &stats.last_error {
+                    Some(e) => println!(", last error: {e})"),
+                    None => println!(")"),
+                }
+            }
+        }
+        code
+    }
+
+    /// Returns a handle for waking up or stopping `run()` from another
+    /// thread
+    pub fn loop_signal(&self) -> LoopSignal {
+        self.signal.clone()
+    }
+
+    /// Adds a notifier to be told about what happens from now on
+    #[cfg(test)]
+    pub(crate) fn add_notifier(&mut self, notifier: Box<dyn Notifier>) {
+        self.notifiers.push(notifier);
+    }
+
+    /// Tells an external monitor that the client is alive, or with `failed`
+    /// that it is exiting because of an error. Failures are only logged.
+    fn ping_heartbeat(&self, failed: bool) {
+        let Some(base) = &self.config.heartbeat_url else {
+            return;
+        };
+        let url = if failed {
+            format!("{}/fail", base.trim_end_matches('/'))
         } else {
-            Err(DyfiError(format!(
-                "Error fetching current IP. Server responded with status {}",
-                response.status()
-            )))
+            base.clone()
+        };
+        // Only dy.fi and the public IP APIs are watched for slowness
+        let result = send_timed(self.http_client.get(&url), &url, 0)
+            .map_err(DyfiError::from)
+            .and_then(|r| check_status(&r, "Error pinging heartbeat URL"));
+        if let Err(e) = result {
+            warn!("Heartbeat ping to {url} failed: {e}");
         }
     }
 
-    pub fn from(config: Config) -> Result<Self, DyfiError> {
-        if config.hostnames.is_empty() {
-            return Err(DyfiError("No hostnames configured".to_string()));
+    /// Makes `get_current_ip()` return `ips` without asking the public IP
+    /// APIs
+    #[cfg(test)]
+    pub(crate) fn set_fake_ip(&mut self, ips: CurrentIps) {
+        self.fake_ip = Some(ips);
+    }
+
+    /// Replaces the clock. Times already recorded are kept, so the new
+    /// clock should start from the current time.
+    #[cfg(test)]
+    pub(crate) fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Replaces what tells which families this host has routes for
+    #[cfg(test)]
+    pub(crate) fn set_routes(&mut self, routes: Box<dyn Routes>) {
+        self.routes = routes;
+    }
+
+    /// Replaces the resolver used for looking up the hostnames
+    #[cfg(test)]
+    pub(crate) fn set_resolver(&mut self, resolver: Box<dyn Resolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Sets the records the hostnames are known to have, as if they had
+    /// been resolved at startup
+    #[cfg(test)]
+    pub(crate) fn set_previous_ips(&mut self, host: &str, ips: Vec<IpAddr>) {
+        self.previous_ips.insert(host.to_string(), ips);
+    }
+
+    /// Sets the address `resolve_status()` compares the records to, as if
+    /// it had just been detected
+    #[cfg(test)]
+    pub(crate) fn set_detected_ip(&mut self, ips: Option<CurrentIps>) {
+        self.my_ip = ips;
+    }
+
+    fn notify(&self, event: &UpdateEvent) {
+        for notifier in &self.notifiers {
+            notifier.notify(event);
         }
-        debug!("Initializing HTTP client...");
-        Ok(Self {
-            // init blocking reqwest http client
-            http_client: ClientBuilder::new()
-                .user_agent("Dyfi-client-rs")
-                .build()?,
-            previous_update_time: None,
+    }
+
+    /// Sends every notifier a made-up update of the hostnames to
+    /// `notify::TEST_IP`, for checking how they are set up without waiting
+    /// for the address to change. Returns what became of it in each one.
+    pub fn test_notify(&self) -> Vec<Result<String, String>> {
+        let event = UpdateEvent::Updated {
+            hostnames: self.config.hostnames.clone(),
+            ip: notify::TEST_IP,
+        };
+        self.notifiers
+            .iter()
+            .map(|notifier| notifier.test(&event))
+            .collect()
+    }
+
+    /// Returns a handle that can stop `run()` from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::from(self.signal.clone())
+    }
+
+    pub fn from(mut config: Config) -> Result<Self, DyfiError> {
+        config.validate().map_err(|errors| {
+            let messages: Vec<_> =
+                errors.iter().map(ToString::to_string).collect();
+            DyfiError::Config(messages.join("; "))
+        })?;
+        normalize_urls(&mut config);
+        let abuse_locked =
+            config.abuse_lockout_file.as_deref().is_some_and(|path| {
+                let delay = Duration::from_secs(config.abuse_lockout);
+                let remaining = abuse_lockout::remaining(path, delay);
+                if let Some(remaining) = remaining {
+                    error!(
+                        "dy.fi recently denied a request due to abuse. \
+                        Refusing to run for another {}",
+                        format_duration(remaining.as_secs())
+                    );
+                }
+                remaining.is_some()
+            });
+        let mut state = config
+            .state_file
+            .as_deref()
+            .and_then(State::load)
+            .unwrap_or_default();
+        state.discard_future_times(SystemTime::now());
+        let previous_update_time = state
+            .last_update
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .and_then(|elapsed| {
+                info!(
+                    "Restored state, last update was {} ago",
+                    format_duration(elapsed.as_secs())
+                );
+                Instant::now().checked_sub(elapsed)
+            });
+        let http_client = build_http_client(&config)?;
+        let ip_client = build_ip_client(&config, &http_client)?;
+        let resolver = system_resolver(config.resolve_timeout);
+        let ip_family = config.ip_family;
+        let mut dyfi = Self {
+            backoff: Backoff::new(config.retry_base, config.retry_cap),
+            notifiers: notify::from_config(&config)?,
+            http_client,
+            ip_client,
+            ip_api_health: Mutex::new(ApiHealth::new(
+                config.public_ip_apis.len(),
+            )),
+            previous_update_time,
+            previous_update_wall: state.last_update,
             previous_ips: HashMap::new(),
             config,
             my_ip: None,
+            update_deferred: false,
+            manual_update: false,
+            signal: LoopSignal::default(),
+            abuse_locked,
+            ip_candidate: None,
+            last_heartbeat: Instant::now(),
+            released: false,
+            repeated_error: RepeatedError::default(),
+            restored_ips: state.previous_ips,
+            request_times: state.requests,
+            host_stats: state.host_stats,
+            pending_change: None,
+            bound: HashSet::new(),
+            release_warned: false,
+            acknowledged_ip: None,
+            stale_dns: HashMap::new(),
+            ip_failures: 0,
+            ip_fallback: false,
+            paused: false,
+            one_shot: false,
+            clock: Box::new(SystemClock),
+            resolver,
+            ip_family,
+            #[cfg(not(test))]
+            routes: Box::new(SystemRoutes),
+            #[cfg(test)]
+            routes: Box::new((true, true)),
+            #[cfg(test)]
+            fake_ip: None,
+        };
+        dyfi.ip_family = dyfi.routed_family();
+        log_ip_family(ip_family, dyfi.ip_family);
+        Ok(dyfi)
+    }
+
+    /// The configured family, narrowed down to the one this host can reach
+    /// the Internet over if there is only one
+    fn routed_family(&self) -> IpFamily {
+        let (v4, v6) = self.routes.available(self.config.bind_address);
+        debug!("Route to the Internet over IPv4: {v4}, over IPv6: {v6}");
+        self.config.ip_family.for_connectivity(v4, v6)
+    }
+
+    /// Checks the routes again, as a family may come up or go away while
+    /// the client runs, and logs it if the family managed changes
+    fn check_routes(&mut self) {
+        let family = self.routed_family();
+        if family != self.ip_family {
+            info!("The routes of this host have changed");
+            log_ip_family(self.config.ip_family, family);
+            self.ip_family = family;
+        }
+    }
+}
+
+/// Makes URLs that differ only by trailing slashes equal, and points a dy.fi
+/// API URL without a path at the update path
+fn normalize_urls(config: &mut Config) {
+    config.dyfi_api = normalize_url(&config.dyfi_api, DYFI_UPDATE_PATH);
+    debug!("dy.fi API URL: {}", config.dyfi_api);
+    for api in config
+        .public_ip_apis
+        .iter_mut()
+        .chain(&mut config.public_ip_apis_v6)
+    {
+        *api = normalize_url(api, "/");
+        debug!("Public IP API URL: {api}");
+    }
+}
+
+/// Logs which family is managed when the `configured` one has been narrowed
+/// down to `family`
+fn log_ip_family(configured: IpFamily, family: IpFamily) {
+    match (configured, family) {
+        (IpFamily::Both, IpFamily::Both) => {
+            info!("Managing both IPv4 and IPv6 records");
+        }
+        (IpFamily::Both, family) => info!(
+            "This host only has an {family} route to the Internet, managing \
+            {family} records only"
+        ),
+        (_, family) => {
+            info!("Managing {family} records only, as set by DYFI_IP_FAMILY");
+        }
+    }
+}
+
+/// Sets up the HTTP client according to the TLS backend and the transport
+/// options in `config`
+fn build_http_client(
+    config: &Config,
+) -> Result<reqwest::blocking::Client, DyfiError> {
+    Ok(client_builder(config)?.build()?)
+}
+
+/// Sets up the client for the public IP APIs. It is the same as the dy.fi
+/// client, unless the public key of the APIs is pinned.
+fn build_ip_client(
+    config: &Config,
+    http_client: &reqwest::blocking::Client,
+) -> Result<reqwest::blocking::Client, DyfiError> {
+    let Some(pin) = config.ip_api_pin.as_deref().and_then(parse_sha256_hex)
+    else {
+        return Ok(http_client.clone());
+    };
+    info!("Pinning the public key of the public IP API(s)");
+    #[cfg(feature = "rustls-tls")]
+    {
+        let tls = pinning::tls_config(pin)?;
+        Ok(client_builder(config)?.use_preconfigured_tls(tls).build()?)
+    }
+    #[cfg(not(feature = "rustls-tls"))]
+    {
+        let _ = pin;
+        Err(DyfiError::Config(PIN_NEEDS_RUSTLS.to_string()))
+    }
+}
+
+fn client_builder(config: &Config) -> Result<ClientBuilder, DyfiError> {
+    debug!("Initializing HTTP client...");
+    // init blocking reqwest http client
+    let mut client_builder = ClientBuilder::new()
+        .user_agent("Dyfi-client-rs")
+        .redirect(Policy::limited(MAX_REDIRECTS));
+    // rustls wins if both backends are compiled in, since it can only
+    // have been enabled on purpose
+    #[cfg(feature = "rustls-tls")]
+    {
+        client_builder = client_builder.use_rustls_tls();
+    }
+    #[cfg(all(feature = "native-tls", not(feature = "rustls-tls")))]
+    {
+        client_builder = client_builder.use_native_tls();
+    }
+    if let Some(headers) = &config.extra_headers {
+        client_builder =
+            client_builder.default_headers(parse_headers(headers)?);
+    }
+    if let Some(addr) = config.bind_address {
+        client_builder = client_builder.local_address(addr);
+        info!("Connecting over TCP from local address {addr}");
+    } else {
+        info!("Connecting directly over TCP");
+    }
+    Ok(client_builder)
+}
+
+/// Parses semicolon-separated `Key: Value` pairs. The headers the client
+/// sets itself can't be overridden.
+pub(crate) fn parse_headers(headers: &str) -> Result<HeaderMap, DyfiError> {
+    let mut map = HeaderMap::new();
+    for pair in headers.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+        let invalid = || DyfiError::Config(format!("Invalid header '{pair}'"));
+        let (key, value) = pair.split_once(':').ok_or_else(invalid)?;
+        let name = HeaderName::from_bytes(key.trim().as_bytes())
+            .map_err(|_| invalid())?;
+        let value =
+            HeaderValue::from_str(value.trim()).map_err(|_| invalid())?;
+        if [AUTHORIZATION, PROXY_AUTHORIZATION, USER_AGENT].contains(&name) {
+            return Err(DyfiError::Config(format!(
+                "Header {name} is set by the client and can't be overridden"
+            )));
+        }
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+/// The resolver of the operating system, with lookups given up on after
+/// `timeout` seconds unless it's 0
+fn system_resolver(timeout: u64) -> Box<dyn Resolver> {
+    if timeout == 0 {
+        Box::new(SystemResolver)
+    } else {
+        let timeout = Duration::from_secs(timeout);
+        Box::new(TimeoutResolver::new(SystemResolver, timeout))
+    }
+}
+
+/// Sends `request` to `url`, logging the response status and how long the
+/// request took. A response that took longer than `warn_after_ms` is warned
+/// about, as a sign that the server is struggling.
+fn send_timed(
+    request: RequestBuilder,
+    url: &str,
+    warn_after_ms: u64,
+) -> reqwest::Result<Response> {
+    let start = Instant::now();
+    let result = request.send();
+    let elapsed = start.elapsed().as_millis();
+    match &result {
+        Ok(response) => {
+            debug!("GET {url}: {} in {elapsed} ms", response.status());
+            if warn_after_ms > 0 && elapsed > u128::from(warn_after_ms) {
+                warn!(
+                    "GET {url} took {elapsed} ms, more than the \
+                    {warn_after_ms} ms of DYFI_SLOW_REQUEST_WARN_MS"
+                );
+            }
+        }
+        Err(e) => debug!("GET {url}: failed after {elapsed} ms: {e}"),
+    }
+    result
+}
+
+/// Turns an unsuccessful status of `response` into an error. Server errors
+/// and rate limiting are worth retrying.
+fn check_status(response: &Response, context: &str) -> Result<(), DyfiError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let message = format!("{context}. Server responded with status {status}");
+    if is_transient_status(status) {
+        Err(DyfiError::Transient(message))
+    } else {
+        Err(DyfiError::Http(message))
+    }
+}
+
+/// Makes sure `response` is plain text, and not e.g. an HTML page from a
+/// captive portal that happens to have a success status
+fn check_plain_text(response: &Response) -> Result<(), DyfiError> {
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    if mime.eq_ignore_ascii_case("text/plain") {
+        Ok(())
+    } else {
+        Err(DyfiError::Transient(format!(
+            "Expected a plain text response from {}, got '{content_type}'",
+            response.url()
+        )))
+    }
+}
+
+/// Counts an update request for `hostnames` that resulted in `res`
+fn count_update(
+    stats: &mut HashMap<Hostname, HostStats>,
+    hostnames: &[Hostname],
+    res: &Result<DyfiResponse, DyfiError>,
+) {
+    let error = match res {
+        Ok(DyfiResponse::Good(_) | DyfiResponse::NoChg) => None,
+        Ok(DyfiResponse::Other(text)) => Some(format!(
+            "unrecognized response '{}'",
+            sanitize_snippet(text, 60)
+        )),
+        Ok(response) => Some(format!("{:?}", response.code()).to_lowercase()),
+        Err(e) => Some(sanitize_snippet(&e.to_string(), 200)),
+    };
+    for host in hostnames {
+        stats
+            .entry(host.clone())
+            .or_default()
+            .record(error.as_deref());
+    }
+}
+
+/// The headers in `DIAGNOSTIC_HEADERS` and any rate limiting headers, as
+/// `name: value` pairs for logging
+fn diagnostic_headers(headers: &HeaderMap) -> String {
+    let found: Vec<_> = headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            DIAGNOSTIC_HEADERS.contains(&name)
+                || name.starts_with("x-ratelimit-")
+                || name.starts_with("ratelimit")
         })
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            format!("{name}: {}", sanitize_snippet(&value, 100))
+        })
+        .collect();
+    if found.is_empty() {
+        "none of interest".to_string()
+    } else {
+        found.join(", ")
+    }
+}
+
+fn format_ips(ips: &[IpAddr]) -> String {
+    if ips.is_empty() {
+        return "no addresses".to_string();
     }
+    ips.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
@@ -0,0 +1,79 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A marker file recording when dy.fi last replied `abuse`, so that
+//! restarting the client doesn't immediately send more requests.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Records the current time as the start of the lockout
+pub fn start(path: &Path) -> std::io::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    open_no_follow(&mut options, path)?.write_all(format!("{now}\n").as_bytes())
+}
+
+/// Returns how long the lockout recorded at `path` still lasts, if at all.
+/// A missing or unreadable marker means there is no lockout.
+pub fn remaining(path: &Path, lockout: Duration) -> Option<Duration> {
+    let mut contents = String::new();
+    open_no_follow(OpenOptions::new().read(true), path)
+        .ok()?
+        .read_to_string(&mut contents)
+        .ok()?;
+    let started = contents.trim().parse().ok()?;
+    let started = UNIX_EPOCH + Duration::from_secs(started);
+    let elapsed = SystemTime::now()
+        .duration_since(started)
+        .unwrap_or_default();
+    lockout.checked_sub(elapsed).filter(|d| !d.is_zero())
+}
+
+/// Opens the marker without following a symlink at `path`, so that the file
+/// can't be pointed somewhere else by whoever can write to its directory
+#[cfg(unix)]
+fn open_no_follow(
+    options: &mut OpenOptions,
+    path: &Path,
+) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    options
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn open_no_follow(
+    options: &mut OpenOptions,
+    path: &Path,
+) -> std::io::Result<File> {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is a symlink", path.display()),
+        ));
+    }
+    options.open(path)
+}
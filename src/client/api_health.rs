@@ -0,0 +1,62 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Health of the public IP APIs, so that an API that has failed isn't asked
+//! first on every check, but still gets a chance to take over again once it
+//! has recovered.
+
+use std::time::{Duration, Instant};
+
+/// How long a failed API is passed over before it is tried again
+pub const REPROBE_INTERVAL: Duration = Duration::from_mins(30);
+
+#[derive(Debug)]
+pub struct ApiHealth {
+    /// When each API, in order of preference, last failed, if it hasn't
+    /// succeeded since
+    failed_at: Vec<Option<Instant>>,
+}
+
+impl ApiHealth {
+    pub fn new(count: usize) -> Self {
+        Self {
+            failed_at: vec![None; count],
+        }
+    }
+
+    /// The indices of the APIs in the order they should be tried: those that
+    /// are healthy or due for another try, by preference, and then the rest
+    /// as a last resort
+    pub fn order(&self, now: Instant) -> Vec<usize> {
+        let (usable, failed): (Vec<_>, Vec<_>) = (0..self.failed_at.len())
+            .partition(|&i| {
+                self.failed_at[i].is_none_or(|t| {
+                    now.saturating_duration_since(t) >= REPROBE_INTERVAL
+                })
+            });
+        usable.into_iter().chain(failed).collect()
+    }
+
+    /// Marks API `index` as failed. Returns whether it was healthy before.
+    pub fn record_failure(&mut self, index: usize, now: Instant) -> bool {
+        self.failed_at[index].replace(now).is_none()
+    }
+
+    /// Marks API `index` as healthy. Returns whether it had failed before.
+    pub fn record_success(&mut self, index: usize) -> bool {
+        self.failed_at[index].take().is_some()
+    }
+}
@@ -0,0 +1,573 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Whether a check should send an update, decided without side effects so
+//! that both the update loop and `--plan` come to the same conclusion. The
+//! loop then acts on the `Decision`: it logs it, remembers the records and
+//! sends the update.
+
+use super::notify::UpdateEvent;
+use super::resolver::BrokenResolution;
+use super::run_loop::warn_extra_records;
+use super::{format_ips, Dyfi};
+use crate::types::{CurrentIps, Hostname};
+use crate::util::format_duration;
+use crate::FORCE_UPDATE_INTERVAL;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Why an update is deferred by the daily request limit
+pub(super) const DAILY_LIMIT: &str = "daily request limit";
+
+/// The current records of some hostnames, or why they couldn't be looked up
+pub(crate) type Lookups = Vec<(Hostname, io::Result<Vec<IpAddr>>)>;
+
+/// Why a hostname would or wouldn't be updated
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    /// The records match the current address
+    Current,
+    /// The hostname has no records or couldn't be looked up
+    Unresolved,
+    /// A record is an old address
+    Stale(IpAddr),
+    /// A record is an old address, but dy.fi has already accepted the
+    /// current one and the lookups have lagged behind for so long that it's
+    /// likely DNS caching
+    Lagging(IpAddr),
+    /// The address has changed since the last run, by the state file
+    ChangedSinceLastRun,
+    /// No update has gone through for so long that one is due anyway
+    Forced,
+    /// An update has been asked for, e.g. with `SIGUSR2`
+    Requested,
+    /// The lookup only gave loopback or unspecified addresses, which says
+    /// nothing about the records
+    Broken,
+    /// The records weren't looked at, because the check ends before that
+    Unchecked,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Current => f.write_str("current"),
+            Self::Unresolved => f.write_str("unresolved"),
+            Self::Stale(ip) => write!(f, "stale ({ip})"),
+            Self::Lagging(ip) => write!(f, "DNS lagging ({ip})"),
+            Self::ChangedSinceLastRun => f.write_str("changed since last run"),
+            Self::Forced => f.write_str("forced"),
+            Self::Requested => f.write_str("requested"),
+            Self::Broken => f.write_str("resolution broken"),
+            Self::Unchecked => f.write_str("not checked"),
+        }
+    }
+}
+
+/// What was found out about one hostname
+#[derive(Debug)]
+pub struct HostCheck {
+    pub hostname: Hostname,
+    /// The records the hostname is known to have after the check
+    pub records: Vec<IpAddr>,
+    pub reason: Reason,
+    /// Why the lookup failed
+    pub(super) lookup_error: Option<String>,
+    /// The hostname had records before the check
+    pub(super) had_records: bool,
+    /// The records were compared to the current address
+    pub(super) compared: bool,
+    /// The number of checks in a row an old address has been seen on since
+    /// dy.fi accepted the current one, or `None` if that isn't being counted
+    pub(super) dns_lag: Option<u32>,
+}
+
+/// The conclusion of a check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// The hostnames have been released and must never be bound again
+    Released,
+    /// The detected address isn't allowed to be sent
+    Disallowed(IpAddr),
+    /// Nothing needs updating
+    Nop,
+    /// An update is needed, but it is being held back
+    Deferred {
+        retry_after: Duration,
+        reason: &'static str,
+    },
+    /// An update is to be sent
+    Update,
+}
+
+/// How far the check looked into the records
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Scope {
+    /// Not at all, the check ended before
+    Skipped,
+    /// An update has been asked for, so they don't matter
+    Requested,
+    /// An update is due anyway, so they don't matter
+    Forced,
+    /// The hostnames were looked up and compared to the current address
+    Checked,
+}
+
+/// What to do about a new address that may not have settled yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Debounce {
+    /// Forget any pending change without a word
+    Clear,
+    /// The pending change went away, cancel it
+    Cancel,
+    /// Start waiting for `CurrentIps` to settle
+    Start(CurrentIps),
+    /// Keep waiting for the pending change to settle
+    Wait(Duration),
+}
+
+impl Debounce {
+    /// How long the update has to wait with a debounce window of `window`
+    pub(super) fn wait(self, window: Duration) -> Option<Duration> {
+        match self {
+            Self::Start(_) => Some(window),
+            Self::Wait(remaining) => Some(remaining),
+            Self::Clear | Self::Cancel => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Decision {
+    pub verdict: Verdict,
+    /// The hostnames in alphabetical order
+    pub hosts: Vec<HostCheck>,
+    /// The detected address, unless dy.fi is left to detect it
+    pub(super) ip: Option<CurrentIps>,
+    pub(super) scope: Scope,
+    /// Hostnames whose address in the state file isn't the current one,
+    /// with that address
+    pub(super) restored_changes: Vec<(Hostname, Vec<IpAddr>)>,
+    /// The state file has been compared to the current address
+    pub(super) restored_checked: bool,
+    /// The families whose address has changed
+    pub(super) changed: CurrentIps,
+    /// Every family must be sent, not only the ones that have changed
+    pub(super) update_all: bool,
+    pub(super) debounce: Debounce,
+}
+
+impl Decision {
+    /// The addresses to send to dy.fi
+    pub(super) fn update_ips(&self) -> Option<CurrentIps> {
+        self.ip
+            .map(|ips| if self.update_all { ips } else { self.changed })
+    }
+
+    /// An undecided check of hostnames with the records `previous`
+    fn new(
+        ip: Option<CurrentIps>,
+        previous: &HashMap<Hostname, Vec<IpAddr>>,
+    ) -> Self {
+        let mut hosts: Vec<_> = previous
+            .iter()
+            .map(|(host, records)| HostCheck {
+                hostname: host.clone(),
+                records: records.clone(),
+                reason: Reason::Unchecked,
+                lookup_error: None,
+                had_records: !records.is_empty(),
+                compared: false,
+                dns_lag: None,
+            })
+            .collect();
+        hosts.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        Self {
+            verdict: Verdict::Nop,
+            hosts,
+            ip,
+            scope: Scope::Skipped,
+            restored_changes: vec![],
+            restored_checked: false,
+            changed: CurrentIps::default(),
+            update_all: false,
+            debounce: Debounce::Clear,
+        }
+    }
+
+    fn set_reasons(&mut self, reason: &Reason) {
+        for host in &mut self.hosts {
+            host.reason = reason.clone();
+        }
+    }
+}
+
+impl Dyfi {
+    /// Decides what a check finding `current_ip` should do about hostnames
+    /// that had the records `previous`. The hostnames are only looked up
+    /// through `lookup` if their records matter.
+    pub(crate) fn decide(
+        &self,
+        current_ip: Option<CurrentIps>,
+        previous: &HashMap<Hostname, Vec<IpAddr>>,
+        manual: bool,
+        lookup: impl FnOnce(&[Hostname]) -> Lookups,
+    ) -> Decision {
+        let mut decision = Decision::new(current_ip, previous);
+        // Released hostnames stop resolving, which would otherwise look like
+        // they need updating. They are only released on the way out, so
+        // they must never be bound again by this client.
+        if self.released {
+            decision.verdict = Verdict::Released;
+            return decision;
+        }
+        if let Some(outside) = current_ip.and_then(|ip| {
+            ip.iter()
+                .find(|a| !self.config.allowed_ip_ranges.allows(*a))
+        }) {
+            decision.verdict = Verdict::Disallowed(outside);
+            return decision;
+        }
+        let names: Vec<_> =
+            decision.hosts.iter().map(|h| h.hostname.clone()).collect();
+        let recent = self
+            .since_last_update()
+            .is_some_and(|x| x.as_secs() < FORCE_UPDATE_INTERVAL);
+        let mut must_update = false;
+        if let Some(curr_ips) = current_ip {
+            decision.restored_checked = true;
+            for (host, ips) in &self.restored_ips {
+                let host_changed = curr_ips.changed_from(ips);
+                if host_changed != CurrentIps::default() {
+                    decision.restored_changes.push((host.clone(), ips.clone()));
+                    decision.changed = decision.changed.union(host_changed);
+                }
+            }
+            decision.restored_changes.sort();
+            must_update = decision.changed != CurrentIps::default();
+        }
+        if manual {
+            decision.scope = Scope::Requested;
+            decision.set_reasons(&Reason::Requested);
+        } else if recent {
+            decision.scope = Scope::Checked;
+            let lookups = lookup(&names);
+            must_update |= self.check_hosts(&mut decision, lookups);
+        } else {
+            decision.scope = Scope::Forced;
+            decision.set_reasons(&Reason::Forced);
+        }
+        if decision.scope != Scope::Checked {
+            must_update = true;
+            decision.update_all = true;
+        }
+        if !decision.update_all {
+            let new_ip = current_ip.filter(|_| {
+                must_update && decision.changed != CurrentIps::default()
+            });
+            decision.debounce = self.debounce_step(new_ip);
+            if let Some(retry_after) =
+                decision.debounce.wait(self.debounce_window())
+            {
+                decision.verdict = Verdict::Deferred {
+                    retry_after,
+                    reason: "IP change debounce",
+                };
+                return decision;
+            }
+        }
+        decision.verdict = if !must_update {
+            Verdict::Nop
+        } else if let Some(retry_after) =
+            self.min_interval_wait().filter(|_| !manual)
+        {
+            Verdict::Deferred {
+                retry_after,
+                reason: "minimum update interval",
+            }
+        } else if let Some(retry_after) =
+            self.request_limit_wait(self.requests_per_update())
+        {
+            Verdict::Deferred {
+                retry_after,
+                reason: DAILY_LIMIT,
+            }
+        } else {
+            Verdict::Update
+        };
+        decision
+    }
+
+    /// Compares the looked up records of the hostnames to the current
+    /// address, and returns whether something needs updating
+    fn check_hosts(&self, decision: &mut Decision, lookups: Lookups) -> bool {
+        let expected = self.config.expected_records;
+        let limit = self.config.stale_dns_checks;
+        let mut must_update = false;
+        for (host, result) in lookups {
+            let Some(check) =
+                decision.hosts.iter_mut().find(|h| h.hostname == host)
+            else {
+                continue;
+            };
+            // Nothing is known about the records, which isn't a reason to
+            // update them. The last known records are kept.
+            if let Some(e) =
+                result.as_ref().err().filter(|e| BrokenResolution::is(e))
+            {
+                check.reason = Reason::Broken;
+                check.lookup_error = Some(e.to_string());
+                continue;
+            }
+            // No records means the dy.fi DNS service doesn't know about the
+            // host and needs to be told by an update
+            let mut unresolved = !check.had_records;
+            match result {
+                Ok(ips) => check.records = ips,
+                Err(e) => {
+                    check.lookup_error = Some(e.to_string());
+                    unresolved = true;
+                }
+            }
+            if unresolved {
+                must_update = true;
+                decision.update_all = true;
+            }
+            check.reason = if unresolved {
+                Reason::Unresolved
+            } else {
+                Reason::Current
+            };
+            let Some(curr_ips) = decision.ip else {
+                continue;
+            };
+            check.compared = true;
+            // Only compare against the current address of the same family
+            let changed =
+                curr_ips.changed_from_records(&check.records, expected);
+            let outdated = check.records.iter().copied().find(|ip| {
+                changed.of_family(ip).is_some_and(|curr| curr != *ip)
+            });
+            decision.changed = decision.changed.union(changed);
+            if let Some(ip) = outdated {
+                check.dns_lag = self.dns_lag(&host, decision.ip);
+                let lagging = check.dns_lag.is_some_and(|n| n >= limit);
+                must_update |= !lagging;
+                if !unresolved {
+                    check.reason = if lagging {
+                        Reason::Lagging(ip)
+                    } else {
+                        Reason::Stale(ip)
+                    };
+                }
+            } else if decision.restored_changes.iter().any(|(h, _)| *h == host)
+            {
+                check.reason = Reason::ChangedSinceLastRun;
+            }
+        }
+        must_update
+    }
+
+    /// The number of checks in a row `host` will have resolved to an old
+    /// address on, counting this one, if dy.fi has already accepted
+    /// `current_ip`. Otherwise the lag isn't counted.
+    pub(super) fn dns_lag(
+        &self,
+        host: &str,
+        current_ip: Option<CurrentIps>,
+    ) -> Option<u32> {
+        if self.config.stale_dns_checks == 0
+            || self.acknowledged_ip.is_none()
+            || self.acknowledged_ip != current_ip
+        {
+            return None;
+        }
+        Some(self.stale_dns.get(host).map_or(1, |n| n.saturating_add(1)))
+    }
+
+    /// Acts on what `decide` found out and logs it. Doesn't send anything.
+    pub(crate) fn apply_decision(&mut self, decision: &Decision) {
+        match decision.verdict {
+            Verdict::Released => {
+                debug!("Hostname(s) have been released, not updating");
+                return;
+            }
+            Verdict::Disallowed(outside) => {
+                warn!(
+                    "Detected address {outside} is outside of \
+                    DYFI_ALLOWED_IP_RANGES, not updating"
+                );
+                return;
+            }
+            _ => (),
+        }
+        if let Some(ip) = decision.ip {
+            self.notify(&UpdateEvent::IpDetected(ip));
+        }
+        // The state file holding every hostname at the current address is
+        // what would otherwise have been a forced update on startup
+        let restored_current = decision.restored_checked
+            && decision.restored_changes.is_empty()
+            && self.restored_is_complete();
+        if decision.restored_checked {
+            self.restored_ips.clear();
+        }
+        for (host, ips) in &decision.restored_changes {
+            info!(
+                "Address of {host} has changed from {} since the last run, \
+                updating...",
+                format_ips(ips)
+            );
+        }
+        if restored_current && decision.verdict == Verdict::Nop {
+            info!("Records are already current, skipping initial update");
+        }
+        match decision.scope {
+            Scope::Checked => self.apply_host_checks(decision),
+            Scope::Forced => info!(
+                "No updates in the last {} or no updates yet. Updating...",
+                format_duration(FORCE_UPDATE_INTERVAL)
+            ),
+            Scope::Requested | Scope::Skipped => (),
+        }
+        self.apply_debounce(decision.debounce);
+        if let Verdict::Deferred { reason, .. } = decision.verdict {
+            if reason == DAILY_LIMIT && !self.update_deferred {
+                warn!(
+                    "Reached the limit of {} update requests in 24 hours, \
+                    not sending any more for now",
+                    self.config.max_daily_requests
+                );
+            }
+        }
+    }
+
+    fn apply_host_checks(&mut self, decision: &Decision) {
+        let expected = self.config.expected_records;
+        for check in &decision.hosts {
+            let host = &check.hostname;
+            if check.reason == Reason::Broken {
+                let e = check.lookup_error.as_deref().unwrap_or_default();
+                warn!("{host} {e}, not updating it on that basis");
+                continue;
+            }
+            if !check.had_records {
+                info!("No current IP for {host}, updating...");
+            }
+            if let Some(e) = &check.lookup_error {
+                error!("Unable to resolve host {host}: {e}");
+            }
+            if let Some(ips) = self.previous_ips.get_mut(host) {
+                ips.clone_from(&check.records);
+            }
+            if !check.compared {
+                continue;
+            }
+            warn_extra_records(host, &check.records, expected);
+            match check.reason {
+                Reason::Lagging(ip) => {
+                    self.record_dns_lag(host, ip, check.dns_lag);
+                }
+                Reason::Stale(ip) => {
+                    info!("Host {host} has outdated ip {ip}, updating...");
+                    self.record_dns_lag(host, ip, check.dns_lag);
+                }
+                _ => {
+                    self.stale_dns.remove(host);
+                }
+            }
+        }
+    }
+
+    /// Remembers `lag` as the number of checks in a row `host` has resolved
+    /// to the old address `ip` on, and warns when it reaches the limit
+    pub(super) fn record_dns_lag(
+        &mut self,
+        host: &str,
+        ip: IpAddr,
+        lag: Option<u32>,
+    ) {
+        let Some(count) = lag else {
+            self.stale_dns.remove(host);
+            return;
+        };
+        self.stale_dns.insert(host.to_string(), count);
+        let limit = self.config.stale_dns_checks;
+        if count == limit {
+            warn!(
+                "{host} still resolves to {ip} after {limit} checks, although \
+                dy.fi has accepted the current address. This is likely DNS \
+                caching; trusting dy.fi and not updating {host} again until \
+                the address changes"
+            );
+        }
+    }
+
+    pub(super) fn debounce_window(&self) -> Duration {
+        if self.one_shot {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(self.config.debounce)
+        }
+    }
+
+    /// What to do about `new_ip` if it has only just been detected. An
+    /// address that goes away within the debounce window cancels the
+    /// pending update.
+    pub(super) fn debounce_step(&self, new_ip: Option<CurrentIps>) -> Debounce {
+        let window = self.debounce_window();
+        let Some(ip) = new_ip.filter(|_| !window.is_zero()) else {
+            return Debounce::Cancel;
+        };
+        match self.pending_change {
+            Some((pending, since)) if pending == ip => {
+                let remaining = window.saturating_sub(self.clock.since(since));
+                if remaining.is_zero() {
+                    Debounce::Clear
+                } else {
+                    Debounce::Wait(remaining)
+                }
+            }
+            _ => Debounce::Start(ip),
+        }
+    }
+
+    pub(super) fn apply_debounce(&mut self, step: Debounce) {
+        match step {
+            Debounce::Clear => self.pending_change = None,
+            Debounce::Cancel => {
+                if let Some((ip, _)) = self.pending_change.take() {
+                    info!(
+                        "IP address {ip} went away within the debounce \
+                        window, cancelling the update"
+                    );
+                }
+            }
+            Debounce::Start(ip) => {
+                info!(
+                    "New IP address {ip} detected, waiting {} for it to \
+                    settle",
+                    format_duration(self.config.debounce)
+                );
+                self.pending_change = Some((ip, self.clock.now()));
+            }
+            Debounce::Wait(_) => (),
+        }
+    }
+}
@@ -0,0 +1,377 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Telling other programs about what the client does.
+
+use crate::types::{Config, CurrentIps, DyfiError, DyfiResponseCode, Hostname};
+use crate::util::write_atomic;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(all(unix, feature = "serde"))]
+use {
+    std::io::{ErrorKind, Write as _},
+    std::os::unix::fs::FileTypeExt,
+    std::os::unix::net::{UnixListener, UnixStream},
+    std::path::Path,
+    std::time::Instant,
+};
+
+/// The address of the made-up update sent by `--test-notify`, from the
+/// range reserved for documentation (RFC 5737)
+pub const TEST_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+/// How many bytes of events are held for a consumer that isn't reading
+/// them. Events beyond that are dropped, so that a stuck consumer can't make
+/// the client run out of memory.
+#[cfg(all(unix, feature = "serde"))]
+pub const EVENT_BACKLOG: usize = 64 * 1024;
+
+/// How long `--test-notify` gives a consumer to connect to the event socket
+#[cfg(all(unix, feature = "serde", not(test)))]
+const CONSUMER_WAIT: Duration = Duration::from_secs(5);
+#[cfg(all(unix, feature = "serde", test))]
+const CONSUMER_WAIT: Duration = Duration::from_millis(100);
+
+/// Something that has happened that notifiers may want to pass on. It is
+/// serialized with the name of the event as `event`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+pub enum UpdateEvent {
+    /// The current address has been checked. Sent on every check, whether or
+    /// not it has changed.
+    IpDetected(CurrentIps),
+    /// dy.fi has pointed the hostnames to a new address
+    Updated {
+        hostnames: Vec<Hostname>,
+        ip: IpAddr,
+    },
+    /// dy.fi has refused an update
+    Rejected { code: DyfiResponseCode },
+    /// The client keeps running after an error it would otherwise exit on,
+    /// and tries again after `retry_after`. Someone should look into it.
+    Failing {
+        code: DyfiResponseCode,
+        #[cfg_attr(
+            feature = "serde",
+            serde(serialize_with = "serialize_secs")
+        )]
+        retry_after: Duration,
+    },
+}
+
+#[cfg(feature = "serde")]
+impl UpdateEvent {
+    /// The event as a single line of JSON, without the newline
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("an event is always valid JSON")
+    }
+}
+
+/// Durations are serialized in seconds
+#[cfg(feature = "serde")]
+fn serialize_secs<S: serde::Serializer>(
+    d: &Duration,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(d.as_secs())
+}
+
+/// Receives events as they happen. Notifiers handle their own errors, so
+/// that a failing notifier never stops the client.
+pub trait Notifier: Send {
+    fn notify(&self, event: &UpdateEvent);
+
+    /// Passes on `event` for `--test-notify`, telling what became of it
+    /// instead of logging any error
+    fn test(&self, event: &UpdateEvent) -> Result<String, String>;
+}
+
+/// Creates the notifiers enabled in `config`
+#[cfg_attr(not(all(unix, feature = "serde")), allow(clippy::unnecessary_wraps))]
+pub fn from_config(
+    config: &Config,
+) -> Result<Vec<Box<dyn Notifier>>, DyfiError> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    if let Some(path) = &config.ip_output_file {
+        notifiers.push(Box::new(IpFile::new(path.clone())));
+    }
+    #[cfg(all(unix, feature = "serde"))]
+    if let Some(path) = &config.event_socket {
+        let socket = EventSocket::bind(path).map_err(|e| {
+            DyfiError::Config(format!(
+                "Error creating event socket {}: {e}",
+                path.display()
+            ))
+        })?;
+        info!("Streaming events to consumers of {}", path.display());
+        notifiers.push(Box::new(socket));
+    }
+    Ok(notifiers)
+}
+
+/// Writes the current IP address to a file whenever it changes, for other
+/// services to read
+pub struct IpFile {
+    path: PathBuf,
+    /// The address last written to the file
+    written: Mutex<Option<CurrentIps>>,
+}
+
+impl IpFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            written: Mutex::new(None),
+        }
+    }
+
+    fn write(&self, ip: &CurrentIps) -> std::io::Result<()> {
+        let mut contents = ip
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        contents.push('\n');
+        write_atomic(&self.path, contents.as_bytes())
+    }
+}
+
+impl Notifier for IpFile {
+    fn notify(&self, event: &UpdateEvent) {
+        let UpdateEvent::IpDetected(ip) = event else {
+            return;
+        };
+        let mut written = self.written.lock().unwrap();
+        if *written == Some(*ip) {
+            return;
+        }
+        match self.write(ip) {
+            Ok(()) => {
+                debug!("Wrote IP address {ip} to {}", self.path.display());
+                *written = Some(*ip);
+            }
+            Err(e) => error!("Error writing {}: {e}", self.path.display()),
+        }
+    }
+
+    /// Writes the address of an update, which would have been detected
+    /// first, and then puts back what the file had, so that whatever reads
+    /// it isn't left with a made-up address
+    fn test(&self, event: &UpdateEvent) -> Result<String, String> {
+        let path = self.path.display();
+        let ip = match event {
+            UpdateEvent::IpDetected(ip) => *ip,
+            UpdateEvent::Updated { ip, .. } => CurrentIps::from(*ip),
+            _ => return Ok(format!("{path} only gets addresses")),
+        };
+        let previous = std::fs::read(&self.path).ok();
+        self.write(&ip)
+            .map_err(|e| format!("Error writing {path}: {e}"))?;
+        let restored = match previous {
+            Some(contents) => write_atomic(&self.path, &contents),
+            None => std::fs::remove_file(&self.path),
+        };
+        restored.map_err(|e| {
+            format!("Wrote {ip} to {path}, but couldn't restore it: {e}")
+        })?;
+        Ok(format!("Wrote {ip} to {path}"))
+    }
+}
+
+/// Streams events as JSON lines to the programs connected to a Unix socket.
+/// Nothing here ever blocks: events are dropped and counted while no one is
+/// connected, or when a consumer has fallen too far behind.
+#[cfg(all(unix, feature = "serde"))]
+pub struct EventSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    state: Mutex<EventSocketState>,
+}
+
+#[cfg(all(unix, feature = "serde"))]
+#[derive(Default)]
+struct EventSocketState {
+    consumers: Vec<Consumer>,
+    /// Events no consumer has received since the count was last logged
+    dropped: u64,
+}
+
+/// A connected program and the events it hasn't read yet
+#[cfg(all(unix, feature = "serde"))]
+struct Consumer {
+    stream: UnixStream,
+    backlog: Vec<u8>,
+}
+
+#[cfg(all(unix, feature = "serde"))]
+impl EventSocket {
+    /// Listens on `path`. A socket left behind by an earlier run is
+    /// replaced, but one that a running client still listens on isn't, and
+    /// neither is any other kind of file.
+    pub fn bind(path: &Path) -> std::io::Result<Self> {
+        if std::fs::symlink_metadata(path)
+            .is_ok_and(|meta| meta.file_type().is_socket())
+        {
+            if UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::new(
+                    ErrorKind::AddrInUse,
+                    "another client is listening on it",
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener,
+            state: Mutex::default(),
+        })
+    }
+
+    /// How many events have been dropped since the count was last logged
+    #[cfg(test)]
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    fn accept_consumers(&self, state: &mut EventSocketState) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("Error setting up event consumer: {e}");
+                        continue;
+                    }
+                    debug!(
+                        "Event consumer connected to {}",
+                        self.path.display()
+                    );
+                    if state.dropped > 0 {
+                        info!(
+                            "{} event(s) were dropped while no consumer was \
+                            connected to {}",
+                            state.dropped,
+                            self.path.display()
+                        );
+                        state.dropped = 0;
+                    }
+                    state.consumers.push(Consumer {
+                        stream,
+                        backlog: Vec::new(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Error accepting event consumer: {e}");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "serde"))]
+impl Consumer {
+    /// Writes as much of the backlog as the consumer takes without waiting.
+    /// Returns false if the consumer has gone away.
+    fn flush(&mut self) -> bool {
+        while !self.backlog.is_empty() {
+            match self.stream.write(&self.backlog) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.backlog.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(all(unix, feature = "serde"))]
+impl EventSocket {
+    /// Queues `event` for every consumer, returning how many got it
+    fn send(&self, event: &UpdateEvent) -> usize {
+        let mut state = self.state.lock().unwrap();
+        self.accept_consumers(&mut state);
+        let mut line = event.to_json();
+        line.push('\n');
+        let mut delivered = 0;
+        let mut lagging = 0;
+        state.consumers.retain_mut(|consumer| {
+            // Whole events are dropped rather than cut off, which would
+            // garble the stream
+            if consumer.backlog.len() + line.len() <= EVENT_BACKLOG {
+                consumer.backlog.extend_from_slice(line.as_bytes());
+                delivered += 1;
+            } else {
+                lagging += 1;
+            }
+            let connected = consumer.flush();
+            if !connected {
+                debug!("Event consumer disconnected");
+            }
+            connected
+        });
+        if lagging > 0 {
+            warn!("{lagging} event consumer(s) are not keeping up, dropped an event");
+        }
+        if delivered == 0 {
+            state.dropped += 1;
+        }
+        delivered
+    }
+}
+
+#[cfg(all(unix, feature = "serde"))]
+impl Notifier for EventSocket {
+    fn notify(&self, event: &UpdateEvent) {
+        self.send(event);
+    }
+
+    /// Gives a consumer that was started just for the test a moment to
+    /// connect before sending the event
+    fn test(&self, event: &UpdateEvent) -> Result<String, String> {
+        let deadline = Instant::now() + CONSUMER_WAIT;
+        loop {
+            let mut state = self.state.lock().unwrap();
+            self.accept_consumers(&mut state);
+            if !state.consumers.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            drop(state);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let path = self.path.display();
+        match self.send(event) {
+            0 => Err(format!("No consumer is connected to {path}")),
+            n => Ok(format!("Sent the event to {n} consumer(s) of {path}")),
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "serde"))]
+impl Drop for EventSocket {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
@@ -0,0 +1,162 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! What the client would do on its next check, for `--plan`. Nothing is
+//! sent to dy.fi and nothing is remembered.
+
+use super::decide::{HostCheck, Verdict};
+use super::{format_ips, Dyfi};
+use crate::types::{CurrentIps, DyfiError};
+use crate::util::format_duration;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct Plan {
+    /// The detected address, unless dy.fi is left to detect it
+    pub ip: Option<CurrentIps>,
+    pub hosts: Vec<HostCheck>,
+    /// Why nothing would be sent although something needs updating, and
+    /// for how long, if that is known
+    pub held_back: Option<(String, Option<Duration>)>,
+    /// What the check would conclude
+    pub verdict: Verdict,
+}
+
+impl Plan {
+    /// Whether an update request would be sent. dy.fi is always sent every
+    /// hostname at once, so one hostname that needs updating is enough.
+    pub fn would_update(&self) -> bool {
+        self.held_back.is_none() && self.verdict == Verdict::Update
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => writeln!(f, "Current address: {ip}")?,
+            None => writeln!(f, "Current address: left to dy.fi to detect")?,
+        }
+        let action = if self.would_update() { "update" } else { "-" };
+        let rows: Vec<_> = self
+            .hosts
+            .iter()
+            .map(|host| {
+                [
+                    host.hostname.clone(),
+                    format_ips(&host.records),
+                    action.to_string(),
+                    host.reason.to_string(),
+                ]
+            })
+            .collect();
+        let header =
+            ["HOSTNAME", "RECORDS", "ACTION", "REASON"].map(String::from);
+        let mut widths = [0; 4];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        for row in std::iter::once(&header).chain(&rows) {
+            writeln!(
+                f,
+                "{:w0$}  {:w1$}  {:w2$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+            )?;
+        }
+        match &self.held_back {
+            Some((reason, Some(wait))) => write!(
+                f,
+                "Nothing would be sent for another {} due to {reason}",
+                format_duration(wait.as_secs())
+            ),
+            Some((reason, None)) => {
+                write!(f, "Nothing would be sent due to {reason}")
+            }
+            None if self.would_update() => {
+                write!(f, "{} hostname(s) would be updated", self.hosts.len())
+            }
+            None => write!(f, "Nothing needs updating"),
+        }
+    }
+}
+
+impl Dyfi {
+    /// Detects the current address and looks up the hostnames, and tells
+    /// what the next check would do about them, without sending anything.
+    /// The decision is the one the update loop makes, for a client that has
+    /// just started.
+    pub fn plan(&mut self) -> Result<Plan, DyfiError> {
+        let ip = self.detect_ip()?;
+        let mut hosts = self.config.hostnames.clone();
+        hosts.sort();
+        let lookups = self.lookup_hosts(&hosts);
+        // As on startup, the records last known are the current ones
+        let previous: HashMap<_, _> = lookups
+            .iter()
+            .map(|(host, result)| {
+                (host.clone(), result.as_ref().cloned().unwrap_or_default())
+            })
+            .collect();
+        let decision =
+            self.decide(ip, &previous, self.manual_update, |_| lookups);
+        let held_back = match decision.verdict {
+            Verdict::Nop => None,
+            _ if self.abuse_locked => {
+                Some(("an abuse lockout".to_string(), None))
+            }
+            _ if ip.is_some_and(|ip| {
+                self.stable_count(ip) < self.config.ip_stable_checks
+            }) =>
+            {
+                Some((
+                    format!(
+                        "the address having to be seen on {} consecutive \
+                        checks first",
+                        self.config.ip_stable_checks
+                    ),
+                    None,
+                ))
+            }
+            Verdict::Released => {
+                Some(("the hostnames having been released".to_string(), None))
+            }
+            Verdict::Disallowed(outside) => Some((
+                format!("{outside} being outside of DYFI_ALLOWED_IP_RANGES"),
+                None,
+            )),
+            Verdict::Deferred {
+                retry_after,
+                reason,
+            } => Some((format!("the {reason}"), Some(retry_after))),
+            Verdict::Update => None,
+        };
+        Ok(Plan {
+            ip,
+            hosts: decision.hosts,
+            held_back,
+            verdict: decision.verdict,
+        })
+    }
+}
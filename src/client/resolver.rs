@@ -0,0 +1,108 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Looking up the current records of the hostnames. Going through a
+//! `Resolver` lets tests decide what the hostnames resolve to.
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+pub trait Resolver: Send + Sync {
+    /// All addresses `host` resolves to, non-routable ones included
+    fn lookup(&self, host: &str) -> std::io::Result<Vec<IpAddr>>;
+}
+
+/// The error of a lookup that only gave loopback or unspecified addresses,
+/// such as `127.0.0.1` or `::`. No dy.fi record holds those, so something in
+/// the local resolution chain is broken.
+#[derive(Debug)]
+pub struct BrokenResolution(pub Vec<IpAddr>);
+
+impl std::fmt::Display for BrokenResolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ips: Vec<_> = self.0.iter().map(ToString::to_string).collect();
+        write!(
+            f,
+            "resolves only to {}, hostname resolution appears to be broken",
+            ips.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for BrokenResolution {}
+
+impl BrokenResolution {
+    /// Whether `ips` are all loopback or unspecified addresses
+    pub fn check(ips: &[IpAddr]) -> Result<(), Error> {
+        if !ips.is_empty()
+            && ips.iter().all(|ip| ip.is_loopback() || ip.is_unspecified())
+        {
+            return Err(Error::other(Self(ips.to_vec())));
+        }
+        Ok(())
+    }
+
+    /// Whether `e` is a `BrokenResolution`
+    pub fn is(e: &Error) -> bool {
+        e.get_ref()
+            .is_some_and(<dyn std::error::Error + Send + Sync>::is::<Self>)
+    }
+}
+
+/// The resolver of the operating system
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        Ok((host, 0).to_socket_addrs()?.map(|x| x.ip()).collect())
+    }
+}
+
+/// Gives up on lookups of `inner` that take longer than `timeout`, with a
+/// `TimedOut` error. A lookup can't be cancelled, so one that has been given
+/// up on is left to finish on its own thread.
+pub struct TimeoutResolver<R> {
+    inner: Arc<R>,
+    timeout: Duration,
+}
+
+impl<R: Resolver + 'static> TimeoutResolver<R> {
+    pub fn new(inner: R, timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            timeout,
+        }
+    }
+}
+
+impl<R: Resolver + 'static> Resolver for TimeoutResolver<R> {
+    fn lookup(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        let (tx, rx) = mpsc::channel();
+        let inner = Arc::clone(&self.inner);
+        let name = host.to_string();
+        std::thread::Builder::new()
+            .name("resolver".to_string())
+            .spawn(move || tx.send(inner.lookup(&name)))?;
+        rx.recv_timeout(self.timeout).unwrap_or_else(|_| {
+            Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("no answer in {} ms", self.timeout.as_millis()),
+            ))
+        })
+    }
+}
@@ -0,0 +1,57 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Which families of addresses this host can reach the Internet over. Going
+//! through `Routes` lets tests decide what the routes are.
+
+use crate::util::has_route;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Addresses of a.root-servers.net, for finding out which families this
+/// host can reach the Internet over
+const ROUTE_PROBE_V4: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)), 53);
+const ROUTE_PROBE_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+    53,
+);
+
+pub trait Routes: Send {
+    /// Whether there is a route to the Internet over IPv4 and over IPv6,
+    /// from `bind` if given
+    fn available(&self, bind: Option<IpAddr>) -> (bool, bool);
+}
+
+/// The routes of the operating system
+pub struct SystemRoutes;
+
+impl Routes for SystemRoutes {
+    fn available(&self, bind: Option<IpAddr>) -> (bool, bool) {
+        (
+            has_route(ROUTE_PROBE_V4, bind),
+            has_route(ROUTE_PROBE_V6, bind),
+        )
+    }
+}
+
+/// Routes that are always the same, whether there is one over IPv4 and over
+/// IPv6, so that tests don't depend on the network they run on
+#[cfg(test)]
+impl Routes for (bool, bool) {
+    fn available(&self, _bind: Option<IpAddr>) -> (bool, bool) {
+        *self
+    }
+}
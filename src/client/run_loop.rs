@@ -14,170 +14,994 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use super::Dyfi;
-use crate::types::{DyfiResponse, DyfiResponseCode, LoopStatus};
-use crate::FORCE_UPDATE_INTERVAL;
-use std::net::{IpAddr, ToSocketAddrs};
-use std::time::{Duration, Instant};
+use super::decide::{Lookups, Verdict};
+use super::notify::UpdateEvent;
+use super::resolver::{BrokenResolution, Resolver};
+use super::{abuse_lockout, format_ips, hook, Dyfi};
+use crate::state::State;
+use crate::telemetry;
+use crate::types::{
+    CheckOutcome, CurrentIps, DyfiError, DyfiResponse, DyfiResponseCode,
+    ErrorPolicy, Hostname, LoopStatus,
+};
+use crate::util::{
+    addresses_diverge, format_duration, is_routable,
+    is_temporary_resolve_error, map_concurrently,
+};
+use crate::{FORCE_UPDATE_INTERVAL, RELEASE_WARNING_AGE};
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::time::Duration;
 
 #[cfg(not(test))]
-use crate::LOOP_DELAY;
+use crate::types::LoopSignal;
+
+/// The window in which update requests are counted against the daily limit
+pub(super) const REQUEST_WINDOW: Duration = Duration::from_hours(24);
+
+/// How many times a hostname is looked up at startup if the resolver fails
+/// temporarily
+const STARTUP_RESOLVE_ATTEMPTS: u32 = 3;
+/// How many hostnames are looked up at the same time, so as not to
+/// overwhelm the resolver
+pub(super) const MAX_CONCURRENT_RESOLVES: usize = 8;
 #[cfg(not(test))]
-use std::thread::sleep;
+const STARTUP_RESOLVE_DELAY: Duration = Duration::from_secs(1);
+#[cfg(not(test))]
+use crate::STABLE_CHECK_DELAY;
 
-#[inline]
+/// What the loop does after dy.fi has responded
+enum Outcome {
+    /// Carry on as usual
+    Done,
+    /// The response was an error, but try again after the longest backoff
+    Retry,
+    /// Stop the client with the given exit code
+    Exit(DyfiResponseCode),
+}
+
+/// Why a check of the loop can't go on
+enum Interruption {
+    /// Stop the client with the given exit code
+    Exit(DyfiResponseCode),
+    /// There is nothing to do on this check, sleep for the given number of
+    /// seconds until the next one
+    #[cfg_attr(test, allow(dead_code))]
+    Skip(u64),
+    /// The check failed, sleep for the given number of seconds and retry
+    #[cfg_attr(test, allow(dead_code))]
+    Retry(u64),
+}
+
+/// Sleeps for `secs` seconds, unless woken up early through `signal`
 #[cfg(not(test))]
-fn do_sleep(secs: u64) {
-    debug!("Sleeping {} seconds...", secs);
-    sleep(Duration::from_secs(secs));
+fn do_sleep(secs: u64, signal: &LoopSignal) {
+    debug!("Sleeping {}...", format_duration(secs));
+    signal.wait(Duration::from_secs(secs));
 }
 
 #[inline]
-fn resolve_host(host: &str) -> std::io::Result<impl Iterator<Item = IpAddr>> {
-    Ok((host, 0).to_socket_addrs()?.map(|x| x.ip()))
+pub(super) fn resolve_host(
+    resolver: &dyn Resolver,
+    host: &str,
+) -> std::io::Result<Vec<IpAddr>> {
+    // dy.fi never holds non-routable addresses, so any of those come from
+    // the local resolver and must not be compared against our own address
+    let mut ips = resolver.lookup(host)?;
+    BrokenResolution::check(&ips)?;
+    ips.retain(|ip| {
+        let routable = is_routable(ip);
+        if !routable {
+            debug!("Ignoring non-routable address {ip} for {host}");
+        }
+        routable
+    });
+    Ok(ips)
+}
+
+/// Logs if `host` has more records of a family than it's expected to
+pub(super) fn warn_extra_records(
+    host: &str,
+    records: &[IpAddr],
+    expected: usize,
+) {
+    for (family, is_v4) in [("IPv4", true), ("IPv6", false)] {
+        let count = records.iter().filter(|ip| ip.is_ipv4() == is_v4).count();
+        if count > expected {
+            info!(
+                "Host {host} has {count} {family} records, expected at most \
+                {expected}: {}",
+                format_ips(records)
+            );
+        }
+    }
+}
+
+/// Resolves `host`, retrying a few times with a short backoff if the
+/// resolver fails temporarily, as it may do right after booting. A name that
+/// doesn't exist isn't retried.
+fn resolve_host_at_startup(resolver: &dyn Resolver, host: &str) -> Vec<IpAddr> {
+    let mut attempt = 1;
+    loop {
+        match resolve_host(resolver, host) {
+            Ok(ips) => return ips,
+            Err(e) if BrokenResolution::is(&e) => {
+                warn!("{host} {e}");
+                return vec![];
+            }
+            // Retrying a resolver that doesn't answer would only hold up
+            // startup further
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                info!("Timed out resolving {host}, treating it as unresolved: {e}");
+                return vec![];
+            }
+            Err(e)
+                if is_temporary_resolve_error(&e)
+                    && attempt < STARTUP_RESOLVE_ATTEMPTS =>
+            {
+                info!(
+                    "Temporary failure resolving {host} \
+                    (attempt {attempt}/{STARTUP_RESOLVE_ATTEMPTS}): {e}"
+                );
+                #[cfg(not(test))]
+                std::thread::sleep(STARTUP_RESOLVE_DELAY * (1 << attempt));
+                attempt += 1;
+            }
+            Err(e) if is_temporary_resolve_error(&e) => {
+                info!("Unable to resolve {host}, giving up for now: {e}");
+                return vec![];
+            }
+            Err(e) => {
+                debug!("{host} doesn't resolve: {e}");
+                return vec![];
+            }
+        }
+    }
 }
 
 impl Dyfi {
     pub fn run(&mut self) -> DyfiResponseCode {
-        debug!("Resolving hostname(s)...");
-        for host in &self.config.hostnames {
-            let ips = match resolve_host(host) {
-                Ok(ips) => ips.collect(),
-                Err(_) => vec![],
-            };
-            debug!("{} currently resolves to {:?}", &host, ips);
-            self.previous_ips.insert(host.clone(), ips);
+        let code = self.run_loop();
+        if code.is_failure() {
+            self.ping_heartbeat(true);
+        }
+        code
+    }
+
+    #[cfg_attr(test, allow(clippy::never_loop))]
+    fn run_loop(&mut self) -> DyfiResponseCode {
+        if self.abuse_locked {
+            return DyfiResponseCode::Abuse;
         }
+        self.resolve_initial();
 
         loop {
-            debug!(
-                "Getting my current IP address from {}",
-                self.config.public_ip_api
-            );
-            self.my_ip = match self.get_current_ip() {
-                Ok(ip) => Some(ip),
-                Err(e) => {
-                    // we hit an error checking our current ip address.
-                    // log it and try again later.
-                    info!("{}", e);
-                    #[cfg(test)]
-                    break DyfiResponseCode::OtherNonFatal;
+            let _span = telemetry::check_span().entered();
+            self.warn_before_release();
+            if self.signal.is_shutdown() {
+                info!("Shutting down...");
+                self.shutdown();
+                break DyfiResponseCode::Ok;
+            }
+            if self.is_paused() {
+                #[cfg(test)]
+                break DyfiResponseCode::Ok;
 
-                    #[cfg(not(test))]
-                    {
-                        do_sleep(LOOP_DELAY / 4);
-                        continue;
-                    }
+                #[cfg(not(test))]
+                {
+                    do_sleep(self.config.check_interval, &self.signal);
+                    continue;
                 }
-            };
-            if let Some(ip) = self.my_ip {
-                debug!("My current IP address is {ip}");
-            } else {
-                debug!("My current IP is unknown");
             }
+            self.my_ip = match self.detect_ip_in_loop() {
+                Ok(ip) => ip,
+                Err(Interruption::Exit(code)) => break code,
+                #[cfg(test)]
+                Err(Interruption::Skip(_)) => break DyfiResponseCode::Ok,
+                #[cfg(test)]
+                Err(Interruption::Retry(_)) => {
+                    break DyfiResponseCode::OtherNonFatal
+                }
+                #[cfg(not(test))]
+                Err(Interruption::Skip(secs) | Interruption::Retry(secs)) => {
+                    do_sleep(secs, &self.signal);
+                    continue;
+                }
+            };
 
-            let dyfi_status = self.resolve_status();
+            if !self.ip_is_stable() {
+                #[cfg(test)]
+                break DyfiResponseCode::Ok;
 
-            match dyfi_status {
+                #[cfg(not(test))]
+                {
+                    do_sleep(STABLE_CHECK_DELAY, &self.signal);
+                    continue;
+                }
+            }
+
+            match self.resolve_status() {
                 LoopStatus::Action(Ok(response)) => {
-                    if let Err(e) = self.handle_ok_response(response) {
-                        break e;
+                    match self.handle_ok_response(&response) {
+                        Outcome::Exit(code) => break code,
+                        #[cfg(not(test))]
+                        Outcome::Retry => {
+                            do_sleep(self.config.retry_cap, &self.signal);
+                            continue;
+                        }
+                        #[cfg(test)]
+                        Outcome::Retry => break DyfiResponseCode::Ok,
+                        Outcome::Done => (),
                     }
                 }
-                // do_update() returned an error. This is probably a temporary
-                // HTTP error.
+                LoopStatus::Action(Err(e)) if !e.is_transient() => {
+                    error!("{e}");
+                    break DyfiResponseCode::Error;
+                }
+                // do_update() returned a temporary error, such as a timeout
                 LoopStatus::Action(Err(e)) => {
-                    error!("{}", e);
+                    if self.repeated_error.record(&e.to_string()) {
+                        error!("{}", e);
+                    }
+                    #[cfg(not(test))]
+                    {
+                        do_sleep(self.backoff.next_delay(), &self.signal);
+                        continue;
+                    }
                 }
-                LoopStatus::Nop => (),
+                LoopStatus::Deferred {
+                    retry_after,
+                    reason,
+                } => {
+                    #[cfg_attr(test, allow(unused_variables))]
+                    let delay = self.defer(retry_after, reason);
+                    #[cfg(not(test))]
+                    {
+                        do_sleep(delay, &self.signal);
+                        continue;
+                    }
+                    // Like a deferral outside of tests, this skips
+                    // check_succeeded(), which would ping the heartbeat
+                    // again
+                    #[cfg(test)]
+                    break DyfiResponseCode::Ok;
+                }
+                LoopStatus::Nop => self.heartbeat(),
             }
+            self.check_succeeded();
+
             #[cfg(test)]
             break DyfiResponseCode::Ok;
 
             #[cfg(not(test))]
-            // Sleep for LOOP_DELAY seconds.
-            do_sleep(LOOP_DELAY);
+            // Sleep until the next check
+            do_sleep(self.config.check_interval, &self.signal);
+        }
+    }
+
+    /// Detects the current address for a check of the loop, or tells what
+    /// to do instead if that fails
+    fn detect_ip_in_loop(
+        &mut self,
+    ) -> Result<Option<CurrentIps>, Interruption> {
+        match self.detect_ip() {
+            Ok(ip) => Ok(ip),
+            // Not a failure, so not counted or backed off from
+            Err(DyfiError::NoAddress(msg)) => {
+                if self.repeated_error.record(&msg) {
+                    info!("{msg}");
+                }
+                Err(Interruption::Skip(self.config.check_interval))
+            }
+            Err(e) if !e.is_transient() => {
+                error!("{e}");
+                Err(Interruption::Exit(DyfiResponseCode::Error))
+            }
+            Err(e) => {
+                if let Some(code) = self.ip_detection_failed(&e) {
+                    return Err(Interruption::Exit(code));
+                }
+                if self.ip_fallback {
+                    return Ok(None);
+                }
+                Err(Interruption::Retry(self.backoff.next_delay()))
+            }
+        }
+    }
+
+    /// Whether an update has been forced through the loop signal, e.g. by
+    /// `SIGUSR2`, and not sent yet
+    fn manual_update_requested(&mut self) -> bool {
+        if self.signal.take_force_update() {
+            info!("Forcing an update of all hostnames as requested");
+            self.manual_update = true;
+        }
+        self.manual_update
+    }
+
+    /// How many requests an update takes: each hostname is a request of its
+    /// own when they're sent one by one
+    pub(super) fn requests_per_update(&self) -> usize {
+        if self.config.drop_rejected_hostnames {
+            self.config.hostnames.len()
+        } else {
+            1
+        }
+    }
+
+    /// Whether the pause file exists. Logs only when it appears or goes away.
+    fn is_paused(&mut self) -> bool {
+        let Some(path) = &self.config.pause_file else {
+            return false;
+        };
+        let paused = path.exists();
+        if paused != self.paused {
+            if paused {
+                info!(
+                    "Paused (pause file {} present), skipping checks",
+                    path.display()
+                );
+            } else {
+                info!("Pause file {} removed, resuming", path.display());
+            }
+            self.paused = paused;
+        }
+        paused
+    }
+
+    /// Asks the public IP API(s) for the current address, unless dy.fi is
+    /// left to detect it
+    pub(super) fn detect_ip(
+        &mut self,
+    ) -> Result<Option<CurrentIps>, DyfiError> {
+        if self.config.skip_ip_check {
+            debug!("My current IP is unknown");
+            return Ok(None);
+        }
+        self.check_routes();
+        debug!(
+            "Getting my current IP address from {}",
+            self.config.public_ip_apis.join(", ")
+        );
+        let ip = match self.get_current_ip() {
+            // The public IP API has answered, only not with a wanted family
+            Err(e @ DyfiError::NoAddress(_)) => {
+                self.ip_failures = 0;
+                return Err(e);
+            }
+            result => result?,
+        };
+        debug!("My current IP address is {ip}");
+        self.ip_failures = 0;
+        if std::mem::take(&mut self.ip_fallback) {
+            info!("The current address has been detected again");
+        }
+        Ok(Some(ip))
+    }
+
+    /// Logs a temporary error detecting the current address, to be tried
+    /// again later. After `max_ip_failures` of them in a row, returns the
+    /// code to exit with, or with `ErrorPolicy::Continue`, raises an alarm
+    /// and leaves dy.fi to detect the address until the public IP APIs work
+    /// again.
+    fn ip_detection_failed(
+        &mut self,
+        e: &DyfiError,
+    ) -> Option<DyfiResponseCode> {
+        if self.repeated_error.record(&e.to_string()) {
+            info!("{e}");
+        }
+        let limit = self.config.max_ip_failures;
+        self.ip_failures = self.ip_failures.saturating_add(1);
+        if limit == 0 || self.ip_failures != limit {
+            return None;
+        }
+        if self.config.on_error == ErrorPolicy::Exit {
+            error!(
+                "Unable to detect the current address on {limit} consecutive \
+                checks, exiting..."
+            );
+            return Some(DyfiResponseCode::NoIp);
+        }
+        error!(
+            "Unable to detect the current address on {limit} consecutive \
+            checks, letting dy.fi detect it until the public IP API(s) work \
+            again"
+        );
+        self.notify(&UpdateEvent::Failing {
+            code: DyfiResponseCode::NoIp,
+            retry_after: Duration::from_secs(self.config.check_interval),
+        });
+        self.ping_heartbeat(true);
+        self.ip_fallback = true;
+        None
+    }
+
+    fn check_succeeded(&mut self) {
+        self.backoff.reset();
+        self.repeated_error.clear();
+        self.ping_heartbeat(false);
+    }
+
+    fn resolve_initial(&mut self) {
+        debug!("Resolving hostname(s)...");
+        let lookup = self.resolver.as_ref();
+        let resolved = map_concurrently(
+            &self.config.hostnames,
+            MAX_CONCURRENT_RESOLVES,
+            |host| resolve_host_at_startup(lookup, host),
+        );
+        for (host, ips) in self.config.hostnames.iter().zip(resolved) {
+            debug!("{} currently resolves to {:?}", &host, ips);
+            self.previous_ips.insert(host.clone(), ips);
+        }
+        self.warn_divergent_hosts();
+    }
+
+    /// Looks up the current records of `hosts`
+    pub(super) fn lookup_hosts(&self, hosts: &[Hostname]) -> Lookups {
+        let lookup = self.resolver.as_ref();
+        let resolved =
+            map_concurrently(hosts, MAX_CONCURRENT_RESOLVES, |host| {
+                resolve_host(lookup, host)
+            });
+        hosts.iter().cloned().zip(resolved).collect()
+    }
+
+    /// Checks the current address and updates the hostnames once if needed,
+    /// without looping, retrying or waiting for the address to stabilize
+    pub fn check_once(&mut self) -> CheckOutcome {
+        if self.abuse_locked {
+            return CheckOutcome::Failed(DyfiResponseCode::Abuse);
+        }
+        if self.is_paused() {
+            return CheckOutcome::Paused;
+        }
+        // There is no later check to send a debounced update on
+        self.one_shot = true;
+        self.resolve_initial();
+        match self.detect_ip() {
+            Ok(ip) => self.my_ip = ip,
+            Err(DyfiError::NoAddress(msg)) => {
+                info!("{msg}");
+                return CheckOutcome::NoAddress;
+            }
+            Err(e) => {
+                error!("{e}");
+                return CheckOutcome::Failed(e.code());
+            }
+        }
+        match self.resolve_status() {
+            LoopStatus::Nop => CheckOutcome::Unchanged,
+            LoopStatus::Deferred { reason, .. } => {
+                info!("Update deferred due to {reason}");
+                CheckOutcome::Deferred
+            }
+            LoopStatus::Action(Ok(response)) => {
+                match self.handle_ok_response(&response) {
+                    Outcome::Done
+                        if matches!(response, DyfiResponse::NoChg) =>
+                    {
+                        CheckOutcome::Unchanged
+                    }
+                    Outcome::Done => CheckOutcome::Updated,
+                    Outcome::Exit(code) => CheckOutcome::Failed(code),
+                    Outcome::Retry => CheckOutcome::Failed(response.code()),
+                }
+            }
+            LoopStatus::Action(Err(e)) => {
+                error!("{e}");
+                CheckOutcome::Failed(e.code())
+            }
+        }
+    }
+
+    /// Releases all configured hostnames right away, whether or not this
+    /// client has updated them, and stops the client
+    pub fn release(&mut self) -> DyfiResponseCode {
+        if self.abuse_locked {
+            return DyfiResponseCode::Abuse;
+        }
+        self.signal.shutdown();
+        self.released = true;
+        info!("Releasing {}...", self.config.hostnames.join(", "));
+        match self.send_offline(&self.config.hostnames) {
+            Ok(DyfiResponse::Good(None)) => {
+                info!("Hostname(s) released");
+                DyfiResponseCode::Ok
+            }
+            Ok(response) => {
+                response.log(self.config.other_response_logging);
+                error!("Failed to release hostname(s)");
+                if let DyfiResponse::Abuse = response {
+                    self.start_abuse_lockout();
+                }
+                match response.code() {
+                    DyfiResponseCode::Ok => DyfiResponseCode::Error,
+                    code => code,
+                }
+            }
+            Err(e) => {
+                error!("Failed to release hostname(s): {e}");
+                DyfiResponseCode::Error
+            }
+        }
+    }
+
+    /// Stops the client: a running loop exits at the next opportunity and any
+    /// later `run()` returns right away. If configured, the hostnames are
+    /// released, but only once.
+    pub fn shutdown(&mut self) {
+        self.signal.shutdown();
+        if self.config.offline_on_exit && !self.released {
+            self.release_on_exit();
+            self.released = true;
+        }
+    }
+
+    fn release_on_exit(&self) {
+        let (bound, unbound): (Vec<_>, Vec<_>) = self
+            .config
+            .hostnames
+            .iter()
+            .cloned()
+            .partition(|host| self.bound.contains(host));
+        if !unbound.is_empty() {
+            info!(
+                "Not releasing {}, not updated by this client since it started",
+                unbound.join(", ")
+            );
+        }
+        if bound.is_empty() {
+            return;
+        }
+        info!("Releasing hostname(s) before exiting...");
+        match self.send_offline(&bound) {
+            Ok(DyfiResponse::Good(None)) => {
+                info!("Hostname(s) released");
+            }
+            Ok(response) => {
+                response.log(self.config.other_response_logging);
+                error!("Failed to release hostname(s)");
+            }
+            Err(e) => error!("Failed to release hostname(s): {e}"),
+        }
+    }
+
+    /// All hostnames are updated to the same address, so warn if they
+    /// currently point to different ones
+    fn warn_divergent_hosts(&self) {
+        let lists = self.previous_ips.values().map(Vec::as_slice);
+        if !addresses_diverge(lists) {
+            return;
+        }
+        let details = self
+            .config
+            .hostnames
+            .iter()
+            .map(|host| {
+                let ips = self.previous_ips.get(host).map_or(&[][..], |x| x);
+                format!("{host}: {}", format_ips(ips))
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        warn!(
+            "Hostnames currently resolve to different addresses, all of them \
+            will be updated to the same address ({details})"
+        );
+    }
+
+    fn start_abuse_lockout(&self) {
+        if let Some(path) = &self.config.abuse_lockout_file {
+            if let Err(e) = abuse_lockout::start(path) {
+                error!("Error writing {}: {e}", path.display());
+            }
+            info!(
+                "Not sending any requests for the next {}",
+                format_duration(self.config.abuse_lockout)
+            );
+        }
+    }
+
+    /// Counts how many consecutive checks have returned the current IP
+    /// address, and tells whether that is enough to act on it
+    fn ip_is_stable(&mut self) -> bool {
+        let Some(ip) = self.my_ip else {
+            return true;
+        };
+        let count = self.stable_count(ip);
+        self.ip_candidate = Some((ip, count));
+        if count < self.config.ip_stable_checks {
+            info!(
+                "IP address {ip} seen on {count} of {} consecutive checks, \
+                waiting for it to stabilize",
+                self.config.ip_stable_checks
+            );
+            return false;
+        }
+        true
+    }
+
+    /// The number of consecutive checks `ip` will have been seen on,
+    /// counting this one
+    pub(super) fn stable_count(&self, ip: CurrentIps) -> u32 {
+        match self.ip_candidate {
+            Some((candidate, count)) if candidate == ip => count + 1,
+            _ => 1,
+        }
+    }
+
+    /// Logs a deferred update once, not on every retry, and returns the
+    /// number of seconds to sleep: only until the deferral expires, if that
+    /// happens before the next regular check
+    fn defer(&mut self, retry_after: Duration, reason: &str) -> u64 {
+        if !self.update_deferred {
+            info!(
+                "Update deferred due to {reason}, retrying in {}",
+                format_duration(retry_after.as_secs())
+            );
+            self.update_deferred = true;
+        }
+        self.ping_heartbeat(false);
+        retry_after.as_secs().clamp(1, self.config.check_interval)
+    }
+
+    /// Logs a sign of life every now and then while there's nothing to do,
+    /// so that a healthy client isn't completely silent for days
+    fn heartbeat(&mut self) {
+        let interval = Duration::from_secs(self.config.heartbeat_interval);
+        if interval.is_zero()
+            || self.clock.since(self.last_heartbeat) < interval
+        {
+            return;
         }
+        let next_forced = self
+            .since_last_update()
+            .map_or(0, |x| FORCE_UPDATE_INTERVAL.saturating_sub(x.as_secs()));
+        info!(
+            "Alive, no change. Next forced update in {}",
+            format_duration(next_forced)
+        );
+        self.last_heartbeat = self.clock.now();
     }
 
     #[inline]
     fn refresh_update_time(&mut self) {
-        self.previous_update_time = Some(Instant::now());
+        self.previous_update_time = Some(self.clock.now());
+        self.previous_update_wall = Some(self.clock.wall());
+        self.release_warned = false;
+        self.save_state();
+    }
+
+    /// Records that dy.fi has just accepted an update
+    #[cfg(test)]
+    pub(crate) fn mark_updated(&mut self) {
+        self.refresh_update_time();
+    }
+
+    /// Warns once if no update has gone through for so long that dy.fi will
+    /// soon release the hostnames, e.g. because dy.fi or the public IP API
+    /// has been failing for days
+    fn warn_before_release(&mut self) {
+        let Some(age) = self.since_last_update() else {
+            return;
+        };
+        if self.release_warned || age.as_secs() < RELEASE_WARNING_AGE {
+            return;
+        }
+        error!(
+            "The last successful update was {} ago. dy.fi releases hostnames \
+            that haven't been updated in 7 days, so {} will soon be released \
+            unless an update goes through",
+            format_duration(age.as_secs()),
+            self.config.hostnames.join(", ")
+        );
+        self.release_warned = true;
+    }
+
+    /// Time since the last update, for the forced update interval. By
+    /// default the monotonic clock is used, which isn't fooled by changes to
+    /// the system time but may stop while the machine is suspended.
+    pub(super) fn since_last_update(&self) -> Option<Duration> {
+        if self.config.wall_clock_force_interval {
+            self.previous_update_wall.map(|t| {
+                self.clock.wall().duration_since(t).unwrap_or_default()
+            })
+        } else {
+            self.previous_update_time.map(|t| self.clock.since(t))
+        }
+    }
+
+    fn save_state(&self) {
+        let Some(path) = &self.config.state_file else {
+            return;
+        };
+        let state = State {
+            last_update: if self.config.wall_clock_force_interval {
+                self.previous_update_wall
+            } else {
+                self.previous_update_time.and_then(|t| {
+                    self.clock.wall().checked_sub(self.clock.since(t))
+                })
+            },
+            previous_ips: self.previous_ips.clone(),
+            requests: self.request_times.clone(),
+            host_stats: self.host_stats.clone(),
+        };
+        if let Err(e) = state.save(path) {
+            error!("Error writing state file {}: {e}", path.display());
+        }
     }
 
     /// A command has been successfully sent to dy.fi and a response has been
     /// received. This function handles the response, which can be a success
     /// or an error.
-    fn handle_ok_response(
-        &mut self,
-        res: DyfiResponse,
-    ) -> Result<(), DyfiResponseCode> {
-        res.log();
+    fn handle_ok_response(&mut self, res: &DyfiResponse) -> Outcome {
+        telemetry::Span::current().record("response_code", res.code() as i32);
+        res.log(self.config.other_response_logging);
+        if self.config.ip_diagnostics {
+            self.diagnose_ip_mismatch(res);
+        }
         match res {
             // New IP has been set.
-            // Set previous_ip and previous_update_time.
+            // Set previous_ip and previous_update_time. dy.fi only echoes
+            // one address, so the records of the other family are kept.
             DyfiResponse::Good(Some(new_ip)) => {
-                self.previous_ips
-                    .iter_mut()
-                    .for_each(|(_, val)| *val = vec![new_ip]);
+                self.acknowledged_ip = self.my_ip;
+                for ips in self.previous_ips.values_mut() {
+                    ips.retain(|ip| ip.is_ipv4() != new_ip.is_ipv4());
+                    ips.push(*new_ip);
+                }
                 self.refresh_update_time();
+                self.notify(&UpdateEvent::Updated {
+                    hostnames: self.config.hostnames.clone(),
+                    ip: *new_ip,
+                });
             }
             // No change. Set previous_update_time.
             DyfiResponse::NoChg => {
+                self.acknowledged_ip = self.my_ip;
                 self.refresh_update_time();
             }
             // Dy.fi returned a bad status.
             // Log it and break the program loop.
             _ => {
-                error!("Unrecoverable error, exiting...");
-                return Err(DyfiResponseCode::from(res));
+                self.notify(&UpdateEvent::Rejected { code: res.code() });
+                return self.handle_fatal_response(res);
             }
         }
-        Ok(())
+        Outcome::Done
     }
 
-    /// Decide what action is needed on this iteration
-    fn resolve_status(&mut self) -> LoopStatus {
-        let force_time = Duration::from_secs(FORCE_UPDATE_INTERVAL);
-        let current_ip = self.my_ip;
-        let mut must_update = false;
-        if self
-            .previous_update_time
-            .is_some_and(|x| x.elapsed() < force_time)
-        {
-            for (host, ips) in &mut self.previous_ips {
-                if ips.is_empty() {
-                    // This means the dy.fi DNS service doesn't know about this
-                    // host and we need to tell it by running an update
-                    info!("No current IP for {host}, updating...");
-                    // ret_status = LoopStatus::Action(self.do_update());
-                    must_update = true;
-                }
-                match resolve_host(host) {
-                    Ok(new_ips) => {
-                        *ips = new_ips.collect();
-                    }
-                    Err(e) => {
-                        error!("Unable to resolve host {host}: {e}");
-                        must_update = true;
-                    }
+    /// Decides, according to the error policy, whether to exit after an
+    /// error response or to raise the alarm and try again much later, e.g.
+    /// once a rotated password has been put in place
+    fn handle_fatal_response(&mut self, res: &DyfiResponse) -> Outcome {
+        // Carrying on after `abuse` would only make things worse
+        if let DyfiResponse::Abuse = res {
+            self.start_abuse_lockout();
+        } else if self.config.on_error == ErrorPolicy::Continue {
+            let retry_after = self.config.retry_cap;
+            error!(
+                "Unrecoverable error, retrying in {} anyway...",
+                format_duration(retry_after)
+            );
+            self.notify(&UpdateEvent::Failing {
+                code: res.code(),
+                retry_after: Duration::from_secs(retry_after),
+            });
+            self.ping_heartbeat(true);
+            return Outcome::Retry;
+        }
+        error!("Unrecoverable error, exiting...");
+        Outcome::Exit(res.code())
+    }
+
+    /// Compares the address the public IP API reported with the one dy.fi
+    /// saw and the one the hostnames resolve to, and explains any difference
+    fn diagnose_ip_mismatch(&self, res: &DyfiResponse) {
+        let Some(my_ip) = self.my_ip else {
+            return;
+        };
+        match res {
+            DyfiResponse::Good(Some(echoed)) => {
+                if let Some(detected) =
+                    my_ip.of_family(echoed).filter(|ip| ip != echoed)
+                {
+                    warn!(
+                        "dy.fi set the address to {echoed}, but the public IP \
+                        API reported {detected}. The two see this host behind \
+                        different addresses, which usually means carrier-grade \
+                        NAT, asymmetric routing or a proxy on only one path."
+                    );
                 }
-                if let Some(curr_ip) = current_ip {
-                    if let Some(ip) = ips.iter_mut().find(|ip| **ip != curr_ip)
-                    {
-                        info!("Host {host} has outdated ip {ip}, updating...");
-                        must_update = true;
+            }
+            DyfiResponse::NoChg => {
+                for (host, ips) in &self.previous_ips {
+                    let changed = my_ip.changed_from(ips);
+                    if changed != CurrentIps::default() {
+                        warn!(
+                            "dy.fi reported no change, but {host} resolves to \
+                            {} instead of {changed}. The record is probably \
+                            cached by a resolver, or dy.fi sees this host \
+                            behind a different address than the public IP API.",
+                            format_ips(ips)
+                        );
                     }
                 }
             }
-        } else {
-            info!("Too long since last update or no updates yet. Updating...");
-            must_update = true;
+            _ => (),
         }
-        if must_update {
-            LoopStatus::Action(self.do_update())
-        } else {
-            LoopStatus::Nop
+    }
+
+    /// Decide what action is needed on this iteration
+    pub(crate) fn resolve_status(&mut self) -> LoopStatus {
+        self.prune_request_times();
+        let manual = self.manual_update_requested();
+        let current_ip = self.my_ip;
+        let decision =
+            self.decide(current_ip, &self.previous_ips, manual, |hosts| {
+                self.lookup_hosts(hosts)
+            });
+        self.apply_decision(&decision);
+        match decision.verdict {
+            Verdict::Update => (),
+            Verdict::Deferred {
+                retry_after,
+                reason,
+            } => {
+                return LoopStatus::Deferred {
+                    retry_after,
+                    reason,
+                }
+            }
+            Verdict::Released | Verdict::Disallowed(_) | Verdict::Nop => {
+                return LoopStatus::Nop
+            }
+        }
+        if !manual && !self.ip_confirmed(current_ip, decision.changed) {
+            return LoopStatus::Nop;
+        }
+        self.update_deferred = false;
+        self.manual_update = false;
+        let ips = decision.update_ips();
+        if let Err(e) = self.run_pre_update_hook(ips) {
+            return LoopStatus::Action(Err(e));
+        }
+        let now = self.clock.wall();
+        self.request_times
+            .extend(std::iter::repeat_n(now, self.requests_per_update()));
+        let result = self.do_update(ips);
+        self.save_state();
+        LoopStatus::Action(result)
+    }
+
+    /// With `DYFI_CONFIRM_DELAY`, if some of `ips` have `changed`, waits
+    /// and asks for the current address again, so that a momentary change
+    /// isn't sent to dy.fi. Returns whether the address is still `ips`.
+    fn ip_confirmed(
+        &mut self,
+        ips: Option<CurrentIps>,
+        changed: CurrentIps,
+    ) -> bool {
+        let delay = self.config.confirm_delay;
+        let Some(ips) = ips.filter(|_| changed != CurrentIps::default()) else {
+            return true;
+        };
+        if delay == 0 {
+            return true;
+        }
+        info!(
+            "Confirming the new address {ips} in {}...",
+            format_duration(delay)
+        );
+        #[cfg(not(test))]
+        do_sleep(delay, &self.signal);
+        if self.signal.is_shutdown() {
+            return false;
+        }
+        match self.get_current_ip() {
+            Ok(now) if now == ips => true,
+            Ok(now) => {
+                info!(
+                    "The address changed again to {now}, skipping this check"
+                );
+                false
+            }
+            Err(e) => {
+                info!("Unable to confirm the new address, skipping this check: {e}");
+                false
+            }
+        }
+    }
+
+    /// Whether the state file, not yet checked, had addresses for every
+    /// hostname
+    pub(super) fn restored_is_complete(&self) -> bool {
+        !self.restored_ips.is_empty()
+            && self.config.hostnames.iter().all(|host| {
+                self.restored_ips
+                    .get(host)
+                    .is_some_and(|ips| !ips.is_empty())
+            })
+    }
+
+    /// Returns how long until the minimum update interval has passed
+    pub(super) fn min_interval_wait(&self) -> Option<Duration> {
+        let min_interval = Duration::from_secs(self.config.min_update_interval);
+        self.previous_update_time
+            .and_then(|x| min_interval.checked_sub(self.clock.since(x)))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Forgets the update requests that are no longer in the 24 hour window
+    fn prune_request_times(&mut self) {
+        let now = self.clock.wall();
+        self.request_times.retain(|t| {
+            now.duration_since(*t)
+                .map_or(true, |age| age < REQUEST_WINDOW)
+        });
+    }
+
+    /// If sending `requests` more update requests would exceed the daily
+    /// limit, returns how long until enough of the earlier ones have aged
+    /// out of the 24 hour window
+    pub(super) fn request_limit_wait(
+        &self,
+        requests: usize,
+    ) -> Option<Duration> {
+        let limit = self.config.max_daily_requests;
+        if limit == 0 {
+            return None;
+        }
+        let now = self.clock.wall();
+        let mut times: Vec<_> = self
+            .request_times
+            .iter()
+            .filter(|t| {
+                now.duration_since(**t)
+                    .map_or(true, |age| age < REQUEST_WINDOW)
+            })
+            .collect();
+        let excess = (times.len() + requests).checked_sub(limit)?;
+        if excess == 0 {
+            return None;
+        }
+        // The window frees up once the oldest `excess` requests are outside
+        // of it
+        times.sort();
+        let expiry = *times[excess - 1] + REQUEST_WINDOW;
+        Some(
+            expiry
+                .duration_since(now)
+                .unwrap_or_default()
+                .max(Duration::from_secs(1)),
+        )
+    }
+
+    /// Runs the configured pre-update command. If it fails, the update is
+    /// skipped and retried later.
+    fn run_pre_update_hook(
+        &self,
+        ips: Option<CurrentIps>,
+    ) -> Result<(), DyfiError> {
+        let Some(cmd) = &self.config.pre_update_cmd else {
+            return Ok(());
+        };
+        let ips = ips.unwrap_or_default();
+        let mut env = vec![("DYFI_HOSTNAMES", self.config.hostnames.join(","))];
+        if let Some(ip) = ips.v4 {
+            env.push(("DYFI_MYIP", ip.to_string()));
+        }
+        if let Some(ip) = ips.v6 {
+            env.push(("DYFI_MYIP6", ip.to_string()));
         }
+        debug!("Running pre-update command '{cmd}'");
+        let timeout = Duration::from_secs(self.config.pre_update_timeout);
+        hook::run(cmd, &env, timeout).map_err(|e| {
+            DyfiError::Transient(format!(
+                "Pre-update command {e}, skipping update"
+            ))
+        })
     }
 }
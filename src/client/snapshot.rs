@@ -0,0 +1,155 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A snapshot of the client's state, for `--dump-state` and anything else
+//! that wants to look inside a running client.
+
+use super::run_loop::REQUEST_WINDOW;
+use super::Dyfi;
+use crate::state::HostStats;
+use crate::types::{CurrentIps, ErrorPolicy, Hostname, IpFamily};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+/// The settings that shape what the client does. Credentials, URLs and
+/// headers are left out, as they may contain secrets.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConfigSnapshot {
+    pub user: String,
+    pub hostnames: Vec<Hostname>,
+    pub ip_family: IpFamily,
+    pub check_interval: u64,
+    pub min_update_interval: u64,
+    pub debounce: u64,
+    pub max_daily_requests: usize,
+    pub max_ip_failures: u32,
+    pub skip_ip_check: bool,
+    pub on_error: ErrorPolicy,
+}
+
+/// Durations are in seconds and times in seconds since the Unix epoch when
+/// serialized.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StateSnapshot {
+    pub config: ConfigSnapshot,
+    /// The most recently detected address
+    pub my_ip: Option<CurrentIps>,
+    /// What the hostnames resolved to on the last check
+    pub previous_ips: BTreeMap<Hostname, Vec<IpAddr>>,
+    /// Addresses from the state file that haven't been checked yet
+    pub restored_ips: BTreeMap<Hostname, Vec<IpAddr>>,
+    /// How long ago the last update went through, by the clock used for the
+    /// forced update interval
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_secs"))]
+    pub since_last_update: Option<Duration>,
+    /// When the last update went through by the wall clock
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "serialize_epoch_secs")
+    )]
+    pub last_update_wall: Option<SystemTime>,
+    /// Update requests sent in the last 24 hours
+    pub requests_last_day: usize,
+    pub abuse_locked: bool,
+    /// Consecutive errors that are being backed off from
+    pub backoff_failures: u32,
+    /// Consecutive checks the address couldn't be detected on
+    pub ip_failures: u32,
+    /// Whether dy.fi is left to detect the address
+    pub ip_fallback: bool,
+    /// Consecutive checks each hostname has resolved to an old address
+    /// although dy.fi has accepted the current one
+    pub stale_dns: BTreeMap<Hostname, u32>,
+    pub host_stats: BTreeMap<Hostname, HostStats>,
+}
+
+#[cfg(feature = "serde")]
+impl StateSnapshot {
+    /// The snapshot as a JSON object
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a snapshot is always valid JSON")
+    }
+}
+
+/// Durations are serialized in seconds. Serde decides the signature.
+#[cfg(feature = "serde")]
+#[allow(clippy::ref_option)]
+fn serialize_secs<S: serde::Serializer>(
+    d: &Option<Duration>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(&d.map(|d| d.as_secs()), s)
+}
+
+/// Times are serialized in seconds since the Unix epoch
+#[cfg(feature = "serde")]
+#[allow(clippy::ref_option)]
+fn serialize_epoch_secs<S: serde::Serializer>(
+    t: &Option<SystemTime>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    let secs = t.map(|t| {
+        t.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    });
+    serde::Serialize::serialize(&secs, s)
+}
+
+impl Dyfi {
+    /// Takes a snapshot of the client's state. The password is never part
+    /// of it.
+    pub fn state_snapshot(&self) -> StateSnapshot {
+        let config = &self.config;
+        let now = self.clock.wall();
+        StateSnapshot {
+            config: ConfigSnapshot {
+                user: config.user.clone(),
+                hostnames: config.hostnames.clone(),
+                ip_family: config.ip_family,
+                check_interval: config.check_interval,
+                min_update_interval: config.min_update_interval,
+                debounce: config.debounce,
+                max_daily_requests: config.max_daily_requests,
+                max_ip_failures: config.max_ip_failures,
+                skip_ip_check: config.skip_ip_check,
+                on_error: config.on_error,
+            },
+            my_ip: self.my_ip,
+            previous_ips: self.previous_ips.clone().into_iter().collect(),
+            restored_ips: self.restored_ips.clone().into_iter().collect(),
+            since_last_update: self.since_last_update(),
+            last_update_wall: self.previous_update_wall,
+            requests_last_day: self
+                .request_times
+                .iter()
+                .filter(|t| {
+                    now.duration_since(**t)
+                        .map_or(true, |age| age < REQUEST_WINDOW)
+                })
+                .count(),
+            abuse_locked: self.abuse_locked,
+            backoff_failures: self.backoff.failures(),
+            ip_failures: self.ip_failures,
+            ip_fallback: self.ip_fallback,
+            stale_dns: self.stale_dns.clone().into_iter().collect(),
+            host_stats: self.host_stats.clone().into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A minimal DNS client for asking a specific nameserver for the A and AAAA
+//! records of a host, bypassing the system resolver and its caches.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Queries `server` for all A and AAAA records of `host`
+pub fn query_addresses(server: SocketAddr, host: &str) -> Result<Vec<IpAddr>> {
+    let mut out = query(server, host, TYPE_A)?;
+    out.extend(query(server, host, TYPE_AAAA)?);
+    Ok(out)
+}
+
+fn query(server: SocketAddr, host: &str, qtype: u16) -> Result<Vec<IpAddr>> {
+    let bind_addr: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(server)?;
+
+    // A random id makes it harder to slip in a forged response
+    let mut id = [0u8; 2];
+    getrandom::getrandom(&mut id).map_err(Error::other)?;
+    let query = build_query(u16::from_be_bytes(id), host, qtype)?;
+    socket.send(&query)?;
+
+    let mut buf = [0u8; 1500];
+    let len = socket.recv(&mut buf)?;
+    parse_response(&buf[..len], &query)
+}
+
+pub fn build_query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(host.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Standard query without recursion, one question
+    packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|len| (1..64).contains(len))
+            .ok_or_else(|| invalid(format!("invalid hostname '{host}'")))?;
+        packet.push(len);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+/// Extracts the addresses from the answer section of a response to `query`.
+/// The response must have the id of the query and repeat its question.
+pub fn parse_response(packet: &[u8], query: &[u8]) -> Result<Vec<IpAddr>> {
+    let question = &query[12..];
+    let echoed = packet.get(12..12 + question.len());
+    if packet.len() < 12
+        || packet[..2] != query[..2]
+        || read_u16(packet, 4)? != 1
+        || !echoed.is_some_and(|echoed| echoed.eq_ignore_ascii_case(question))
+    {
+        return Err(invalid("unexpected DNS response".to_string()));
+    }
+    // The answers of a truncated response may be incomplete
+    if packet[2] & 0x02 != 0 {
+        return Err(invalid("truncated DNS response".to_string()));
+    }
+    match packet[3] & 0x0f {
+        0 => (),
+        3 => return Ok(vec![]), // NXDOMAIN
+        rcode => {
+            return Err(invalid(format!("DNS server returned error {rcode}")))
+        }
+    }
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos)? + 4;
+    }
+    let mut out = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(packet, pos)?;
+        let rtype = read_u16(packet, pos)?;
+        let rdlength = usize::from(read_u16(packet, pos + 8)?);
+        pos += 10;
+        let rdata = packet
+            .get(pos..pos + rdlength)
+            .ok_or_else(|| invalid("truncated DNS response".to_string()))?;
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => {
+                out.push(IpAddr::from(<[u8; 4]>::try_from(rdata).unwrap()));
+            }
+            (TYPE_AAAA, 16) => {
+                out.push(IpAddr::from(<[u8; 16]>::try_from(rdata).unwrap()));
+            }
+            _ => (),
+        }
+        pos += rdlength;
+    }
+    Ok(out)
+}
+
+/// Returns the position right after the (possibly compressed) name at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *packet
+            .get(pos)
+            .ok_or_else(|| invalid("truncated DNS response".to_string()))?;
+        match len {
+            0 => return Ok(pos + 1),
+            // A compression pointer ends the name
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += usize::from(len) + 1,
+        }
+    }
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Result<u16> {
+    packet
+        .get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("truncated DNS response".to_string()))
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
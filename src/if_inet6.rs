@@ -0,0 +1,123 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Telling temporary IPv6 addresses from stable ones.
+//!
+//! With privacy extensions, outgoing connections use a temporary address
+//! that changes every few hours or days. Sending that to dy.fi would cause
+//! an update on every rotation, so the stable address of the same interface
+//! and prefix is used instead. Linux lists the addresses of each interface
+//! with their flags in `/proc/net/if_inet6`; elsewhere nothing is changed.
+
+use std::net::Ipv6Addr;
+
+const IF_INET6_PATH: &str = "/proc/net/if_inet6";
+
+// Address flags from <linux/if_addr.h>
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DADFAILED: u32 = 0x08;
+const IFA_F_DEPRECATED: u32 = 0x20;
+const IFA_F_TENTATIVE: u32 = 0x40;
+
+const SCOPE_GLOBAL: u32 = 0x00;
+
+/// An IPv6 address assigned to a local interface
+#[derive(Debug, PartialEq)]
+pub struct LocalAddr {
+    pub addr: Ipv6Addr,
+    pub interface: String,
+    pub scope: u32,
+    pub flags: u32,
+}
+
+impl LocalAddr {
+    fn is_temporary(&self) -> bool {
+        self.flags & IFA_F_TEMPORARY != 0
+    }
+
+    /// Whether the address is global, stable and fully configured
+    fn is_stable(&self) -> bool {
+        self.scope == SCOPE_GLOBAL
+            && self.flags
+                & (IFA_F_TEMPORARY
+                    | IFA_F_DADFAILED
+                    | IFA_F_DEPRECATED
+                    | IFA_F_TENTATIVE)
+                == 0
+    }
+}
+
+/// Parses the contents of `/proc/net/if_inet6`. Each line has the address
+/// as 32 hex digits, the interface index, the prefix length, the scope, the
+/// flags and the interface name. Lines that don't look like that are skipped.
+pub fn parse(contents: &str) -> Vec<LocalAddr> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [addr, _, _, scope, flags, interface] = fields[..] else {
+                return None;
+            };
+            Some(LocalAddr {
+                addr: u128::from_str_radix(addr, 16).ok()?.into(),
+                interface: interface.to_string(),
+                scope: u32::from_str_radix(scope, 16).ok()?,
+                flags: u32::from_str_radix(flags, 16).ok()?,
+            })
+        })
+        .collect()
+}
+
+/// If `ip` is a temporary address in `local`, returns a stable address of
+/// the same interface in the same /64 network
+pub fn stable_replacement(
+    ip: Ipv6Addr,
+    local: &[LocalAddr],
+) -> Option<Ipv6Addr> {
+    let temporary = local
+        .iter()
+        .find(|entry| entry.addr == ip && entry.is_temporary())?;
+    local
+        .iter()
+        .find(|entry| {
+            entry.interface == temporary.interface
+                && entry.is_stable()
+                && same_64(entry.addr, ip)
+        })
+        .map(|entry| entry.addr)
+}
+
+fn same_64(a: Ipv6Addr, b: Ipv6Addr) -> bool {
+    a.segments()[..4] == b.segments()[..4]
+}
+
+/// Replaces `ip` with a stable address of this host, if it is one of our own
+/// temporary addresses and a stable one is available
+pub fn prefer_stable(ip: Ipv6Addr) -> Ipv6Addr {
+    let Ok(contents) = std::fs::read_to_string(IF_INET6_PATH) else {
+        return ip;
+    };
+    match stable_replacement(ip, &parse(&contents)) {
+        Some(stable) => {
+            debug!(
+                "{ip} is a temporary IPv6 address, using the stable address \
+                {stable} instead"
+            );
+            stable
+        }
+        None => ip,
+    }
+}
@@ -0,0 +1,108 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A log file that is rotated by size, for running without journald.
+//!
+//! When a record would take the file past its maximum size, `dyfi.log` is
+//! renamed to `dyfi.log.1`, `dyfi.log.1` to `dyfi.log.2` and so on, the
+//! oldest one is dropped and a new `dyfi.log` is started.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub struct LogFile {
+    path: PathBuf,
+    /// Rotate before the file grows past this many bytes, or never if 0
+    max_size: u64,
+    /// How many rotated files to keep. With none, the file is emptied.
+    keep: usize,
+    /// Whether to write everything to stderr as well
+    stderr: bool,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    /// Opens `path` for appending, creating it if needed
+    pub fn open(
+        path: PathBuf,
+        max_size: u64,
+        keep: usize,
+        stderr: bool,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            keep,
+            stderr,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            match std::fs::rename(
+                numbered(&self.path, n),
+                numbered(&self.path, n + 1),
+            ) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        if self.keep > 0 {
+            std::fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// `path` with `.n` appended, e.g. `dyfi.log.1`
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+impl Write for LogFile {
+    /// Writes all of `buf`, which is one record, so that a record is never
+    /// split between two files
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len() as u64;
+        if self.max_size != 0
+            && self.size > 0
+            && self.size + len > self.max_size
+        {
+            self.rotate()?;
+        }
+        if self.stderr {
+            // Losing the copy on stderr is no reason to lose the record
+            io::stderr().write_all(buf).ok();
+        }
+        self.file.write_all(buf)?;
+        self.size += len;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
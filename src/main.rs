@@ -19,41 +19,265 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+compile_error!("Enable a TLS backend: `native-tls` or `rustls-tls`");
+
 #[cfg(test)]
 mod tests;
 
+#[macro_use]
+mod telemetry;
+
+mod backoff;
+mod cli;
 mod client;
+mod dns;
+mod if_inet6;
+mod logfile;
+mod settings;
+mod state;
 mod types;
 mod util;
+use cli::Args;
 use client::Dyfi;
-use types::{Config, Hostname};
-use util::split_to_sorted_vec;
+use logfile::LogFile;
+use settings::Settings;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+use signal_hook::iterator::Signals;
+use std::path::{Path, PathBuf};
+use types::{
+    Config, DyfiResponseCode, ErrorPolicy, Hostname, HostnameStyle, IpFamily,
+    IpRanges, OtherResponseLogging, ResponseMap, SuccessCodes,
+};
+use util::split_hostnames;
 
 const DEFAULT_PUBLIC_IP_API: &str = "http://checkip.amazonaws.com/";
 const DEFAULT_DYFI_API: &str = "https://www.dy.fi/nic/update";
 const FORCE_UPDATE_INTERVAL: u64 = 3600 * 24 * 5;
+// dy.fi releases hostnames that haven't been updated in 7 days, so warn a
+// day before that
+const RELEASE_WARNING_AGE: u64 = 3600 * 24 * 6;
+const DEFAULT_MIN_UPDATE_INTERVAL: u64 = 0;
+const DEFAULT_AUTHORITATIVE_NS: &str = "ns1.dy.fi";
+const DEFAULT_ABUSE_LOCKOUT_FILE: &str = "dyfi-client.abuse";
+const DEFAULT_ABUSE_LOCKOUT: u64 = 3600 * 24;
+const DEFAULT_IP_STABLE_CHECKS: u32 = 1;
+const DEFAULT_HEARTBEAT_INTERVAL: u64 = 3600 * 24;
+const DEFAULT_RETRY_BASE: u64 = 60;
+const DEFAULT_RETRY_CAP: u64 = 3600;
+const DEFAULT_PRE_UPDATE_TIMEOUT: u64 = 60;
+const DEFAULT_MAX_DAILY_REQUESTS: usize = 50;
+const DEFAULT_STALE_DNS_CHECKS: u32 = 3;
+const DEFAULT_RESOLVE_TIMEOUT: u64 = 10;
+const DEFAULT_LOG_MAX_SIZE: u64 = 1024 * 1024;
+const DEFAULT_LOG_KEEP: usize = 3;
+// Well above the usual round trip, even on a slow mobile connection
+const DEFAULT_SLOW_REQUEST_WARN_MS: u64 = 5000;
+const DEFAULT_CHECK_INTERVAL: u64 = 3600;
+const DEFAULT_DEBOUNCE: u64 = 0;
 
 #[cfg(not(test))]
-const LOOP_DELAY: u64 = 3600; // seconds
+const STABLE_CHECK_DELAY: u64 = 300; // seconds
 
-fn main() {
-    env_logger::init();
-    debug!("Reading configuration from environment...");
-    dotenvy::dotenv().ok();
+/// Logs to stderr or, with `DYFI_LOG_FILE`, to a file that is rotated by
+/// size. `RUST_LOG` applies first, and the `level` given with `-v` or `-q`
+/// then replaces whatever it says about this crate.
+fn init_logger(settings: &Settings, level: Option<log::LevelFilter>) {
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = level {
+        logger.filter_module(env!("CARGO_CRATE_NAME"), level);
+    }
+    let path = settings.var("DYFI_LOG_FILE").map(PathBuf::from);
+    let max_size = settings.parse("DYFI_LOG_MAX_SIZE", DEFAULT_LOG_MAX_SIZE);
+    let keep = settings.parse("DYFI_LOG_KEEP", DEFAULT_LOG_KEEP);
+    let stderr = settings.flag("DYFI_LOG_STDERR");
+    if let Some(path) = path {
+        match LogFile::open(path.clone(), max_size, keep, stderr) {
+            Ok(file) => {
+                logger.target(env_logger::Target::Pipe(Box::new(file)));
+            }
+            Err(e) => {
+                eprintln!("Error opening log file {}: {e}", path.display());
+                std::process::exit(10);
+            }
+        }
+    }
+    logger.init();
+}
 
-    let hostnames: Vec<Hostname> = split_to_sorted_vec(
-        &dotenvy::var("DYFI_HOSTNAMES").expect("DYFI_HOSTNAMES not set"),
-    );
-
-    let config = Config {
-        dyfi_api: dotenvy::var("DYFI_API")
-            .unwrap_or_else(|_| DEFAULT_DYFI_API.to_string()),
-        public_ip_api: dotenvy::var("PUBLIC_IP_API")
-            .unwrap_or_else(|_| DEFAULT_PUBLIC_IP_API.to_string()),
-        user: dotenvy::var("DYFI_USER").expect("DYFI_USERNAME not set"),
-        password: dotenvy::var("DYFI_PASSWORD").expect("DYFI_PASSWORD not set"),
+/// Prints how each notifier fared with a made-up update and returns the
+/// exit status: `10` if there are no notifiers, `11` if any of them failed
+fn test_notify(dyfi: &Dyfi) -> i32 {
+    let results = dyfi.test_notify();
+    if results.is_empty() {
+        error!("No notifiers are configured");
+        return 10;
+    }
+    let mut failed = false;
+    for result in results {
+        match result {
+            Ok(message) => println!("OK: {message}"),
+            Err(message) => {
+                println!("FAILED: {message}");
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        DyfiResponseCode::Error as i32
+    } else {
+        0
+    }
+}
+
+/// The hostnames to update. Those given on the command line replace the
+/// configured ones, including any `DYFI_HOSTNAMES` given with `--set`.
+fn configured_hostnames(settings: &Settings, args: &[String]) -> Vec<Hostname> {
+    if args.is_empty() {
+        split_hostnames(
+            &settings
+                .var("DYFI_HOSTNAMES")
+                .expect("DYFI_HOSTNAMES not set"),
+        )
+    } else {
+        split_hostnames(&args.join(","))
+    }
+}
+
+/// Reads the rest of the configuration
+fn read_config(settings: &Settings, hostnames: Vec<Hostname>) -> Config {
+    Config {
+        dyfi_api: settings
+            .var("DYFI_API")
+            .unwrap_or_else(|| DEFAULT_DYFI_API.to_string()),
+        public_ip_apis: settings.var("PUBLIC_IP_API").map_or_else(
+            || vec![DEFAULT_PUBLIC_IP_API.to_string()],
+            |s| s.split(',').map(|url| url.trim().to_string()).collect(),
+        ),
+        public_ip_apis_v6: settings
+            .var("PUBLIC_IP_API_V6")
+            .map_or_else(Vec::new, |s| {
+                s.split(',').map(|url| url.trim().to_string()).collect()
+            }),
+        ip_quorum: settings.parse("DYFI_IP_QUORUM", 1),
+        user: settings.var("DYFI_USER").expect("DYFI_USERNAME not set"),
+        password: settings
+            .var("DYFI_PASSWORD")
+            .expect("DYFI_PASSWORD not set")
+            .into(),
         hostnames,
+        min_update_interval: settings
+            .parse("DYFI_MIN_UPDATE_INTERVAL", DEFAULT_MIN_UPDATE_INTERVAL),
+        unix_proxy: settings.var("DYFI_UNIX_PROXY").map(PathBuf::from),
+        bind_address: settings.parse_opt("DYFI_BIND_ADDRESS"),
+        drop_rejected_hostnames: settings.flag("DYFI_DROP_REJECTED_HOSTNAMES"),
+        authoritative_ns: settings
+            .var("DYFI_AUTHORITATIVE_NS")
+            .unwrap_or_else(|| DEFAULT_AUTHORITATIVE_NS.to_string()),
+        offline_on_exit: settings.flag("DYFI_OFFLINE_ON_EXIT"),
+        abuse_lockout_file: settings
+            .var("DYFI_ABUSE_LOCKOUT_FILE")
+            .map(PathBuf::from)
+            .or_else(|| {
+                let state_file = settings.var("DYFI_STATE_FILE")?;
+                Some(
+                    Path::new(&state_file)
+                        .with_file_name(DEFAULT_ABUSE_LOCKOUT_FILE),
+                )
+            }),
+        abuse_lockout: settings
+            .parse("DYFI_ABUSE_LOCKOUT", DEFAULT_ABUSE_LOCKOUT),
+        ip_stable_checks: settings
+            .parse("DYFI_IP_STABLE_CHECKS", DEFAULT_IP_STABLE_CHECKS),
+        ip_output_file: settings.var("DYFI_IP_OUTPUT_FILE").map(PathBuf::from),
+        event_socket: settings.var("DYFI_EVENT_SOCKET").map(PathBuf::from),
+        heartbeat_interval: settings
+            .parse("DYFI_HEARTBEAT_INTERVAL", DEFAULT_HEARTBEAT_INTERVAL),
+        retry_base: settings.parse("DYFI_RETRY_BASE", DEFAULT_RETRY_BASE),
+        retry_cap: settings.parse("DYFI_RETRY_CAP", DEFAULT_RETRY_CAP),
+        state_file: settings.var("DYFI_STATE_FILE").map(PathBuf::from),
+        resolve_timeout: settings
+            .parse("DYFI_RESOLVE_TIMEOUT", DEFAULT_RESOLVE_TIMEOUT),
+        pause_file: settings.var("DYFI_PAUSE_FILE").map(PathBuf::from),
+        strict_content_type: settings.flag("DYFI_STRICT_CONTENT_TYPE"),
+        pre_update_cmd: settings.var("DYFI_PRE_UPDATE_CMD"),
+        pre_update_timeout: settings
+            .parse("DYFI_PRE_UPDATE_TIMEOUT", DEFAULT_PRE_UPDATE_TIMEOUT),
+        ip_api_pin: settings.var("DYFI_IP_API_PIN"),
+        heartbeat_url: settings.var("DYFI_HEARTBEAT_URL"),
+        skip_ip_check: settings.flag("DYFI_SKIP_IP_CHECK"),
+        ip_family: settings.parse("DYFI_IP_FAMILY", IpFamily::default()),
+        allowed_ip_ranges: settings
+            .parse("DYFI_ALLOWED_IP_RANGES", IpRanges::default()),
+        extra_headers: settings.var("DYFI_EXTRA_HEADERS"),
+        on_error: settings.parse("DYFI_ON_ERROR", ErrorPolicy::default()),
+        ip_diagnostics: settings.flag("DYFI_IP_DIAGNOSTICS"),
+        track_temporary_ipv6: settings.flag("DYFI_TRACK_TEMPORARY_IPV6"),
+        debounce: settings.parse("DYFI_DEBOUNCE", DEFAULT_DEBOUNCE),
+        confirm_delay: settings.parse("DYFI_CONFIRM_DELAY", 0),
+        wall_clock_force_interval: settings
+            .flag("DYFI_WALL_CLOCK_FORCE_INTERVAL"),
+        check_interval: settings
+            .parse("DYFI_CHECK_INTERVAL", DEFAULT_CHECK_INTERVAL),
+        max_daily_requests: settings
+            .parse("DYFI_MAX_DAILY_REQUESTS", DEFAULT_MAX_DAILY_REQUESTS),
+        expected_records: settings.parse("DYFI_EXPECTED_RECORDS", 1),
+        success_codes: settings
+            .parse("DYFI_SUCCESS_CODES", SuccessCodes::default()),
+        stale_dns_checks: settings
+            .parse("DYFI_STALE_DNS_CHECKS", DEFAULT_STALE_DNS_CHECKS),
+        max_ip_failures: settings.parse("DYFI_MAX_IP_FAILURES", 0),
+        slow_request_warn_ms: settings
+            .parse("DYFI_SLOW_REQUEST_WARN_MS", DEFAULT_SLOW_REQUEST_WARN_MS),
+        other_response_logging: settings
+            .parse("DYFI_LOG_OTHER_RESPONSES", OtherResponseLogging::default()),
+        response_map: settings
+            .parse("DYFI_RESPONSE_MAP", ResponseMap::default()),
+        hostname_style: settings
+            .parse("DYFI_HOSTNAME_STYLE", HostnameStyle::default()),
+    }
+}
+
+fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{}", cli::USAGE);
+            std::process::exit(10);
+        }
     };
+    // The log file is configured like everything else, so the
+    // configuration is read before the logger is set up
+    let level = args.log_level();
+    dotenvy::dotenv().ok();
+    let settings = Settings::new(args.settings, |name| dotenvy::var(name).ok());
+    init_logger(&settings, level);
+    if args.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+
+    debug!("Reading configuration from environment...");
+
+    let hostnames = configured_hostnames(&settings, &args.hostnames);
+    let mut config = read_config(&settings, hostnames);
+    let unknown = settings.unknown_overrides();
+    if !unknown.is_empty() {
+        error!(
+            "Unknown setting(s) given with --set: {}",
+            unknown.join(", ")
+        );
+        std::process::exit(10);
+    }
+    // These modes run alongside the daemon, whose notifiers they mustn't
+    // take over
+    if args.query || args.plan || args.dump_state || args.offline {
+        config.ip_output_file = None;
+        config.event_socket = None;
+    }
+    // The password is redacted from the debug output
+    debug!("Configuration: {config:?}");
+    let success = config.success_codes.clone();
     let mut dyfi = match Dyfi::from(config) {
         Ok(dyfi) => dyfi,
         Err(e) => {
@@ -62,5 +286,76 @@ fn main() {
         }
     };
 
-    std::process::exit(dyfi.run() as i32)
+    // Read-only mode: only show what dy.fi currently has registered
+    if args.query {
+        std::process::exit(success.exit_status(dyfi.report_records() as i32))
+    }
+
+    // Dry run: tell what the next check would do
+    if args.plan {
+        let code = match dyfi.plan() {
+            Ok(plan) => {
+                println!("{plan}");
+                0
+            }
+            Err(e) => {
+                error!("{e}");
+                DyfiResponseCode::Error as i32
+            }
+        };
+        std::process::exit(success.exit_status(code))
+    }
+
+    // Debugging: show what the client knows, including the state file
+    if args.dump_state {
+        #[cfg(feature = "serde")]
+        {
+            println!("{}", dyfi.state_snapshot().to_json());
+            return;
+        }
+        #[cfg(not(feature = "serde"))]
+        {
+            error!("Dumping the state requires building with serde");
+            std::process::exit(10);
+        }
+    }
+
+    // Decommissioning: take the hostnames offline without updating them
+    if args.offline {
+        std::process::exit(success.exit_status(dyfi.release() as i32))
+    }
+
+    if args.once {
+        std::process::exit(success.exit_status(dyfi.check_once().exit_code()))
+    }
+
+    // Checking the notifiers without waiting for the address to change
+    if args.test_notify {
+        std::process::exit(test_notify(&dyfi))
+    }
+
+    let mut signals = match Signals::new([SIGTERM, SIGINT, SIGHUP, SIGUSR2]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Error registering signal handlers: {e}");
+            std::process::exit(10);
+        }
+    };
+    let loop_signal = dyfi.loop_signal();
+    let shutdown_handle = dyfi.shutdown_handle();
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                info!("Received SIGHUP, checking for updates now");
+                loop_signal.wake();
+            } else if signal == SIGUSR2 {
+                info!("Received SIGUSR2, forcing an update now");
+                loop_signal.force_update();
+            } else {
+                shutdown_handle.shutdown();
+            }
+        }
+    });
+
+    std::process::exit(success.exit_status(dyfi.run() as i32))
 }
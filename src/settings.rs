@@ -0,0 +1,146 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Where the settings come from. Each setting is named after its environment
+//! variable. A value given with `--set` on the command line takes precedence
+//! over the environment, which in turn takes precedence over the `.env` file.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The names of all settings, so that a typo given with `--set` can be told
+/// apart from a setting that just isn't used in this run
+pub const KNOWN_SETTINGS: &[&str] = &[
+    "DYFI_ABUSE_LOCKOUT",
+    "DYFI_ABUSE_LOCKOUT_FILE",
+    "DYFI_ALLOWED_IP_RANGES",
+    "DYFI_API",
+    "DYFI_AUTHORITATIVE_NS",
+    "DYFI_BIND_ADDRESS",
+    "DYFI_CHECK_INTERVAL",
+    "DYFI_CONFIRM_DELAY",
+    "DYFI_DEBOUNCE",
+    "DYFI_DROP_REJECTED_HOSTNAMES",
+    "DYFI_EVENT_SOCKET",
+    "DYFI_EXPECTED_RECORDS",
+    "DYFI_EXTRA_HEADERS",
+    "DYFI_HEARTBEAT_INTERVAL",
+    "DYFI_HEARTBEAT_URL",
+    "DYFI_HOSTNAMES",
+    "DYFI_HOSTNAME_STYLE",
+    "DYFI_IP_API_PIN",
+    "DYFI_IP_DIAGNOSTICS",
+    "DYFI_IP_FAMILY",
+    "DYFI_IP_OUTPUT_FILE",
+    "DYFI_IP_QUORUM",
+    "DYFI_IP_STABLE_CHECKS",
+    "DYFI_LOG_FILE",
+    "DYFI_LOG_KEEP",
+    "DYFI_LOG_MAX_SIZE",
+    "DYFI_LOG_OTHER_RESPONSES",
+    "DYFI_LOG_STDERR",
+    "DYFI_MAX_DAILY_REQUESTS",
+    "DYFI_MAX_IP_FAILURES",
+    "DYFI_MIN_UPDATE_INTERVAL",
+    "DYFI_OFFLINE_ON_EXIT",
+    "DYFI_ON_ERROR",
+    "DYFI_PASSWORD",
+    "DYFI_PAUSE_FILE",
+    "DYFI_PRE_UPDATE_CMD",
+    "DYFI_PRE_UPDATE_TIMEOUT",
+    "DYFI_RESOLVE_TIMEOUT",
+    "DYFI_RESPONSE_MAP",
+    "DYFI_RETRY_BASE",
+    "DYFI_RETRY_CAP",
+    "DYFI_SKIP_IP_CHECK",
+    "DYFI_SLOW_REQUEST_WARN_MS",
+    "DYFI_STALE_DNS_CHECKS",
+    "DYFI_STATE_FILE",
+    "DYFI_STRICT_CONTENT_TYPE",
+    "DYFI_SUCCESS_CODES",
+    "DYFI_TRACK_TEMPORARY_IPV6",
+    "DYFI_UNIX_PROXY",
+    "DYFI_USER",
+    "DYFI_WALL_CLOCK_FORCE_INTERVAL",
+    "PUBLIC_IP_API",
+    "PUBLIC_IP_API_V6",
+];
+
+pub struct Settings {
+    /// Values given with `--set`, by upper-case name
+    overrides: HashMap<String, String>,
+    /// Looks up a variable in the environment, into which the `.env` file
+    /// has been loaded without replacing what was already set
+    env: Box<Lookup>,
+}
+
+/// Looks up a variable by name
+type Lookup = dyn Fn(&str) -> Option<String>;
+
+impl Settings {
+    pub fn new(
+        overrides: Vec<(String, String)>,
+        env: impl Fn(&str) -> Option<String> + 'static,
+    ) -> Self {
+        Self {
+            overrides: overrides.into_iter().collect(),
+            env: Box::new(env),
+        }
+    }
+
+    pub fn var(&self, name: &str) -> Option<String> {
+        self.overrides
+            .get(name)
+            .cloned()
+            .or_else(|| (self.env)(name))
+    }
+
+    /// Reads an optional setting, falling back to `default` if it is not
+    /// set. Panics if the value can't be parsed.
+    pub fn parse<T: FromStr>(&self, name: &str, default: T) -> T {
+        self.parse_opt(name).unwrap_or(default)
+    }
+
+    /// Reads an optional setting. Panics if the value is set but can't be
+    /// parsed.
+    pub fn parse_opt<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.var(name).map(|s| {
+            s.trim().parse().unwrap_or_else(|_| {
+                panic!("{name} has an invalid value: '{s}'")
+            })
+        })
+    }
+
+    /// Reads an optional boolean flag. Unset means `false`.
+    pub fn flag(&self, name: &str) -> bool {
+        self.var(name).is_some_and(|s| {
+            matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes")
+        })
+    }
+
+    /// The names given with `--set` that aren't settings, most likely
+    /// because of a typo
+    pub fn unknown_overrides(&self) -> Vec<&str> {
+        let mut unknown: Vec<_> = self
+            .overrides
+            .keys()
+            .filter(|name| !KNOWN_SETTINGS.contains(&name.as_str()))
+            .map(String::as_str)
+            .collect();
+        unknown.sort_unstable();
+        unknown
+    }
+}
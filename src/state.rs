@@ -0,0 +1,261 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! State that is kept on disk between runs of the client.
+//!
+//! The file is plain text with one `key = value` pair per line, starting with
+//! the schema version and ending with an `end` line:
+//!
+//! ```text
+//! version = 1
+//! last_update = 1700000000
+//! host = a.dy.fi 192.0.2.1 2001:db8::1
+//! requests = 1699990000 1700000000
+//! stats = a.dy.fi 12 11 nohost
+//! end
+//! ```
+//!
+//! Unknown keys are ignored, so that newer versions can add fields without
+//! breaking older ones. A file without the `end` line has been cut short and
+//! is not trusted.
+
+use crate::types::Hostname;
+use crate::util::write_atomic;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct State {
+    /// When dy.fi last accepted an update
+    pub last_update: Option<SystemTime>,
+    /// The addresses the hostnames were last updated to
+    pub previous_ips: HashMap<Hostname, Vec<IpAddr>>,
+    /// When recent update requests were sent, for the daily request limit
+    pub requests: Vec<SystemTime>,
+    /// How the updates of each hostname have gone
+    pub host_stats: HashMap<Hostname, HostStats>,
+}
+
+/// How the updates of one hostname have gone, for telling which of many
+/// hostnames keeps failing
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HostStats {
+    /// Update requests that included the hostname
+    pub attempts: u64,
+    /// Those that dy.fi answered `good` or `nochg` to
+    pub successes: u64,
+    /// What went wrong on the latest failed request, on one line
+    pub last_error: Option<String>,
+}
+
+impl HostStats {
+    /// Counts a request, which failed if there is an `error`
+    pub fn record(&mut self, error: Option<&str>) {
+        self.attempts = self.attempts.saturating_add(1);
+        match error {
+            None => self.successes = self.successes.saturating_add(1),
+            Some(e) => self.last_error = Some(e.to_string()),
+        }
+    }
+}
+
+impl State {
+    /// Reads the state saved at `path`. A missing, damaged or incompatible
+    /// file means there is no prior state; the last two are logged.
+    pub fn load(path: &Path) -> Option<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No state file at {}", path.display());
+                return None;
+            }
+            Err(e) => {
+                warn!("Error reading state file {}: {e}", path.display());
+                return None;
+            }
+        };
+        match Self::parse(&contents) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Ignoring state file {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Writes the state to `path` so that a crash never leaves a partial file
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        write_atomic(path, self.serialize().as_bytes())
+    }
+
+    /// Forgets times later than `now`. They can only come from a clock that
+    /// was wrong when the state was saved, and trusting them would hold back
+    /// updates until the clock catches up.
+    pub fn discard_future_times(&mut self, now: SystemTime) {
+        if self.last_update.is_some_and(|t| t > now) {
+            warn!(
+                "The last update in the state file is in the future, \
+                ignoring it"
+            );
+            self.last_update = None;
+        }
+        let count = self.requests.len();
+        self.requests.retain(|t| *t <= now);
+        if self.requests.len() < count {
+            warn!(
+                "Ignoring {} update request time(s) in the future in the \
+                state file",
+                count - self.requests.len()
+            );
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut lines = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        let version = lines
+            .next()
+            .and_then(|line| parse_line(line).filter(|(k, _)| *k == "version"))
+            .ok_or("missing schema version")?
+            .1;
+        match version.parse::<u32>() {
+            Ok(STATE_VERSION) => (),
+            Ok(v) => return Err(format!("unsupported schema version {v}")),
+            Err(_) => {
+                return Err(format!("invalid schema version '{version}'"))
+            }
+        }
+
+        let mut state = Self::default();
+        let mut complete = false;
+        for line in lines {
+            if line == "end" {
+                complete = true;
+                break;
+            }
+            let (key, value) = parse_line(line)
+                .ok_or_else(|| format!("invalid line '{line}'"))?;
+            match key {
+                "last_update" => {
+                    let secs = value.parse().map_err(|_| {
+                        format!("invalid last_update '{value}'")
+                    })?;
+                    state.last_update =
+                        Some(UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                "host" => {
+                    let mut parts = value.split_whitespace();
+                    let host = parts
+                        .next()
+                        .ok_or_else(|| format!("invalid line '{line}'"))?;
+                    let ips = parts
+                        .map(str::parse)
+                        .collect::<Result<Vec<IpAddr>, _>>()
+                        .map_err(|_| format!("invalid line '{line}'"))?;
+                    state.previous_ips.insert(host.to_string(), ips);
+                }
+                "requests" => {
+                    state.requests = value
+                        .split_whitespace()
+                        .map(|secs| {
+                            secs.parse().map(|secs| {
+                                UNIX_EPOCH + Duration::from_secs(secs)
+                            })
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| format!("invalid line '{line}'"))?;
+                }
+                "stats" => {
+                    let invalid = || format!("invalid line '{line}'");
+                    let mut parts = value.splitn(4, ' ');
+                    let host = parts.next().ok_or_else(invalid)?;
+                    let mut count = || {
+                        parts
+                            .next()
+                            .and_then(|n| n.parse().ok())
+                            .ok_or_else(invalid)
+                    };
+                    let entry = HostStats {
+                        attempts: count()?,
+                        successes: count()?,
+                        last_error: parts.next().map(ToString::to_string),
+                    };
+                    state.host_stats.insert(host.to_string(), entry);
+                }
+                _ => debug!("Ignoring unknown state field '{key}'"),
+            }
+        }
+        if !complete {
+            return Err("file is truncated".to_string());
+        }
+        Ok(state)
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut out = format!("version = {STATE_VERSION}\n");
+        if let Some(secs) = self.last_update.and_then(unix_secs) {
+            let _ = writeln!(out, "last_update = {secs}");
+        }
+        let mut hosts: Vec<_> = self.previous_ips.iter().collect();
+        hosts.sort();
+        for (host, ips) in hosts {
+            let _ = write!(out, "host = {host}");
+            for ip in ips {
+                let _ = write!(out, " {ip}");
+            }
+            out.push('\n');
+        }
+        if !self.requests.is_empty() {
+            out.push_str("requests =");
+            for secs in self.requests.iter().filter_map(|t| unix_secs(*t)) {
+                let _ = write!(out, " {secs}");
+            }
+            out.push('\n');
+        }
+        let mut stats: Vec<_> = self.host_stats.iter().collect();
+        stats.sort_by(|a, b| a.0.cmp(b.0));
+        for (host, stats) in stats {
+            let _ = write!(
+                out,
+                "stats = {host} {} {}",
+                stats.attempts, stats.successes
+            );
+            if let Some(e) = &stats.last_error {
+                let _ = write!(out, " {e}");
+            }
+            out.push('\n');
+        }
+        out.push_str("end\n");
+        out
+    }
+}
+
+fn unix_secs(t: SystemTime) -> Option<u64> {
+    t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    line.split_once('=').map(|(k, v)| (k.trim(), v.trim()))
+}
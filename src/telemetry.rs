@@ -0,0 +1,68 @@
+// Dyfi-client, a dynamic DNS updater for the dy.fi service.
+// Copyright (C) 2020-2023  Ronja Koistinen
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Structured spans for the optional `tracing` feature. Log messages still
+//! go through `log`; with `tracing-log` they become events inside these
+//! spans. Without a `tracing` subscriber, the spans themselves are logged
+//! under the `tracing::span` target. Without the feature, everything here
+//! compiles to nothing.
+
+/// Creates an info-level span with the given name and fields, using the
+/// syntax of `tracing::info_span!`
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($($args:tt)*) => {
+        tracing::info_span!($($args)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($($args:tt)*) => {
+        $crate::telemetry::Span
+    };
+}
+
+#[cfg(feature = "tracing")]
+pub use tracing::Span;
+
+/// The span of one iteration of the main loop
+pub fn check_span() -> Span {
+    span!(
+        "check",
+        ip = tracing::field::Empty,
+        response_code = tracing::field::Empty
+    )
+}
+
+/// Stands in for `tracing::Span` when the feature is disabled
+#[cfg(not(feature = "tracing"))]
+pub struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub fn current() -> Self {
+        Self
+    }
+
+    pub fn entered(self) -> Self {
+        self
+    }
+
+    pub fn record<V>(&self, _field: &str, _value: V) -> &Self {
+        self
+    }
+}
@@ -0,0 +1,33 @@
+use crate::client::api_health::{ApiHealth, REPROBE_INTERVAL};
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_api_health_prefers_first() {
+    let health = ApiHealth::new(3);
+    assert_eq!(health.order(Instant::now()), vec![0, 1, 2]);
+}
+
+#[test]
+fn test_api_health_fails_over_and_back() {
+    let mut health = ApiHealth::new(3);
+    let now = Instant::now();
+    assert!(health.record_failure(0, now));
+    assert!(!health.record_failure(0, now));
+    // The failed API is only tried if the others fail too
+    assert_eq!(health.order(now), vec![1, 2, 0]);
+    assert_eq!(health.order(now + Duration::from_mins(1)), vec![1, 2, 0]);
+    // After a while it is tried first again
+    assert_eq!(health.order(now + REPROBE_INTERVAL), vec![0, 1, 2]);
+    assert!(health.record_success(0));
+    assert!(!health.record_success(0));
+    assert_eq!(health.order(now), vec![0, 1, 2]);
+}
+
+#[test]
+fn test_api_health_all_failed() {
+    let mut health = ApiHealth::new(2);
+    let now = Instant::now();
+    health.record_failure(1, now);
+    health.record_failure(0, now + Duration::from_secs(1));
+    assert_eq!(health.order(now + Duration::from_secs(2)), vec![0, 1]);
+}
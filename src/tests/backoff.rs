@@ -0,0 +1,25 @@
+use crate::backoff::Backoff;
+
+#[test]
+fn test_backoff_grows_and_is_capped() {
+    let mut backoff = Backoff::new(10, 300);
+    let mut ceilings = Vec::new();
+    for _ in 0..10 {
+        let ceiling = backoff.ceiling();
+        let delay = backoff.next_delay();
+        assert!((1..=ceiling).contains(&delay), "{delay} > {ceiling}");
+        ceilings.push(ceiling);
+    }
+    assert_eq!(ceilings, vec![10, 20, 40, 80, 160, 300, 300, 300, 300, 300]);
+}
+
+#[test]
+fn test_backoff_reset() {
+    let mut backoff = Backoff::new(10, 300);
+    for _ in 0..100 {
+        backoff.next_delay();
+    }
+    assert_eq!(backoff.ceiling(), 300);
+    backoff.reset();
+    assert_eq!(backoff.ceiling(), 10);
+}
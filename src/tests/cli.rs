@@ -0,0 +1,97 @@
+use crate::cli::Args;
+
+fn parse(args: &[&str]) -> Result<Args, String> {
+    Args::parse(args.iter().map(ToString::to_string))
+}
+
+#[test]
+fn test_parse_no_args() {
+    assert_eq!(parse(&[]).unwrap(), Args::default());
+}
+
+#[test]
+fn test_parse_hostnames() {
+    let args =
+        parse(&["--hostname", "b.dy.fi", "--hostname=a.dy.fi", "--query"])
+            .unwrap();
+    assert_eq!(args.hostnames, vec!["b.dy.fi", "a.dy.fi"]);
+    assert!(args.query);
+}
+
+#[test]
+fn test_parse_set() {
+    let args = parse(&[
+        "--set",
+        "dyfi_debounce=60",
+        "--set=DYFI_PRE_UPDATE_CMD=echo a=b",
+        "--set",
+        "DYFI_STATE_FILE=",
+    ])
+    .unwrap();
+    assert_eq!(
+        args.settings,
+        vec![
+            ("DYFI_DEBOUNCE".to_string(), "60".to_string()),
+            ("DYFI_PRE_UPDATE_CMD".to_string(), "echo a=b".to_string()),
+            ("DYFI_STATE_FILE".to_string(), String::new()),
+        ]
+    );
+    assert!(parse(&["--set", "DYFI_DEBOUNCE"]).is_err());
+    assert!(parse(&["--set", "=60"]).is_err());
+    assert!(parse(&["--set"]).is_err());
+}
+
+#[test]
+fn test_parse_missing_value() {
+    assert!(parse(&["--hostname"]).is_err());
+}
+
+#[test]
+fn test_parse_unknown_option() {
+    assert!(parse(&["--frobnicate"]).is_err());
+}
+
+#[test]
+fn test_parse_once() {
+    assert!(parse(&["--once"]).unwrap().once);
+    assert!(!parse(&["--query"]).unwrap().once);
+}
+
+#[test]
+fn test_parse_test_notify() {
+    assert!(parse(&["--test-notify"]).unwrap().test_notify);
+    assert!(!parse(&["--once"]).unwrap().test_notify);
+}
+
+#[test]
+fn test_parse_offline() {
+    assert!(parse(&["--offline"]).unwrap().offline);
+    assert!(!parse(&["--once"]).unwrap().offline);
+}
+
+#[test]
+fn test_parse_plan() {
+    assert!(parse(&["--plan"]).unwrap().plan);
+    assert!(!parse(&["--query"]).unwrap().plan);
+}
+
+#[test]
+fn test_parse_dump_state() {
+    assert!(parse(&["--dump-state"]).unwrap().dump_state);
+    assert!(!parse(&["--plan"]).unwrap().dump_state);
+}
+
+#[test]
+fn test_parse_verbosity() {
+    use log::LevelFilter;
+    let level = |args: &[&str]| parse(args).unwrap().log_level();
+    assert_eq!(level(&[]), None);
+    assert_eq!(level(&["-v"]), Some(LevelFilter::Info));
+    assert_eq!(level(&["--verbose", "-v"]), Some(LevelFilter::Debug));
+    assert_eq!(level(&["-vv"]), Some(LevelFilter::Debug));
+    assert_eq!(level(&["-vvv"]), Some(LevelFilter::Trace));
+    assert_eq!(level(&["-vvvvv"]), Some(LevelFilter::Trace));
+    assert_eq!(level(&["--quiet"]), Some(LevelFilter::Error));
+    assert!(parse(&["-q", "-v"]).is_err());
+    assert!(parse(&["-vx"]).is_err());
+}
@@ -0,0 +1,94 @@
+use crate::dns::{build_query, parse_response, TYPE_A, TYPE_AAAA};
+use std::net::IpAddr;
+
+fn response_for(query: &[u8], answers: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut packet = query.to_vec();
+    packet[2] |= 0x84; // response, authoritative
+    packet[7] = u8::try_from(answers.len()).unwrap();
+    for (rtype, rdata) in answers {
+        // Pointer to the name in the question section
+        packet.extend_from_slice(&[0xc0, 12]);
+        packet.extend_from_slice(&rtype.to_be_bytes());
+        packet.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+        packet.extend_from_slice(
+            &u16::try_from(rdata.len()).unwrap().to_be_bytes(),
+        );
+        packet.extend_from_slice(rdata);
+    }
+    packet
+}
+
+#[test]
+fn test_build_query() {
+    let query = build_query(0x1234, "mock.dy.fi", TYPE_A).unwrap();
+    assert_eq!(&query[..2], &[0x12, 0x34]);
+    assert_eq!(&query[12..24], b"\x04mock\x02dy\x02fi\x00");
+    assert_eq!(&query[24..], &[0, 1, 0, 1]);
+}
+
+#[test]
+fn test_build_query_invalid_hostname() {
+    assert!(build_query(1, "mock..dy.fi", TYPE_A).is_err());
+}
+
+#[test]
+fn test_parse_response() {
+    let query = build_query(7, "mock.dy.fi", TYPE_A).unwrap();
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+    let IpAddr::V6(v6_octets) = v6 else {
+        unreachable!()
+    };
+    let response = response_for(
+        &query,
+        &[(TYPE_A, &[192, 0, 2, 1]), (28, &v6_octets.octets())],
+    );
+    assert_eq!(
+        parse_response(&response, &query).unwrap(),
+        vec!["192.0.2.1".parse::<IpAddr>().unwrap(), v6]
+    );
+}
+
+#[test]
+fn test_parse_response_wrong_id() {
+    let query = build_query(7, "mock.dy.fi", TYPE_A).unwrap();
+    let response = response_for(&query, &[(TYPE_A, &[192, 0, 2, 1])]);
+    let other = build_query(8, "mock.dy.fi", TYPE_A).unwrap();
+    assert!(parse_response(&response, &other).is_err());
+}
+
+#[test]
+fn test_parse_response_wrong_question() {
+    let query = build_query(7, "mock.dy.fi", TYPE_A).unwrap();
+    let other = build_query(7, "other.dy.fi", TYPE_A).unwrap();
+    let response = response_for(&other, &[(TYPE_A, &[192, 0, 2, 1])]);
+    assert!(parse_response(&response, &query).is_err());
+    let aaaa = build_query(7, "mock.dy.fi", TYPE_AAAA).unwrap();
+    assert!(parse_response(&response_for(&aaaa, &[]), &query).is_err());
+    // Names are case-insensitive
+    let upper = build_query(7, "MOCK.dy.fi", TYPE_A).unwrap();
+    let response = response_for(&upper, &[(TYPE_A, &[192, 0, 2, 1])]);
+    assert_eq!(parse_response(&response, &query).unwrap().len(), 1);
+}
+
+#[test]
+fn test_parse_response_tc_bit() {
+    let query = build_query(7, "mock.dy.fi", TYPE_A).unwrap();
+    let mut response = response_for(&query, &[(TYPE_A, &[192, 0, 2, 1])]);
+    response[2] |= 0x02;
+    assert!(parse_response(&response, &query).is_err());
+}
+
+#[test]
+fn test_parse_response_nxdomain() {
+    let query = build_query(7, "mock.dy.fi", TYPE_A).unwrap();
+    let mut response = query.clone();
+    response[3] = 3;
+    assert!(parse_response(&response, &query).unwrap().is_empty());
+}
+
+#[test]
+fn test_parse_response_truncated() {
+    let query = build_query(7, "mock.dy.fi", TYPE_A).unwrap();
+    let response = response_for(&query, &[(TYPE_A, &[192, 0, 2, 1])]);
+    assert!(parse_response(&response[..response.len() - 2], &query).is_err());
+}
@@ -0,0 +1,70 @@
+use crate::if_inet6::{parse, stable_replacement, LocalAddr};
+use std::net::Ipv6Addr;
+
+// Addresses of eth0 with privacy extensions: a stable SLAAC address, a
+// current and a deprecated temporary one and a link-local one
+const IF_INET6: &str = "\
+00000000000000000000000000000001 01 80 10 80       lo
+20010db8000000010000000000000010 02 40 00 00     eth0
+20010db80000000112345678abcdef01 02 40 00 01     eth0
+20010db8000000019999888877776666 02 40 00 21     eth0
+fe80000000000000021122fffe334455 02 40 20 80     eth0
+20010db8000000020000000000000020 03 40 00 00     eth1
+";
+
+fn ip(s: &str) -> Ipv6Addr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn test_parse() {
+    let addrs = parse(IF_INET6);
+    assert_eq!(addrs.len(), 6);
+    assert_eq!(
+        addrs[2],
+        LocalAddr {
+            addr: ip("2001:db8:0:1:1234:5678:abcd:ef01"),
+            interface: "eth0".to_string(),
+            scope: 0,
+            flags: 1,
+        }
+    );
+}
+
+#[test]
+fn test_parse_skips_garbage() {
+    assert!(parse("not an entry\n\nzz 01 40 00 00 eth0\n").is_empty());
+}
+
+#[test]
+fn test_temporary_replaced_by_stable() {
+    let addrs = parse(IF_INET6);
+    assert_eq!(
+        stable_replacement(ip("2001:db8:0:1:1234:5678:abcd:ef01"), &addrs),
+        Some(ip("2001:db8:0:1::10"))
+    );
+    // Deprecated temporary addresses are still temporary
+    assert_eq!(
+        stable_replacement(ip("2001:db8:0:1:9999:8888:7777:6666"), &addrs),
+        Some(ip("2001:db8:0:1::10"))
+    );
+}
+
+#[test]
+fn test_stable_and_foreign_addresses_kept() {
+    let addrs = parse(IF_INET6);
+    assert_eq!(stable_replacement(ip("2001:db8:0:1::10"), &addrs), None);
+    assert_eq!(stable_replacement(ip("2001:db8::1"), &addrs), None);
+}
+
+#[test]
+fn test_no_stable_address_in_same_network() {
+    let addrs = parse(
+        "20010db80000000112345678abcdef01 02 40 00 01 eth0\n\
+        20010db8000000020000000000000020 02 40 00 00 eth0\n",
+    );
+    assert_eq!(
+        stable_replacement(ip("2001:db8:0:1:1234:5678:abcd:ef01"), &addrs),
+        None
+    );
+}
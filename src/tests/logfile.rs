@@ -0,0 +1,77 @@
+use super::TempPath;
+use crate::logfile::LogFile;
+use std::fs;
+use std::io::Write;
+
+fn read(dir: &std::path::Path, name: &str) -> String {
+    fs::read_to_string(dir.join(name)).unwrap_or_default()
+}
+
+#[test]
+fn test_log_file_rotation() {
+    let dir = TempPath::dir("rotation");
+    let path = dir.join("dyfi.log");
+    let mut log = LogFile::open(path, 5, 2, false).unwrap();
+    for record in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+        log.write_all(record.as_bytes()).unwrap();
+    }
+    log.flush().unwrap();
+    let files = (
+        read(&dir, "dyfi.log"),
+        read(&dir, "dyfi.log.1"),
+        read(&dir, "dyfi.log.2"),
+    );
+    let dropped = dir.join("dyfi.log.3").exists();
+    assert_eq!(
+        files,
+        (
+            "five\n".to_string(),
+            "four\n".to_string(),
+            "three\n".to_string()
+        )
+    );
+    assert!(!dropped);
+}
+
+/// A record bigger than the maximum size still goes to the file whole
+#[test]
+fn test_log_file_large_record() {
+    let dir = TempPath::dir("large");
+    let path = dir.join("dyfi.log");
+    let mut log = LogFile::open(path, 4, 1, false).unwrap();
+    log.write_all(b"a long record\n").unwrap();
+    log.write_all(b"another\n").unwrap();
+    let files = (read(&dir, "dyfi.log"), read(&dir, "dyfi.log.1"));
+    assert_eq!(
+        files,
+        ("another\n".to_string(), "a long record\n".to_string())
+    );
+}
+
+/// Without rotated files to keep, the file starts over, and an existing
+/// file counts towards the size
+#[test]
+fn test_log_file_keep_none() {
+    let dir = TempPath::dir("keep-none");
+    let path = dir.join("dyfi.log");
+    fs::write(&path, "old\n").unwrap();
+    let mut log = LogFile::open(path, 8, 0, false).unwrap();
+    log.write_all(b"new\n").unwrap();
+    log.write_all(b"newer\n").unwrap();
+    let contents = read(&dir, "dyfi.log");
+    let rotated = dir.join("dyfi.log.1").exists();
+    assert_eq!(contents, "newer\n");
+    assert!(!rotated);
+}
+
+#[test]
+fn test_log_file_no_max_size() {
+    let dir = TempPath::dir("no-max-size");
+    let path = dir.join("dyfi.log");
+    let mut log = LogFile::open(path, 0, 3, false).unwrap();
+    for _ in 0..100 {
+        log.write_all(b"record\n").unwrap();
+    }
+    let contents = read(&dir, "dyfi.log");
+    assert_eq!(contents.len(), 700);
+}
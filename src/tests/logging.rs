@@ -0,0 +1,64 @@
+//! A logger for tests that, besides printing each record, keeps the records
+//! logged by each thread so that tests can check what was logged
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+static LOGGER: CapturingLogger = CapturingLogger;
+
+thread_local! {
+    static CAPTURED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("dyfi_client")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("{} {}", record.level(), record.args());
+        // Printed with eprintln! so that the test harness only shows it for
+        // failing tests
+        eprintln!("{line}");
+        CAPTURED.with(|captured| captured.borrow_mut().push(line));
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init() {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Debug);
+    });
+    CAPTURED.with(|captured| captured.borrow_mut().clear());
+}
+
+/// The lines logged so far by the current thread, as "LEVEL message"
+pub fn captured() -> Vec<String> {
+    CAPTURED.with(|captured| captured.borrow().clone())
+}
+
+/// Asserts that the current thread has logged a line containing `pattern`
+pub fn assert_logged(pattern: &str) {
+    let lines = captured();
+    assert!(
+        lines.iter().any(|line| line.contains(pattern)),
+        "no log line contains '{pattern}':\n{}",
+        lines.join("\n")
+    );
+}
+
+/// Asserts that the current thread hasn't logged `text` anywhere
+pub fn assert_not_logged(text: &str) {
+    if let Some(line) = captured().iter().find(|line| line.contains(text)) {
+        panic!("'{text}' was logged: {line}");
+    }
+}
@@ -14,22 +14,103 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::client::Dyfi;
-use crate::types::Config;
+// Some of the older tests pass their format arguments positionally
+#![allow(clippy::uninlined_format_args)]
+
+mod api_health;
+mod backoff;
+mod cli;
+mod dns;
+mod if_inet6;
+mod logfile;
+mod logging;
+#[cfg(all(unix, feature = "serde"))]
+mod notify;
+#[cfg(feature = "rustls-tls")]
+mod pinning;
+mod plan;
+mod resolve_status;
+mod resolver;
+mod settings;
+mod snapshot;
+mod state;
+mod types;
+mod util;
+
+use crate::client::decide::{Decision, Reason, Verdict};
+use crate::client::notify::{Notifier, UpdateEvent};
+use crate::client::routes::{Routes, SystemRoutes};
+use crate::client::{parse_headers, Dyfi};
 use crate::types::DyfiResponseCode;
+use crate::types::{
+    CheckOutcome, Config, DyfiError, ErrorPolicy, Hostname, HostnameStyle,
+    IpFamily, IpRanges, OtherResponseLogging, ResponseMap, SuccessCodes,
+};
 use crate::util::split_to_sorted_vec;
 use mockito::{Matcher, Mock};
+use std::collections::HashMap;
 use std::env;
-use std::sync::Once;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-static INIT: Once = Once::new();
 const MOCK_IP: &str = "192.0.2.1"; // RFC 5737
+const MOCK_IP6: &str = "2001:db8::1"; // RFC 3849
 
 fn log_init() {
-    env::set_var("RUST_LOG", "dyfi_client=debug");
-    INIT.call_once(|| {
-        env_logger::init();
-    });
+    logging::init();
+}
+
+/// A path in the temporary directory that is removed, along with anything
+/// beneath it, when the test is done with it
+pub(super) struct TempPath(PathBuf);
+
+impl TempPath {
+    /// A path of its own for `name` in this test run, with whatever an
+    /// earlier run left there removed
+    pub(super) fn new(name: &str) -> Self {
+        let path = env::temp_dir()
+            .join(format!("dyfi-client-test-{}.{name}", std::process::id()));
+        let path = TempPath(path);
+        path.remove();
+        path
+    }
+
+    /// An empty directory
+    pub(super) fn dir(name: &str) -> Self {
+        let path = Self::new(name);
+        std::fs::create_dir(&path).unwrap();
+        path
+    }
+
+    fn remove(&self) {
+        if self.0.is_dir() {
+            std::fs::remove_dir_all(&self.0).ok();
+        } else {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+impl std::ops::Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
 }
 
 struct TestServer {
@@ -47,10 +128,54 @@ impl TestServer {
         let hostnames = split_to_sorted_vec("mock.dy.fi,mock-some-more.dy.fi");
         Config {
             dyfi_api: format!("{}{}", self.server.url(), "/nic/update"),
-            public_ip_api: self.server.url(),
+            public_ip_apis: vec![self.server.url()],
+            public_ip_apis_v6: vec![],
+            ip_quorum: 1,
             user: String::from("mockuser"),
-            password: String::from("mockpassword"),
+            password: String::from("mockpassword").into(),
             hostnames,
+            min_update_interval: 0,
+            unix_proxy: None,
+            bind_address: None,
+            drop_rejected_hostnames: false,
+            authoritative_ns: String::from("127.0.0.1"),
+            offline_on_exit: false,
+            abuse_lockout_file: None,
+            abuse_lockout: 3600,
+            ip_stable_checks: 1,
+            ip_output_file: None,
+            event_socket: None,
+            heartbeat_interval: 0,
+            retry_base: 60,
+            retry_cap: 3600,
+            state_file: None,
+            pause_file: None,
+            resolve_timeout: 10,
+            strict_content_type: false,
+            pre_update_cmd: None,
+            pre_update_timeout: 5,
+            other_response_logging: OtherResponseLogging::Truncated,
+            response_map: ResponseMap::default(),
+            hostname_style: HostnameStyle::Joined,
+            ip_api_pin: None,
+            heartbeat_url: None,
+            skip_ip_check: false,
+            ip_family: IpFamily::Both,
+            allowed_ip_ranges: IpRanges::default(),
+            extra_headers: None,
+            on_error: ErrorPolicy::Exit,
+            ip_diagnostics: false,
+            debounce: 0,
+            confirm_delay: 0,
+            track_temporary_ipv6: false,
+            wall_clock_force_interval: false,
+            check_interval: 3600,
+            max_daily_requests: 0,
+            expected_records: 1,
+            success_codes: SuccessCodes::default(),
+            stale_dns_checks: 3,
+            max_ip_failures: 0,
+            slow_request_warn_ms: 5000,
         }
     }
 
@@ -66,6 +191,17 @@ impl TestServer {
             .expect(1)
     }
 
+    /// A successful update to `MOCK_IP`, which doesn't match `offline`
+    /// requests
+    fn update_mock(&mut self) -> Mock {
+        self.dyfi_mock_base()
+            .match_query(Matcher::UrlEncoded(
+                "myip".to_string(),
+                MOCK_IP.to_string(),
+            ))
+            .with_body(format!("good {MOCK_IP}"))
+    }
+
     fn get_ip_mock(&mut self) -> Mock {
         self.server
             .mock("GET", "/")
@@ -103,6 +239,42 @@ fn test_update_badauth() {
     assert_eq!(code, DyfiResponseCode::BadAuth);
 }
 
+#[test]
+fn test_update_compatible_service() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .server
+        .mock("GET", "/nic/update")
+        .match_query(Matcher::UrlEncoded(
+            "hostname".to_string(),
+            "home.example.net".to_string(),
+        ))
+        .with_body("911")
+        .create();
+    let mut config = server.make_test_config();
+    config.hostnames = vec!["home.example.net".to_string()];
+    config.response_map = "911=badauth".parse().unwrap();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::BadAuth);
+}
+
+#[test]
+fn test_config_other_hostname_needs_compatible_service() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.dyfi_api = "https://www.dy.fi/nic/update".to_string();
+    config.hostnames = vec!["home.example.net".to_string()];
+    assert!(config.validate().is_err());
+    config.response_map = "911=dnserr".parse().unwrap();
+    assert!(config.validate().is_ok());
+    config.hostnames = vec!["bad..example.net".to_string()];
+    assert!(config.validate().is_err());
+}
+
 #[test]
 fn test_update_nohost() {
     log_init();
@@ -110,20 +282,85 @@ fn test_update_nohost() {
     let get_ip = server.get_ip_mock();
     let response = server
         .dyfi_mock_base()
-        .match_query(Matcher::AnyOf(vec![
-            Matcher::Missing,
-            Matcher::Regex("".to_string()),
-            Matcher::Regex("hostname=".to_string()),
-        ]))
+        .match_query(Matcher::UrlEncoded(
+            "hostname".to_string(),
+            "not-mine.dy.fi".to_string(),
+        ))
         .with_body("nohost")
         .create();
     let mut config = server.make_test_config();
-    config.hostnames = vec!["".to_string()];
-    let code = Dyfi::from(config).unwrap().run();
+    config.hostnames = vec!["not-mine.dy.fi".to_string()];
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let code = dyfi.run();
     get_ip.assert();
     response.assert();
     response.matched();
     assert_eq!(code, DyfiResponseCode::NoHost);
+    let stats = &dyfi.state_snapshot().host_stats["not-mine.dy.fi"];
+    assert_eq!((stats.attempts, stats.successes), (1, 0));
+    assert_eq!(stats.last_error.as_deref(), Some("nohost"));
+}
+
+/// A keyword of a compatible service is handled like the dy.fi response it
+/// has been mapped to, instead of as an unrecognized one
+#[test]
+fn test_update_mapped_response() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("!donator").create();
+    let mut config = server.make_test_config();
+    config.response_map = "!donator=badauth".parse().unwrap();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::BadAuth);
+}
+
+#[test]
+fn test_update_repeated_hostnames() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .server
+        .mock("GET", "/nic/update")
+        .match_query(Matcher::Regex(format!(
+            "^hostname=mock-some-more.dy.fi&hostname=mock.dy.fi&myip={MOCK_IP}$"
+        )))
+        .with_body(format!("good {MOCK_IP}"))
+        .expect(1)
+        .create();
+    let mut config = server.make_test_config();
+    config.hostname_style = HostnameStyle::Repeated;
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_update_repeated_hostnames_compatible_service() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .server
+        .mock("GET", "/nic/update")
+        .match_query(Matcher::Regex(format!(
+            "^hostname=a.example.net&hostname=b.example.net&myip={MOCK_IP}$"
+        )))
+        .with_body(format!("good {MOCK_IP}"))
+        .expect(1)
+        .create();
+    let mut config = server.make_test_config();
+    config.hostnames =
+        vec!["a.example.net".to_string(), "b.example.net".to_string()];
+    config.hostname_style = HostnameStyle::Repeated;
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
 }
 
 #[test]
@@ -136,6 +373,24 @@ fn test_config_nohost() {
     assert!(dyfi.is_err());
 }
 
+#[test]
+fn test_config_empty_user() {
+    log_init();
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.user = String::new();
+    assert!(Dyfi::from(config).is_err());
+}
+
+#[test]
+fn test_config_empty_password() {
+    log_init();
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.password = String::new().into();
+    assert!(Dyfi::from(config).is_err());
+}
+
 #[test]
 fn test_update_notfqdn() {
     log_init();
@@ -145,12 +400,13 @@ fn test_update_notfqdn() {
         .dyfi_mock_base()
         .match_query(Matcher::UrlEncoded(
             "hostname".to_string(),
-            "example.com,something-outrageous".to_string(),
+            "example.dy.fi,something-outrageous.dy.fi".to_string(),
         ))
         .with_body("notfqdn")
         .create();
     let mut config = server.make_test_config();
-    config.hostnames = split_to_sorted_vec("something-outrageous,example.com");
+    config.hostnames =
+        split_to_sorted_vec("something-outrageous.dy.fi,example.dy.fi");
     let code = Dyfi::from(config).unwrap().run();
     get_ip.assert();
     response.assert();
@@ -223,3 +479,2454 @@ fn test_update_abuse() {
         }
     }
 }
+
+#[test]
+fn test_update_deferred_by_min_interval() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.min_update_interval = 3600;
+    let get_ip = server.get_ip_mock().expect(2);
+    let response = server
+        .dyfi_mock_base()
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    // The hostnames still don't resolve to the new address, but the second
+    // update must be held back by the minimum update interval.
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+    logging::assert_logged("Update deferred due to minimum update interval");
+}
+
+#[test]
+fn test_config_unix_proxy_unsupported() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.unix_proxy = Some(env::temp_dir());
+    let errors = config.validate().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), crate::types::UNIX_PROXY_UNSUPPORTED);
+}
+
+#[test]
+fn test_update_drop_rejected_hostname() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.drop_rejected_hostnames = true;
+    let get_ip = server.get_ip_mock();
+    let good = server
+        .dyfi_mock_base()
+        .match_query(Matcher::UrlEncoded(
+            "hostname".to_string(),
+            "mock.dy.fi".to_string(),
+        ))
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let rejected = server
+        .dyfi_mock_base()
+        .match_query(Matcher::UrlEncoded(
+            "hostname".to_string(),
+            "mock-some-more.dy.fi".to_string(),
+        ))
+        .with_body("notfqdn")
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    good.assert();
+    rejected.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_update_drop_rejected_all_hostnames() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.drop_rejected_hostnames = true;
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .with_body("nohost")
+        .expect(2)
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::NoHost);
+}
+
+#[test]
+fn test_offline_on_exit() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.offline_on_exit = true;
+    let get_ip = server.get_ip_mock();
+    let update = server.update_mock().create();
+    let release = server
+        .dyfi_mock_base()
+        .match_query(Matcher::AllOf(vec![
+            Matcher::UrlEncoded(
+                "hostname".to_string(),
+                "mock-some-more.dy.fi,mock.dy.fi".to_string(),
+            ),
+            Matcher::UrlEncoded("offline".to_string(), "YES".to_string()),
+        ]))
+        .with_body("good")
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    dyfi.loop_signal().shutdown();
+    let code = dyfi.run();
+    get_ip.assert();
+    update.assert();
+    release.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_release() {
+    log_init();
+    let mut server = TestServer::new();
+    let config = server.make_test_config();
+    let get_ip = server.get_ip_mock().expect(0);
+    let release = server
+        .dyfi_mock_base()
+        .match_query(Matcher::AllOf(vec![
+            Matcher::UrlEncoded(
+                "hostname".to_string(),
+                "mock-some-more.dy.fi,mock.dy.fi".to_string(),
+            ),
+            Matcher::UrlEncoded("offline".to_string(), "YES".to_string()),
+        ]))
+        .with_body("good")
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.release(), DyfiResponseCode::Ok);
+    // Released hostnames are never updated or released again
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    dyfi.shutdown();
+    get_ip.assert();
+    release.assert();
+}
+
+#[test]
+fn test_release_rejected() {
+    log_init();
+    let mut server = TestServer::new();
+    let config = server.make_test_config();
+    let release = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .with_body("badauth")
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.release(), DyfiResponseCode::BadAuth);
+    release.assert();
+}
+
+#[test]
+fn test_no_offline_without_update() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.offline_on_exit = true;
+    let get_ip = server.get_ip_mock().expect(0);
+    let release = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .with_body("good")
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.loop_signal().shutdown();
+    let code = dyfi.run();
+    get_ip.assert();
+    release.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged("Not releasing mock-some-more.dy.fi, mock.dy.fi");
+}
+
+#[test]
+fn test_no_offline_on_fatal_error() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.offline_on_exit = true;
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .with_body("badauth")
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    // Only the update, no offline request
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::BadAuth);
+}
+
+#[test]
+fn test_current_ip_html_body() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server
+        .server
+        .mock("GET", "/")
+        .with_status(200)
+        .with_header("content-type", "text/html")
+        .with_body("<!DOCTYPE html>\n<html><body>Captive portal</body></html>")
+        .create();
+    let dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let err = dyfi.get_current_ip().unwrap_err();
+    get_ip.assert();
+    assert!(
+        err.to_string().contains("got '<!DOCTYPE html> <html>"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_update_dual_stack() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server
+        .server
+        .mock("GET", "/")
+        .with_status(200)
+        .with_header("content-type", "text/plain")
+        .with_body(format!("{MOCK_IP}\n{MOCK_IP6}\n"))
+        .create();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::AllOf(vec![
+            Matcher::UrlEncoded(
+                "hostname".to_string(),
+                "mock-some-more.dy.fi,mock.dy.fi".to_string(),
+            ),
+            Matcher::UrlEncoded("myip".to_string(), MOCK_IP.to_string()),
+            Matcher::UrlEncoded("myip6".to_string(), MOCK_IP6.to_string()),
+        ]))
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let code = Dyfi::from(server.make_test_config()).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_abuse_lockout() {
+    log_init();
+    let mut server = TestServer::new();
+    let lockout_file = TempPath::new("abuse");
+    let mut config = server.make_test_config();
+    config.abuse_lockout_file = Some(lockout_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("abuse").create();
+    let code = Dyfi::from(config).unwrap().run();
+    assert_eq!(code, DyfiResponseCode::Abuse);
+    assert!(lockout_file.exists());
+
+    // A restarted client must not send any requests during the lockout
+    let mut config = server.make_test_config();
+    config.abuse_lockout_file = Some(lockout_file.to_path_buf());
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Abuse);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_abuse_lockout_symlink() {
+    log_init();
+    let mut server = TestServer::new();
+    let target = TempPath::new("target");
+    let lockout_file = TempPath::new("link");
+    std::fs::write(&target, "untouched").unwrap();
+    std::os::unix::fs::symlink(&target, &lockout_file).unwrap();
+    let mut config = server.make_test_config();
+    config.abuse_lockout_file = Some(lockout_file.to_path_buf());
+    let _get_ip = server.get_ip_mock();
+    let _response = server.dyfi_mock_base().with_body("abuse").create();
+    let code = Dyfi::from(config).unwrap().run();
+    let contents = std::fs::read_to_string(&target).unwrap();
+    assert_eq!(code, DyfiResponseCode::Abuse);
+    assert_eq!(contents, "untouched");
+}
+
+#[test]
+fn test_update_waits_for_stable_ip() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.ip_stable_checks = 2;
+    let get_ip = server.get_ip_mock().expect(2);
+    let response = server.dyfi_mock_base().with_body("nochg").create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    // The first check only records the address
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    assert!(!response.matched());
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+}
+
+#[test]
+fn test_ip_output_file() {
+    log_init();
+    let mut server = TestServer::new();
+    let output_file = TempPath::new("ip");
+    let mut config = server.make_test_config();
+    config.ip_output_file = Some(output_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("nochg").create();
+    let code = Dyfi::from(config).unwrap().run();
+    let contents = std::fs::read_to_string(&output_file).unwrap();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    assert_eq!(contents, format!("{MOCK_IP}\n"));
+}
+
+#[test]
+fn test_config_debug_redacts_password() {
+    let server = TestServer::new();
+    let config = server.make_test_config();
+    let debug = format!("{config:?}");
+    assert!(!debug.contains("mockpassword"), "{debug}");
+    assert!(debug.contains("<redacted>"));
+}
+
+fn ip_api_mock(server: &mut TestServer, path: &str, body: &str) -> Mock {
+    server
+        .server
+        .mock("GET", path)
+        .with_status(200)
+        .with_header("content-type", "text/plain")
+        .with_body(body)
+        .create()
+}
+
+#[test]
+fn test_current_ip_failover() {
+    log_init();
+    let mut server = TestServer::new();
+    let failing = server.server.mock("GET", "/a").with_status(503).create();
+    let working = ip_api_mock(&mut server, "/b", MOCK_IP);
+    let mut config = server.make_test_config();
+    let url = server.server.url();
+    config.public_ip_apis = vec![format!("{url}/a"), format!("{url}/b")];
+    let ip = Dyfi::from(config).unwrap().get_current_ip().unwrap();
+    failing.assert();
+    working.assert();
+    assert_eq!(ip.to_string(), MOCK_IP);
+}
+
+#[test]
+fn test_current_ip_failover_remembers_failure() {
+    log_init();
+    let mut server = TestServer::new();
+    let failing = server
+        .server
+        .mock("GET", "/a")
+        .with_status(503)
+        .expect(1)
+        .create();
+    let working = ip_api_mock(&mut server, "/b", MOCK_IP).expect(2);
+    let mut config = server.make_test_config();
+    let url = server.server.url();
+    config.public_ip_apis = vec![format!("{url}/a"), format!("{url}/b")];
+    let dyfi = Dyfi::from(config).unwrap();
+    dyfi.get_current_ip().unwrap();
+    // The failed API isn't asked again right away
+    let ip = dyfi.get_current_ip().unwrap();
+    failing.assert();
+    working.assert();
+    assert_eq!(ip.to_string(), MOCK_IP);
+    logging::assert_logged("failing over");
+}
+
+/// A client asking `/v4` for the address and `/v6` for the IPv6 address
+fn dual_stack_config(server: &TestServer) -> Config {
+    let mut config = server.make_test_config();
+    let url = server.server.url();
+    config.public_ip_apis = vec![format!("{url}/v4")];
+    config.public_ip_apis_v6 = vec![format!("{url}/v6")];
+    config
+}
+
+#[test]
+fn test_current_ip_separate_ipv6_api() {
+    log_init();
+    let mut server = TestServer::new();
+    let v4 = ip_api_mock(&mut server, "/v4", MOCK_IP);
+    let v6 = ip_api_mock(&mut server, "/v6", MOCK_IP6);
+    let dyfi = Dyfi::from(dual_stack_config(&server)).unwrap();
+    let ip = dyfi.get_current_ip().unwrap();
+    v4.assert();
+    v6.assert();
+    assert_eq!(ip.to_string(), format!("{MOCK_IP}, {MOCK_IP6}"));
+}
+
+/// When the IPv6 API fails, the IPv4 address is still updated, without
+/// touching the IPv6 records
+#[test]
+fn test_ipv6_api_failing() {
+    log_init();
+    let mut server = TestServer::new();
+    let v4 = ip_api_mock(&mut server, "/v4", MOCK_IP);
+    let v6 = server.server.mock("GET", "/v6").with_status(503).create();
+    let response = server
+        .update_mock()
+        // Only the IPv4 address is sent
+        .match_query(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("myip".to_string(), MOCK_IP.to_string()),
+            Matcher::Regex("^hostname=[^&]*&myip=[^&]*$".to_string()),
+        ]))
+        .create();
+    let mut dyfi = Dyfi::from(dual_stack_config(&server)).unwrap();
+    let resolver = resolve_status::FakeResolver::default();
+    for host in resolve_status::HOSTS {
+        resolver.set(host, &["192.0.2.99", "2001:db8::99"]);
+    }
+    dyfi.set_resolver(Box::new(resolver));
+    let code = dyfi.run();
+    v4.assert();
+    v6.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged(
+        "INFO No IPv6 address detected on this check, leaving the IPv6 \
+        records alone",
+    );
+    let snapshot = dyfi.state_snapshot();
+    assert!(snapshot
+        .previous_ips
+        .values()
+        .all(|ips| ips.contains(&"2001:db8::99".parse().unwrap())));
+}
+
+#[test]
+fn test_ipv4_api_failing() {
+    log_init();
+    let mut server = TestServer::new();
+    let v4 = server.server.mock("GET", "/v4").with_status(503).create();
+    let v6 = ip_api_mock(&mut server, "/v6", MOCK_IP6);
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("myip6".to_string(), MOCK_IP6.to_string()),
+            Matcher::Regex("^hostname=[^&]*&myip6=[^&]*$".to_string()),
+        ]))
+        .with_body(format!("good {MOCK_IP6}"))
+        .create();
+    let code = Dyfi::from(dual_stack_config(&server)).unwrap().run();
+    v4.assert();
+    v6.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged("No IPv4 address detected on this check");
+}
+
+/// Both failing is still an error
+#[test]
+fn test_ip_apis_of_both_families_failing() {
+    log_init();
+    let mut server = TestServer::new();
+    let v4 = server.server.mock("GET", "/v4").with_status(503).create();
+    let v6 = server.server.mock("GET", "/v6").with_status(503).create();
+    let dyfi = Dyfi::from(dual_stack_config(&server)).unwrap();
+    assert!(dyfi.get_current_ip().unwrap_err().is_transient());
+    v4.assert();
+    v6.assert();
+}
+
+#[test]
+fn test_current_ip_quorum() {
+    log_init();
+    let mut server = TestServer::new();
+    let mocks = [
+        ip_api_mock(&mut server, "/a", MOCK_IP),
+        ip_api_mock(&mut server, "/b", "198.51.100.1"),
+        ip_api_mock(&mut server, "/c", MOCK_IP),
+    ];
+    let mut config = server.make_test_config();
+    let url = server.server.url();
+    config.public_ip_apis = ["a", "b", "c"]
+        .iter()
+        .map(|p| format!("{url}/{p}"))
+        .collect();
+    config.ip_quorum = 2;
+    let ip = Dyfi::from(config).unwrap().get_current_ip().unwrap();
+    mocks.iter().for_each(Mock::assert);
+    assert_eq!(ip.to_string(), MOCK_IP);
+}
+
+#[test]
+fn test_current_ip_no_quorum() {
+    log_init();
+    let mut server = TestServer::new();
+    let _a = ip_api_mock(&mut server, "/a", MOCK_IP);
+    let _b = ip_api_mock(&mut server, "/b", "198.51.100.1");
+    let mut config = server.make_test_config();
+    let url = server.server.url();
+    config.public_ip_apis = vec![format!("{url}/a"), format!("{url}/b")];
+    config.ip_quorum = 2;
+    assert!(Dyfi::from(config).unwrap().get_current_ip().is_err());
+}
+
+#[test]
+fn test_config_validate_ip_quorum() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.public_ip_apis =
+        (0..4).map(|i| format!("http://{i}.test/")).collect();
+    config.ip_quorum = 2;
+    let errors = config.validate().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].to_string(),
+        "IP quorum of 2 is not a majority of the 4 public IP APIs"
+    );
+    config.ip_quorum = 3;
+    assert!(config.validate().is_ok());
+    // A quorum of one takes the first answer instead of counting them
+    config.ip_quorum = 1;
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_shutdown_handle_from_other_thread() {
+    fn assert_send<T: Send + Clone>(_: &T) {}
+
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock().expect(0);
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let handle = dyfi.shutdown_handle();
+    assert_send(&handle);
+    std::thread::spawn(move || handle.shutdown())
+        .join()
+        .unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+}
+
+#[test]
+fn test_shutdown_releases_once() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.offline_on_exit = true;
+    let get_ip = server.get_ip_mock();
+    let update = server.update_mock().create();
+    let release = server
+        .dyfi_mock_base()
+        .match_query(Matcher::UrlEncoded(
+            "offline".to_string(),
+            "YES".to_string(),
+        ))
+        .with_body("good")
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    dyfi.shutdown();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    update.assert();
+    release.assert();
+}
+
+#[test]
+fn test_state_file_written_after_update() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("state");
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    let state = crate::state::State::load(&state_file);
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    let state = state.unwrap();
+    assert!(state.last_update.is_some());
+    assert!(state
+        .previous_ips
+        .values()
+        .all(|ips| ips == &[MOCK_IP.parse::<std::net::IpAddr>().unwrap()]));
+}
+
+#[test]
+fn test_bind_address() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.bind_address = Some("127.0.0.1".parse().unwrap());
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("nochg").create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_strict_content_type() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.strict_content_type = true;
+    let get_ip = server
+        .server
+        .mock("GET", "/")
+        .with_status(200)
+        .with_header("content-type", "text/plain; charset=utf-8")
+        .with_body(MOCK_IP)
+        .create();
+    let response = server
+        .server
+        .mock("GET", "/nic/update")
+        .match_query(Matcher::Any)
+        .with_status(200)
+        .with_header("content-type", "text/html")
+        .with_body("badauth")
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    // The body isn't parsed as a fatal response but treated as an error from
+    // do_update(), which is logged and retried later
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_strict_content_type_ip_api() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.strict_content_type = true;
+    let get_ip = server
+        .server
+        .mock("GET", "/")
+        .with_status(200)
+        .with_header("content-type", "text/html")
+        .with_body(MOCK_IP)
+        .create();
+    let dyfi = Dyfi::from(config).unwrap();
+    assert!(dyfi.get_current_ip().is_err());
+    get_ip.assert();
+}
+
+#[test]
+fn test_restored_state_with_changed_ip() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("restored");
+    let mut state = crate::state::State {
+        last_update: Some(std::time::SystemTime::now()),
+        ..Default::default()
+    };
+    for host in ["mock.dy.fi", "mock-some-more.dy.fi"] {
+        state
+            .previous_ips
+            .insert(host.to_string(), vec!["192.0.2.99".parse().unwrap()]);
+    }
+    state.save(&state_file).unwrap();
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::UrlEncoded(
+            "myip".to_string(),
+            MOCK_IP.to_string(),
+        ))
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_host_stats_survive_restart() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("stats");
+    let mut state = crate::state::State::default();
+    state.host_stats.insert(
+        "mock.dy.fi".to_string(),
+        crate::state::HostStats {
+            attempts: 2,
+            successes: 1,
+            last_error: Some("dnserr".to_string()),
+        },
+    );
+    state.save(&state_file).unwrap();
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    let state = crate::state::State::load(&state_file);
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    let saved = state.unwrap().host_stats;
+    assert_eq!(
+        saved["mock.dy.fi"],
+        crate::state::HostStats {
+            attempts: 3,
+            successes: 2,
+            last_error: Some("dnserr".to_string()),
+        }
+    );
+    assert_eq!(saved["mock-some-more.dy.fi"].successes, 1);
+}
+
+#[test]
+fn test_connection_error_is_transient() {
+    // Nothing listens on port 1
+    let e = reqwest::blocking::get("http://127.0.0.1:1/").unwrap_err();
+    assert!(DyfiError::from(e).is_transient());
+}
+
+#[test]
+fn test_connection_refused_is_network_error() {
+    let e = reqwest::blocking::get("http://127.0.0.1:1/").unwrap_err();
+    let e = DyfiError::from(e);
+    assert!(matches!(e, DyfiError::Network(_)), "{e:?}");
+    assert!(e.to_string().starts_with("Network unavailable"), "{e}");
+    assert_eq!(e.code(), DyfiResponseCode::NoNetwork);
+}
+
+#[test]
+fn test_ip_api_status_is_transient() {
+    log_init();
+    let mut server = TestServer::new();
+    let config = server.make_test_config();
+    let unavailable = server
+        .server
+        .mock("GET", "/")
+        .with_status(503)
+        .expect(1)
+        .create();
+    let dyfi = Dyfi::from(config).unwrap();
+    assert!(dyfi.get_current_ip().unwrap_err().is_transient());
+    unavailable.assert();
+    unavailable.remove();
+
+    let not_found = server
+        .server
+        .mock("GET", "/")
+        .with_status(404)
+        .expect(1)
+        .create();
+    assert!(!dyfi.get_current_ip().unwrap_err().is_transient());
+    not_found.assert();
+}
+
+#[test]
+fn test_ip_api_empty_body() {
+    log_init();
+    let mut server = TestServer::new();
+    let config = server.make_test_config();
+    let empty = server
+        .server
+        .mock("GET", "/")
+        .with_header("content-type", "text/plain")
+        .with_body(" \n")
+        .expect(2)
+        .create();
+    let response = server.dyfi_mock_base().expect(0).create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let e = dyfi.get_current_ip().unwrap_err();
+    assert!(e.is_transient());
+    assert!(e.to_string().contains("IP API returned an empty body"));
+    // Retried later instead of exiting
+    assert_eq!(dyfi.run(), DyfiResponseCode::OtherNonFatal);
+    empty.assert();
+    response.assert();
+}
+
+#[test]
+fn test_permanent_ip_api_error_exits() {
+    log_init();
+    let mut server = TestServer::new();
+    let config = server.make_test_config();
+    let not_found = server.server.mock("GET", "/").with_status(404).create();
+    let response = server.dyfi_mock_base().expect(0).create();
+    let code = Dyfi::from(config).unwrap().run();
+    not_found.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Error);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pre_update_cmd() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.pre_update_cmd = Some(format!(
+        "test \"$DYFI_MYIP\" = {MOCK_IP} && \
+        test \"$DYFI_HOSTNAMES\" = mock-some-more.dy.fi,mock.dy.fi"
+    ));
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("nochg").create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pre_update_cmd_failure_skips_update() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.pre_update_cmd = Some("exit 1".to_string());
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().expect(0).create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pre_update_cmd_timeout() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.pre_update_cmd = Some("sleep 30".to_string());
+    config.pre_update_timeout = 1;
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().expect(0).create();
+    let started = std::time::Instant::now();
+    let code = Dyfi::from(config).unwrap().run();
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_max_daily_requests() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.max_daily_requests = 1;
+    let get_ip = server.get_ip_mock().expect(2);
+    let response = server
+        .dyfi_mock_base()
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    // The mock hostnames don't resolve, so they always look outdated
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+}
+
+#[test]
+fn test_max_daily_requests_restored() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("requests");
+    let state = crate::state::State {
+        requests: vec![std::time::SystemTime::now()],
+        ..Default::default()
+    };
+    state.save(&state_file).unwrap();
+    let mut config = server.make_test_config();
+    config.max_daily_requests = 1;
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().expect(0).create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_password_never_logged() {
+    log_init();
+    let mut server = TestServer::new();
+    let config = server.make_test_config();
+    debug!("Configuration: {config:?}");
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged("<redacted>");
+    logging::assert_not_logged("mockpassword");
+}
+
+#[test]
+fn test_zero_check_interval() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.check_interval = 0;
+    assert!(Dyfi::from(config).is_err());
+}
+
+#[test]
+fn test_zero_expected_records() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.expected_records = 0;
+    assert!(Dyfi::from(config).is_err());
+}
+
+#[test]
+fn test_ip_diagnostics() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.ip_diagnostics = true;
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .with_body("good 192.0.2.99")
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged(&format!("public IP API reported {MOCK_IP}"));
+}
+
+#[test]
+fn test_on_error_continue() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.on_error = ErrorPolicy::Continue;
+    let get_ip = server.get_ip_mock().expect(2);
+    let response = server
+        .dyfi_mock_base()
+        .with_body("badauth")
+        .expect(2)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+}
+
+#[test]
+fn test_on_error_continue_still_exits_on_abuse() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.on_error = ErrorPolicy::Continue;
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("abuse").create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Abuse);
+}
+
+#[test]
+fn test_update_sends_sorted_hostnames() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.hostnames =
+        vec!["mock.dy.fi".to_string(), "mock-some-more.dy.fi".to_string()];
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("nochg").create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_update_drop_rejected_sends_sorted_hostnames() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.drop_rejected_hostnames = true;
+    config.hostnames =
+        vec!["mock.dy.fi".to_string(), "mock-some-more.dy.fi".to_string()];
+    let get_ip = server.get_ip_mock();
+    // An error concerns every hostname, so the first one sent is the only
+    // one
+    let first = server
+        .dyfi_mock_base()
+        .match_query(Matcher::UrlEncoded(
+            "hostname".to_string(),
+            "mock-some-more.dy.fi".to_string(),
+        ))
+        .with_body("dnserr")
+        .expect(1)
+        .create();
+    let second = server
+        .dyfi_mock_base()
+        .match_query(Matcher::UrlEncoded(
+            "hostname".to_string(),
+            "mock.dy.fi".to_string(),
+        ))
+        .expect(0)
+        .create();
+    Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    first.assert();
+    second.assert();
+}
+
+#[test]
+fn test_extra_headers() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.extra_headers = Some("X-Api-Key: secret; X-Other: 1".to_string());
+    let get_ip = server
+        .server
+        .mock("GET", "/")
+        .match_header("x-api-key", "secret")
+        .match_header("x-other", "1")
+        .with_body(MOCK_IP)
+        .create();
+    let response = server
+        .dyfi_mock_base()
+        .match_header("x-api-key", "secret")
+        .match_header("authorization", Matcher::Regex("^Basic ".to_string()))
+        .match_header("user-agent", "Dyfi-client-rs")
+        .with_body("nochg")
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_extra_headers_invalid() {
+    for headers in [
+        "X-Api-Key secret",
+        "Bad Name: value",
+        "X-Api-Key: line\nbreak",
+        "Authorization: Bearer x",
+        "user-agent: other",
+    ] {
+        let err = parse_headers(headers).unwrap_err();
+        assert!(matches!(err, DyfiError::Config(_)), "{headers}: {err}");
+    }
+    assert_eq!(parse_headers(" ; X-A: 1;").unwrap().len(), 1);
+}
+
+/// Decides a check on which the detected address is `ip` and the hostnames
+/// resolve to `records`, in the order of `HOSTS`, and acts on it like the
+/// loop does, without sending anything
+pub(super) fn decide_check(
+    dyfi: &mut Dyfi,
+    ip: &str,
+    records: [&str; 2],
+) -> Decision {
+    use resolve_status::HOSTS;
+    let records: HashMap<Hostname, Vec<IpAddr>> = HOSTS
+        .iter()
+        .zip(records)
+        .map(|(host, ip)| (host.to_string(), vec![ip.parse().unwrap()]))
+        .collect();
+    let lookups = records
+        .iter()
+        .map(|(host, ips)| (host.clone(), Ok(ips.clone())))
+        .collect();
+    let decision =
+        dyfi.decide(Some(ip.parse().unwrap()), &records, false, |_| lookups);
+    dyfi.apply_decision(&decision);
+    decision
+}
+
+/// A client that has just updated the hostnames, so that the next check
+/// compares the records instead of forcing an update
+fn debounced(debounce: u64) -> (Dyfi, resolve_status::FakeClock) {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.debounce = debounce;
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let clock = resolve_status::FakeClock::new();
+    dyfi.set_clock(Box::new(clock.clone()));
+    dyfi.mark_updated();
+    (dyfi, clock)
+}
+
+fn debounce_wait(decision: &Decision) -> Option<Duration> {
+    match decision.verdict {
+        Verdict::Deferred {
+            retry_after,
+            reason: "IP change debounce",
+        } => Some(retry_after),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_debounce() {
+    use resolve_status::OLD_IP;
+    log_init();
+    let (mut dyfi, clock) = debounced(10);
+    let stale = [OLD_IP, OLD_IP];
+    let wait =
+        |dyfi: &mut Dyfi, ip| debounce_wait(&decide_check(dyfi, ip, stale));
+    assert_eq!(wait(&mut dyfi, MOCK_IP), Some(Duration::from_secs(10)));
+    clock.advance(Duration::from_secs(4));
+    assert_eq!(wait(&mut dyfi, MOCK_IP), Some(Duration::from_secs(6)));
+    // Another address restarts the window
+    assert_eq!(wait(&mut dyfi, "192.0.2.2"), Some(Duration::from_secs(10)));
+    clock.advance(Duration::from_secs(10));
+    let decision = decide_check(&mut dyfi, "192.0.2.2", stale);
+    assert_eq!(decision.verdict, Verdict::Update);
+}
+
+#[test]
+fn test_debounce_cancelled_on_revert() {
+    use resolve_status::OLD_IP;
+    log_init();
+    let (mut dyfi, _clock) = debounced(30);
+    let stale = [OLD_IP, OLD_IP];
+    assert!(debounce_wait(&decide_check(&mut dyfi, MOCK_IP, stale)).is_some());
+    // The address goes back to what the records hold
+    let decision = decide_check(&mut dyfi, OLD_IP, stale);
+    assert_eq!(decision.verdict, Verdict::Nop);
+    logging::assert_logged("cancelling the update");
+    // The window starts over when the address comes back
+    assert_eq!(
+        debounce_wait(&decide_check(&mut dyfi, MOCK_IP, stale)),
+        Some(Duration::from_secs(30))
+    );
+}
+
+#[test]
+fn test_debounce_disabled() {
+    use resolve_status::OLD_IP;
+    log_init();
+    let (mut dyfi, _clock) = debounced(0);
+    let decision = decide_check(&mut dyfi, MOCK_IP, [OLD_IP, OLD_IP]);
+    assert_eq!(decision.verdict, Verdict::Update);
+}
+
+#[test]
+fn test_config_validate_reports_all_problems() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.hostnames = vec!["example.com".to_string(), "a.b.dy.fi".to_string()];
+    config.user = String::new();
+    config.password = String::new().into();
+    config.dyfi_api = "not a url".to_string();
+    config.public_ip_apis.push("ftp://example.com/".to_string());
+    let errors: Vec<_> = config
+        .validate()
+        .unwrap_err()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(errors.len(), 6, "{errors:?}");
+    for expected in [
+        "'example.com' is not a dy.fi hostname",
+        "'a.b.dy.fi' is not a dy.fi hostname",
+        "No dy.fi username configured",
+        "No dy.fi password configured",
+        "Invalid dy.fi API URL",
+        "'ftp://example.com/' is not an HTTP(S) URL",
+    ] {
+        assert!(
+            errors.iter().any(|e| e.contains(expected)),
+            "missing '{expected}' in {errors:?}"
+        );
+    }
+}
+
+#[test]
+fn test_config_validate_ok() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.hostnames.push("Upper-Case.DY.FI".to_string());
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_dyfi_from_reports_all_problems() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.user = String::new();
+    config.check_interval = 0;
+    let Err(DyfiError::Config(msg)) = Dyfi::from(config) else {
+        panic!("expected a configuration error");
+    };
+    assert!(msg.contains("No dy.fi username configured"), "{msg}");
+    assert!(msg.contains("check interval"), "{msg}");
+}
+
+#[test]
+fn test_update_server_error_with_keyword_body() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .with_status(500)
+        .with_body("good 1.2.3.4")
+        .expect(2)
+        .create();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let err = dyfi.do_update(None).unwrap_err();
+    assert!(err.is_transient());
+    assert!(err.to_string().contains("500"), "{err}");
+    // In the loop the error is retried instead of being taken as a success
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+}
+
+#[test]
+fn test_update_client_error_is_fatal() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .with_status(403)
+        .with_body("good 1.2.3.4")
+        .create();
+    let code = Dyfi::from(server.make_test_config()).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Error);
+}
+
+#[test]
+fn test_redirects_are_capped() {
+    log_init();
+    let mut server = TestServer::new();
+    // A chain of redirects /r0 -> /r1 -> ... that is too long to follow
+    // to the end
+    let hops: Vec<_> = (0..=5)
+        .map(|i| {
+            server
+                .server
+                .mock("GET", format!("/r{i}").as_str())
+                .with_status(302)
+                .with_header("location", &format!("/r{}", i + 1))
+                .expect(usize::from(i < 5))
+                .create()
+        })
+        .collect();
+    let mut config = server.make_test_config();
+    config.public_ip_apis = vec![format!("{}/r0", server.server.url())];
+    let dyfi = Dyfi::from(config).unwrap();
+    assert!(dyfi.get_current_ip().is_err());
+    for hop in hops {
+        hop.assert();
+    }
+}
+
+#[test]
+fn test_many_hostnames_resolved_and_stored() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("manyhosts.state");
+    let mut config = server.make_test_config();
+    config.hostnames = (0..20).map(|i| format!("mock-{i:02}.dy.fi")).collect();
+    config.state_file = Some(state_file.to_path_buf());
+    let hostnames = config.hostnames.join(",");
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::UrlEncoded("hostname".to_string(), hostnames))
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let code = Dyfi::from(config).unwrap().run();
+    let state = crate::state::State::load(&state_file);
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    let state = state.unwrap();
+    assert_eq!(state.previous_ips.len(), 20);
+    assert!((0..20).all(|i| state
+        .previous_ips
+        .contains_key(&format!("mock-{i:02}.dy.fi"))));
+}
+
+#[test]
+fn test_restored_state_current_skips_initial_update() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("current");
+    let mut state = crate::state::State {
+        last_update: Some(std::time::SystemTime::now()),
+        ..Default::default()
+    };
+    for host in ["mock.dy.fi", "mock-some-more.dy.fi"] {
+        state
+            .previous_ips
+            .insert(host.to_string(), vec![MOCK_IP.parse().unwrap()]);
+    }
+    state.save(&state_file).unwrap();
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let resolver = resolve_status::FakeResolver::default();
+    for host in resolve_status::HOSTS {
+        resolver.set(host, &[MOCK_IP]);
+    }
+    dyfi.set_resolver(Box::new(resolver));
+    let code = dyfi.run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged("skipping initial update");
+}
+
+/// A state file that is complete and recent doesn't hide records that are
+/// out of date at startup
+#[test]
+fn test_restored_state_current_stale_records() {
+    use resolve_status::{FakeResolver, HOSTS, OLD_IP};
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("stale");
+    let mut state = crate::state::State {
+        last_update: Some(std::time::SystemTime::now()),
+        ..Default::default()
+    };
+    for host in HOSTS {
+        state
+            .previous_ips
+            .insert(host.to_string(), vec![MOCK_IP.parse().unwrap()]);
+    }
+    state.save(&state_file).unwrap();
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let resolver = FakeResolver::default();
+    resolver.set(HOSTS[0], &[MOCK_IP]);
+    resolver.set(HOSTS[1], &[OLD_IP]);
+    dyfi.set_resolver(Box::new(resolver));
+    let code = dyfi.run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_not_logged("skipping initial update");
+}
+
+#[test]
+fn test_restored_state_partial_still_updates() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("partial");
+    let mut state = crate::state::State {
+        last_update: Some(std::time::SystemTime::now()),
+        ..Default::default()
+    };
+    // Nothing is known about mock-some-more.dy.fi
+    state
+        .previous_ips
+        .insert("mock.dy.fi".to_string(), vec![MOCK_IP.parse().unwrap()]);
+    state.save(&state_file).unwrap();
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+/// A damaged state file is logged and replaced, and doesn't stop the client
+#[test]
+fn test_corrupt_state_file_starts_fresh() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("damaged");
+    std::fs::write(&state_file, "version = 1\nlast_update = soon\n").unwrap();
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged("Ignoring state file");
+    assert!(crate::state::State::load(&state_file).is_some());
+}
+
+#[test]
+fn test_wall_clock_force_interval() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("wall");
+    let mut state = crate::state::State {
+        last_update: Some(std::time::SystemTime::now()),
+        ..Default::default()
+    };
+    for host in ["mock.dy.fi", "mock-some-more.dy.fi"] {
+        state
+            .previous_ips
+            .insert(host.to_string(), vec![MOCK_IP.parse().unwrap()]);
+    }
+    state.save(&state_file).unwrap();
+    let make_config = |server: &TestServer| {
+        let mut config = server.make_test_config();
+        config.state_file = Some(state_file.to_path_buf());
+        config.wall_clock_force_interval = true;
+        config
+    };
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(make_config(&server)).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    let resolver = resolve_status::FakeResolver::default();
+    for host in resolve_status::HOSTS {
+        resolver.set(host, &[MOCK_IP]);
+    }
+    dyfi.set_resolver(Box::new(resolver));
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    response.assert();
+    logging::assert_logged("skipping initial update");
+
+    // Six days ago by the wall clock is past the forced update interval
+    let six_days_ago =
+        std::time::SystemTime::now() - Duration::from_hours(6 * 24);
+    state.last_update = Some(six_days_ago);
+    state.save(&state_file).unwrap();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(make_config(&server)).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    let saved = crate::state::State::load(&state_file).unwrap();
+    response.assert();
+    assert!(saved.last_update.unwrap() > six_days_ago);
+}
+
+/// Remembers every event it is told about
+struct RecordingNotifier(Arc<Mutex<Vec<UpdateEvent>>>);
+
+impl RecordingNotifier {
+    fn new() -> (Box<Self>, Arc<Mutex<Vec<UpdateEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        (Box::new(Self(Arc::clone(&events))), events)
+    }
+}
+
+impl Notifier for RecordingNotifier {
+    fn notify(&self, event: &UpdateEvent) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+
+    fn test(&self, event: &UpdateEvent) -> Result<String, String> {
+        self.notify(event);
+        Ok("Recorded".to_string())
+    }
+}
+
+/// Fails to pass on anything
+struct BrokenNotifier;
+
+impl Notifier for BrokenNotifier {
+    fn notify(&self, _event: &UpdateEvent) {}
+
+    fn test(&self, _event: &UpdateEvent) -> Result<String, String> {
+        Err("Broken".to_string())
+    }
+}
+
+#[test]
+fn test_notifiers_all_receive_events() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let (first, first_events) = RecordingNotifier::new();
+    let (second, second_events) = RecordingNotifier::new();
+    dyfi.add_notifier(first);
+    dyfi.add_notifier(second);
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+    let expected = vec![
+        UpdateEvent::IpDetected(MOCK_IP.parse().unwrap()),
+        UpdateEvent::Updated {
+            hostnames: split_to_sorted_vec("mock.dy.fi,mock-some-more.dy.fi"),
+            ip: MOCK_IP.parse().unwrap(),
+        },
+    ];
+    assert_eq!(*first_events.lock().unwrap(), expected);
+    assert_eq!(*second_events.lock().unwrap(), expected);
+}
+
+#[test]
+fn test_notifier_told_about_rejection() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("badauth").create();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let (notifier, events) = RecordingNotifier::new();
+    dyfi.add_notifier(notifier);
+    assert_eq!(dyfi.run(), DyfiResponseCode::BadAuth);
+    get_ip.assert();
+    response.assert();
+    assert_eq!(
+        events.lock().unwrap().last(),
+        Some(&UpdateEvent::Rejected {
+            code: DyfiResponseCode::BadAuth
+        })
+    );
+}
+
+/// Every notifier gets a made-up update, and nothing is sent to dy.fi
+#[test]
+fn test_test_notify() {
+    log_init();
+    let mut server = TestServer::new();
+    let output_file = TempPath::new("test-notify.ip");
+    std::fs::write(&output_file, "198.51.100.1\n").unwrap();
+    let mut config = server.make_test_config();
+    config.ip_output_file = Some(output_file.to_path_buf());
+    let nothing_sent =
+        server.server.mock("GET", Matcher::Any).expect(0).create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let (notifier, events) = RecordingNotifier::new();
+    dyfi.add_notifier(notifier);
+    dyfi.add_notifier(Box::new(BrokenNotifier));
+    let results = dyfi.test_notify();
+    nothing_sent.assert();
+    assert_eq!(
+        results,
+        vec![
+            Ok(format!("Wrote 192.0.2.1 to {}", output_file.display())),
+            Ok("Recorded".to_string()),
+            Err("Broken".to_string()),
+        ]
+    );
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![UpdateEvent::Updated {
+            hostnames: split_to_sorted_vec("mock.dy.fi,mock-some-more.dy.fi"),
+            ip: crate::client::notify::TEST_IP,
+        }]
+    );
+    // Whatever reads the file isn't left with the made-up address
+    assert_eq!(
+        std::fs::read_to_string(&output_file).unwrap(),
+        "198.51.100.1\n"
+    );
+}
+
+#[test]
+fn test_config_validate_ip_api_pin() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.ip_api_pin = Some("abc".to_string());
+    let errors: Vec<_> = config
+        .validate()
+        .unwrap_err()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    assert!(errors.iter().any(|e| e.contains("Invalid public key pin")));
+    // The mock server only speaks plain HTTP
+    assert!(errors.iter().any(|e| e.contains("needs HTTPS")));
+}
+
+#[test]
+fn test_config_validate_ip_api_pin_ok() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.public_ip_apis = vec!["https://ip.example.com/".to_string()];
+    config.ip_api_pin = Some("ab".repeat(32));
+    let result = config.validate();
+    if cfg!(feature = "rustls-tls") {
+        assert!(result.is_ok(), "{result:?}");
+    } else {
+        let errors = result.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), crate::types::PIN_NEEDS_RUSTLS);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_config_validate_event_socket() {
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    let path = TempPath::new("validate.sock");
+    config.event_socket = Some(path.to_path_buf());
+    let result = config.validate();
+    if cfg!(feature = "serde") {
+        assert!(result.is_ok(), "{result:?}");
+    } else {
+        let errors = result.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            crate::types::EVENT_SOCKET_NEEDS_SERDE
+        );
+    }
+}
+
+#[test]
+fn test_check_once_updated() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let outcome = Dyfi::from(server.make_test_config()).unwrap().check_once();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(outcome, CheckOutcome::Updated);
+    assert_eq!(outcome.exit_code(), 0);
+}
+
+#[test]
+fn test_check_once_unchanged() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("nochg").create();
+    let outcome = Dyfi::from(server.make_test_config()).unwrap().check_once();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(outcome, CheckOutcome::Unchanged);
+    assert_eq!(outcome.exit_code(), 12);
+}
+
+#[test]
+fn test_check_once_failed() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("badauth").create();
+    let outcome = Dyfi::from(server.make_test_config()).unwrap().check_once();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(outcome, CheckOutcome::Failed(DyfiResponseCode::BadAuth));
+    assert_eq!(outcome.exit_code(), 1);
+}
+
+/// An update held back by the minimum update interval isn't reported as
+/// nothing needing an update
+#[test]
+fn test_check_once_deferred() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.min_update_interval = 3600;
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().expect(0).create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.mark_updated();
+    let outcome = dyfi.check_once();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(outcome, CheckOutcome::Deferred);
+    assert_eq!(outcome.exit_code(), 15);
+}
+
+/// A one-shot check has no later check to send a debounced update on
+#[test]
+fn test_check_once_not_debounced() {
+    use resolve_status::{FakeResolver, HOSTS, OLD_IP};
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.debounce = 60;
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let resolver = FakeResolver::default();
+    for host in HOSTS {
+        resolver.set(host, &[OLD_IP]);
+    }
+    dyfi.set_resolver(Box::new(resolver));
+    dyfi.mark_updated();
+    let outcome = dyfi.check_once();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(outcome, CheckOutcome::Updated);
+    logging::assert_not_logged("to settle");
+}
+
+/// Without a network, a one-shot check ends with a status of its own
+/// instead of the generic error
+#[test]
+fn test_check_once_no_network() {
+    log_init();
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    // Nothing listens on port 1
+    config.public_ip_apis = vec!["http://127.0.0.1:1/".to_string()];
+    let outcome = Dyfi::from(config).unwrap().check_once();
+    assert_eq!(outcome, CheckOutcome::Failed(DyfiResponseCode::NoNetwork));
+    assert_eq!(outcome.exit_code(), 14);
+    logging::assert_logged("ERROR Network unavailable (connection refused)");
+}
+
+#[test]
+fn test_restored_state_from_the_future_is_not_trusted() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("future");
+    // Saved with a clock that was a month ahead, with the current address
+    let mut state = crate::state::State {
+        last_update: Some(
+            std::time::SystemTime::now() + Duration::from_hours(30 * 24),
+        ),
+        ..Default::default()
+    };
+    for host in ["mock.dy.fi", "mock-some-more.dy.fi"] {
+        state
+            .previous_ips
+            .insert(host.to_string(), vec![MOCK_IP.parse().unwrap()]);
+    }
+    state.save(&state_file).unwrap();
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let code = Dyfi::from(config).unwrap().run();
+    get_ip.assert();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged("in the future");
+}
+
+#[test]
+fn test_allowed_ip_ranges_in_range() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.allowed_ip_ranges = "198.51.100.0/24, 192.0.2.0/24".parse().unwrap();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    let code = dyfi.run();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+}
+
+#[test]
+fn test_allowed_ip_ranges_out_of_range() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.allowed_ip_ranges = "192.0.2.0/24, 2001:db8::/32".parse().unwrap();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip("192.0.2.1\n2001:db9::1\n".parse().unwrap());
+    let code = dyfi.run();
+    response.assert();
+    assert_eq!(code, DyfiResponseCode::Ok);
+    logging::assert_logged("outside of DYFI_ALLOWED_IP_RANGES");
+}
+
+fn reasons(decision: &Decision) -> Vec<Reason> {
+    decision.hosts.iter().map(|h| h.reason.clone()).collect()
+}
+
+#[test]
+fn test_stale_dns_after_update() {
+    use resolve_status::OLD_IP;
+    log_init();
+    let mut server = TestServer::new();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let old_ip = OLD_IP.parse().unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    dyfi.mark_updated();
+    // Nothing has been accepted yet, so keep updating
+    let decision = decide_check(&mut dyfi, MOCK_IP, [OLD_IP, MOCK_IP]);
+    assert_eq!(reasons(&decision)[0], Reason::Stale(old_ip));
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    response.assert();
+    for _ in 0..2 {
+        let decision = decide_check(&mut dyfi, MOCK_IP, [OLD_IP, MOCK_IP]);
+        assert_eq!(reasons(&decision)[0], Reason::Stale(old_ip));
+        assert_eq!(decision.verdict, Verdict::Update);
+    }
+    let decision = decide_check(&mut dyfi, MOCK_IP, [OLD_IP, MOCK_IP]);
+    assert_eq!(reasons(&decision)[0], Reason::Lagging(old_ip));
+    assert_eq!(decision.verdict, Verdict::Nop);
+    logging::assert_logged("This is likely DNS caching");
+    // Other hosts are counted separately
+    let decision = decide_check(&mut dyfi, MOCK_IP, [OLD_IP, OLD_IP]);
+    assert_eq!(
+        reasons(&decision),
+        vec![Reason::Lagging(old_ip), Reason::Stale(old_ip)]
+    );
+    assert_eq!(decision.verdict, Verdict::Update);
+}
+
+#[test]
+fn test_stale_dns_disabled() {
+    use resolve_status::OLD_IP;
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.stale_dns_checks = 0;
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    response.assert();
+    for _ in 0..5 {
+        let decision = decide_check(&mut dyfi, MOCK_IP, [OLD_IP, OLD_IP]);
+        assert_eq!(decision.verdict, Verdict::Update);
+    }
+}
+
+#[test]
+fn test_ip_family_v6_on_v4_only_network() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.ip_family = IpFamily::V6;
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    let e = dyfi.get_current_ip().unwrap_err();
+    assert!(matches!(e, DyfiError::NoAddress(_)));
+    assert!(e
+        .to_string()
+        .contains("No IPv6 address available, skipping cycle"));
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    let outcome = dyfi.check_once();
+    assert_eq!(outcome, CheckOutcome::NoAddress);
+    assert_eq!(outcome.exit_code(), 17);
+    response.assert();
+}
+
+#[test]
+fn test_ip_family_unavailable_is_not_a_failure() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.ip_family = IpFamily::V6;
+    config.max_ip_failures = 2;
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    for _ in 0..5 {
+        assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    }
+    response.assert();
+    logging::assert_not_logged("consecutive checks");
+    logging::assert_logged("INFO No IPv6 address available, skipping cycle");
+}
+
+#[test]
+fn test_ip_family_logged_at_startup() {
+    log_init();
+    let server = TestServer::new();
+    let mut config = server.make_test_config();
+    Dyfi::from(server.make_test_config()).unwrap();
+    logging::assert_logged("Managing both IPv4 and IPv6 records");
+    config.ip_family = IpFamily::V6;
+    Dyfi::from(config).unwrap();
+    logging::assert_logged(
+        "Managing IPv6 records only, as set by DYFI_IP_FAMILY",
+    );
+}
+
+/// Routes that a test can change while the client runs
+#[derive(Clone, Default)]
+struct FakeRoutes(Arc<Mutex<(bool, bool)>>);
+
+impl FakeRoutes {
+    fn set(&self, v4: bool, v6: bool) {
+        *self.0.lock().unwrap() = (v4, v6);
+    }
+}
+
+impl Routes for FakeRoutes {
+    fn available(&self, _bind: Option<IpAddr>) -> (bool, bool) {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// The routes are checked on every check, so that a family that comes up
+/// after startup is managed from then on
+#[test]
+fn test_ip_family_follows_routes() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let routes = FakeRoutes::default();
+    routes.set(true, false);
+    dyfi.set_routes(Box::new(routes.clone()));
+    dyfi.set_fake_ip(format!("{MOCK_IP}\n{MOCK_IP6}\n").parse().unwrap());
+    let v4_only = server
+        .dyfi_mock_base()
+        .match_query(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("myip".to_string(), MOCK_IP.to_string()),
+            // No myip6
+            Matcher::Regex("^[^6]*$".to_string()),
+        ]))
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    v4_only.assert();
+    logging::assert_logged(
+        "This host only has an IPv4 route to the Internet, managing IPv4 \
+        records only",
+    );
+
+    routes.set(true, true);
+    let both = server
+        .dyfi_mock_base()
+        .match_query(Matcher::UrlEncoded(
+            "myip6".to_string(),
+            MOCK_IP6.to_string(),
+        ))
+        .with_body(format!("good {MOCK_IP6}"))
+        .create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    both.assert();
+    logging::assert_logged("Managing both IPv4 and IPv6 records");
+}
+
+/// A bind address of one family can only have a route over that family
+#[test]
+fn test_system_routes_with_bind_address() {
+    let v4: IpAddr = "127.0.0.1".parse().unwrap();
+    assert!(!SystemRoutes.available(Some(v4)).1);
+    let v6: IpAddr = "::1".parse().unwrap();
+    assert!(!SystemRoutes.available(Some(v6)).0);
+}
+
+#[test]
+fn test_ip_family_v4_drops_v6() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.ip_family = IpFamily::V4;
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("myip".to_string(), MOCK_IP.to_string()),
+            // No myip6
+            Matcher::Regex("^[^6]*$".to_string()),
+        ]))
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(format!("{MOCK_IP}\n{MOCK_IP6}\n").parse().unwrap());
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    response.assert();
+}
+
+#[test]
+fn test_heartbeat_url() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.heartbeat_url = Some(format!("{}/ping", server.server.url()));
+    let ping = server.server.mock("GET", "/ping").expect(1).create();
+    let fail = server.server.mock("GET", "/ping/fail").expect(0).create();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    response.assert();
+    ping.assert();
+    fail.assert();
+}
+
+/// A check that finds the records current is a success too
+#[test]
+fn test_heartbeat_url_when_current() {
+    use resolve_status::{FakeResolver, HOSTS};
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.heartbeat_url = Some(format!("{}/ping", server.server.url()));
+    let ping = server.server.mock("GET", "/ping").expect(1).create();
+    let fail = server.server.mock("GET", "/ping/fail").expect(0).create();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    let resolver = FakeResolver::default();
+    for host in HOSTS {
+        resolver.set(host, &[MOCK_IP]);
+    }
+    dyfi.set_resolver(Box::new(resolver));
+    dyfi.mark_updated();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    response.assert();
+    ping.assert();
+    fail.assert();
+}
+
+/// A held back update isn't a failure
+#[test]
+fn test_heartbeat_url_on_deferral() {
+    use resolve_status::{FakeResolver, HOSTS, OLD_IP};
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.heartbeat_url = Some(format!("{}/ping", server.server.url()));
+    config.min_update_interval = 3600;
+    let ping = server.server.mock("GET", "/ping").expect(1).create();
+    let fail = server.server.mock("GET", "/ping/fail").expect(0).create();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    let resolver = FakeResolver::default();
+    for host in HOSTS {
+        resolver.set(host, &[OLD_IP]);
+    }
+    dyfi.set_resolver(Box::new(resolver));
+    dyfi.mark_updated();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    logging::assert_logged("Update deferred");
+    response.assert();
+    ping.assert();
+    fail.assert();
+}
+
+#[test]
+fn test_heartbeat_url_on_failure() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.heartbeat_url = Some(format!("{}/ping/", server.server.url()));
+    let ping = server.server.mock("GET", "/ping/").expect(0).create();
+    let fail = server.server.mock("GET", "/ping/fail").expect(1).create();
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .with_body("badauth")
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    assert_eq!(dyfi.run(), DyfiResponseCode::BadAuth);
+    response.assert();
+    ping.assert();
+    fail.assert();
+}
+
+#[test]
+fn test_heartbeat_url_failure_is_not_fatal() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.heartbeat_url = Some(format!("{}/ping", server.server.url()));
+    let ping = server
+        .server
+        .mock("GET", "/ping")
+        .with_status(500)
+        .expect(1)
+        .create();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    response.assert();
+    ping.assert();
+    logging::assert_logged("Heartbeat ping to");
+}
+
+#[test]
+fn test_skip_ip_check() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.skip_ip_check = true;
+    let get_ip = server.get_ip_mock().expect(0);
+    // dy.fi is left to detect the address, so there is no myip
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Regex("^hostname=[^&]*$".to_string()))
+        .with_body(format!("good {MOCK_IP}"))
+        .expect(2)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    assert_eq!(dyfi.check_once(), CheckOutcome::Updated);
+    get_ip.assert();
+    response.assert();
+}
+
+#[test]
+fn test_url_trailing_slashes() {
+    log_init();
+    let mut server = TestServer::new();
+    let url = server.server.url();
+    let get_ip = server.get_ip_mock().expect(3);
+    let response = server.update_mock().expect(3).create();
+    for (dyfi_api, ip_api) in [
+        (url.clone(), url.clone()),
+        (format!("{url}/"), format!("{url}//")),
+        (format!("{url}/nic/update/"), format!("{url}/")),
+    ] {
+        let mut config = server.make_test_config();
+        config.dyfi_api = dyfi_api;
+        config.public_ip_apis = vec![ip_api];
+        assert_eq!(Dyfi::from(config).unwrap().run(), DyfiResponseCode::Ok);
+    }
+    get_ip.assert();
+    response.assert();
+}
+
+#[test]
+fn test_on_error_continue_raises_alarm() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.on_error = ErrorPolicy::Continue;
+    config.retry_cap = 7200;
+    config.heartbeat_url = Some(format!("{}/ping", server.server.url()));
+    let fail = server.server.mock("GET", "/ping/fail").expect(1).create();
+    let response = server.dyfi_mock_base().with_body("badauth").create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    let (notifier, events) = RecordingNotifier::new();
+    dyfi.add_notifier(notifier);
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    response.assert();
+    fail.assert();
+    assert!(events.lock().unwrap().contains(&UpdateEvent::Failing {
+        code: DyfiResponseCode::BadAuth,
+        retry_after: Duration::from_hours(2),
+    }));
+    logging::assert_logged("retrying in 2h anyway");
+}
+
+#[test]
+fn test_warning_before_release() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("release");
+    let state = crate::state::State {
+        last_update: Some(
+            std::time::SystemTime::now() - Duration::from_hours(6 * 24 + 12),
+        ),
+        ..Default::default()
+    };
+    state.save(&state_file).unwrap();
+    let mut config = server.make_test_config();
+    config.state_file = Some(state_file.to_path_buf());
+    let failing = server
+        .server
+        .mock("GET", "/")
+        .with_status(503)
+        .expect(2)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::OtherNonFatal);
+    logging::assert_logged("dy.fi releases hostnames");
+    // Only once
+    log_init();
+    assert_eq!(dyfi.run(), DyfiResponseCode::OtherNonFatal);
+    failing.assert();
+    logging::assert_not_logged("dy.fi releases hostnames");
+}
+
+fn failing_ip_api(server: &mut TestServer, checks: usize) -> Mock {
+    server
+        .server
+        .mock("GET", "/")
+        .with_status(503)
+        .expect(checks)
+        .create()
+}
+
+#[test]
+fn test_max_ip_failures_exits() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.max_ip_failures = 3;
+    let failing = failing_ip_api(&mut server, 3);
+    let mut dyfi = Dyfi::from(config).unwrap();
+    assert_eq!(dyfi.run(), DyfiResponseCode::OtherNonFatal);
+    assert_eq!(dyfi.run(), DyfiResponseCode::OtherNonFatal);
+    logging::assert_not_logged("consecutive checks");
+    assert_eq!(dyfi.run(), DyfiResponseCode::NoIp);
+    logging::assert_logged(
+        "Unable to detect the current address on 3 consecutive checks, \
+        exiting",
+    );
+    failing.assert();
+}
+
+#[test]
+fn test_max_ip_failures_reset_on_success() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.max_ip_failures = 2;
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let failing = failing_ip_api(&mut server, 1);
+    assert_eq!(dyfi.run(), DyfiResponseCode::OtherNonFatal);
+    failing.assert();
+    failing.remove();
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+    get_ip.remove();
+    let failing = failing_ip_api(&mut server, 1);
+    assert_eq!(dyfi.run(), DyfiResponseCode::OtherNonFatal);
+    failing.assert();
+}
+
+#[test]
+fn test_max_ip_failures_falls_back_to_dyfi() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.max_ip_failures = 2;
+    config.on_error = ErrorPolicy::Continue;
+    let failing = failing_ip_api(&mut server, 2);
+    // Without an address, dy.fi uses the one the request comes from
+    let response = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Regex("^hostname=[^&]*$".to_string()))
+        .with_body(format!("good {MOCK_IP}"))
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let (notifier, events) = RecordingNotifier::new();
+    dyfi.add_notifier(notifier);
+    assert_eq!(dyfi.run(), DyfiResponseCode::OtherNonFatal);
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    failing.assert();
+    response.assert();
+    logging::assert_logged("letting dy.fi detect it");
+    assert!(events.lock().unwrap().contains(&UpdateEvent::Failing {
+        code: DyfiResponseCode::NoIp,
+        retry_after: Duration::from_hours(1),
+    }));
+}
+
+#[test]
+fn test_slow_request_warning() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.slow_request_warn_ms = 1;
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let slow_ip = server
+        .server
+        .mock("GET", "/")
+        .with_status(200)
+        .with_header("content-type", "text/plain")
+        .with_body_from_request(|_| {
+            std::thread::sleep(Duration::from_millis(50));
+            MOCK_IP.into()
+        })
+        .create();
+    let response = server.update_mock().create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    slow_ip.assert();
+    response.assert();
+    logging::assert_logged("WARN GET");
+    logging::assert_logged("more than the 1 ms of DYFI_SLOW_REQUEST_WARN_MS");
+}
+
+#[test]
+fn test_slow_request_warning_disabled() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut config = server.make_test_config();
+    config.slow_request_warn_ms = 0;
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+    logging::assert_not_logged("DYFI_SLOW_REQUEST_WARN_MS");
+}
+
+#[test]
+fn test_pause_file() {
+    log_init();
+    let mut server = TestServer::new();
+    let pause_file = TempPath::new("pause");
+    let mut config = server.make_test_config();
+    config.pause_file = Some(pause_file.to_path_buf());
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let paused_lines = || {
+        logging::captured()
+            .iter()
+            .filter(|line| line.contains("Paused (pause file"))
+            .count()
+    };
+
+    std::fs::write(&pause_file, "").unwrap();
+    let nothing_sent =
+        server.server.mock("GET", Matcher::Any).expect(0).create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    let outcome = dyfi.check_once();
+    assert_eq!(outcome, CheckOutcome::Paused);
+    assert_eq!(outcome.exit_code(), 16);
+    nothing_sent.assert();
+    nothing_sent.remove();
+    assert_eq!(paused_lines(), 1);
+
+    std::fs::remove_file(&pause_file).unwrap();
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+    logging::assert_logged("removed, resuming");
+    assert_eq!(paused_lines(), 1);
+}
+
+#[test]
+fn test_good_response_keeps_other_family() {
+    use resolve_status::{FakeResolver, HOSTS, OLD_IP};
+    log_init();
+    let mut server = TestServer::new();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let resolver = FakeResolver::default();
+    for host in HOSTS {
+        resolver.set(host, &[OLD_IP, "2001:db8::1"]);
+    }
+    dyfi.set_resolver(Box::new(resolver));
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+    let expected: Vec<IpAddr> =
+        vec!["2001:db8::1".parse().unwrap(), MOCK_IP.parse().unwrap()];
+    for (host, ips) in dyfi.state_snapshot().previous_ips {
+        assert_eq!(ips, expected, "{host}");
+    }
+}
+
+#[test]
+fn test_error_response_headers_logged() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .with_status(503)
+        .with_header("server", "overloaded-proxy")
+        .with_header("retry-after", "120")
+        .with_header("x-unrelated", "secret")
+        .create();
+    dyfi.run();
+    get_ip.assert();
+    response.assert();
+    logging::assert_logged("DEBUG Headers of the error response: ");
+    logging::assert_logged("server: overloaded-proxy");
+    logging::assert_logged("retry-after: 120");
+    logging::assert_not_logged("x-unrelated");
+}
+
+#[test]
+fn test_other_response_headers_logged() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let get_ip = server.get_ip_mock();
+    let response = server
+        .dyfi_mock_base()
+        .with_header("x-ratelimit-remaining", "0")
+        .with_body("slow down")
+        .create();
+    dyfi.run();
+    get_ip.assert();
+    response.assert();
+    logging::assert_logged("Headers of the unrecognized response: ");
+    logging::assert_logged("x-ratelimit-remaining: 0");
+}
+
+#[test]
+fn test_good_response_headers_not_logged() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+    logging::assert_not_logged("Headers of the");
+}
+
+#[test]
+fn test_current_ip_quoted() {
+    log_init();
+    let mut server = TestServer::new();
+    let quoted = ip_api_mock(&mut server, "/", &format!("\"{MOCK_IP}\"\n"));
+    let config = server.make_test_config();
+    let ip = Dyfi::from(config).unwrap().get_current_ip().unwrap();
+    quoted.assert();
+    assert_eq!(ip.to_string(), MOCK_IP);
+}
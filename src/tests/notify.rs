@@ -0,0 +1,158 @@
+use super::{log_init, logging, TempPath, TestServer, MOCK_IP};
+use crate::client::notify::{EventSocket, Notifier, UpdateEvent};
+use crate::client::Dyfi;
+use crate::types::{DyfiResponseCode, Hostname};
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+fn socket_path(name: &str) -> TempPath {
+    TempPath::new(&format!("{name}.sock"))
+}
+
+fn read_line(reader: &mut BufReader<UnixStream>) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line
+}
+
+#[test]
+fn test_event_json() {
+    let ip: IpAddr = MOCK_IP.parse().unwrap();
+    assert_eq!(
+        UpdateEvent::IpDetected(MOCK_IP.parse().unwrap()).to_json(),
+        r#"{"event":"ip_detected","ipv4":"192.0.2.1","ipv6":null}"#
+    );
+    let hostnames: Vec<Hostname> = vec!["a.dy.fi".into(), "b\"\\.dy.fi".into()];
+    assert_eq!(
+        UpdateEvent::Updated { hostnames, ip }.to_json(),
+        r#"{"event":"updated","hostnames":["a.dy.fi","b\"\\.dy.fi"],"ip":"192.0.2.1"}"#
+    );
+    assert_eq!(
+        UpdateEvent::Rejected {
+            code: DyfiResponseCode::BadAuth
+        }
+        .to_json(),
+        r#"{"event":"rejected","code":"badauth"}"#
+    );
+    let failing = UpdateEvent::Failing {
+        code: DyfiResponseCode::DNSErr,
+        retry_after: Duration::from_hours(1),
+    };
+    assert_eq!(
+        failing.to_json(),
+        r#"{"event":"failing","code":"dnserr","retry_after":3600}"#
+    );
+}
+
+#[test]
+fn test_event_socket_drops_without_consumer() {
+    log_init();
+    let path = socket_path("drop");
+    let socket = EventSocket::bind(&path).unwrap();
+    let event = UpdateEvent::Rejected {
+        code: DyfiResponseCode::Abuse,
+    };
+    socket.notify(&event);
+    socket.notify(&event);
+    assert_eq!(socket.dropped(), 2);
+    let mut reader = BufReader::new(UnixStream::connect(&path).unwrap());
+    socket.notify(&event);
+    logging::assert_logged("2 event(s) were dropped");
+    assert_eq!(socket.dropped(), 0);
+    assert_eq!(read_line(&mut reader), format!("{}\n", event.to_json()));
+    drop(socket);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_event_socket_slow_consumer() {
+    log_init();
+    let path = socket_path("slow");
+    let socket = EventSocket::bind(&path).unwrap();
+    let stuck = UnixStream::connect(&path).unwrap();
+    let event = UpdateEvent::Rejected {
+        code: DyfiResponseCode::Abuse,
+    };
+    // Never blocks, however much the consumer falls behind
+    for _ in 0..10_000 {
+        socket.notify(&event);
+    }
+    logging::assert_logged("are not keeping up");
+    // A consumer that goes away is forgotten
+    drop(stuck);
+    socket.notify(&event);
+}
+
+#[test]
+fn test_event_socket_replaces_stale_socket() {
+    let path = socket_path("stale");
+    // A crashed run leaves its socket behind, with no one listening on it
+    drop(UnixListener::bind(&path).unwrap());
+    EventSocket::bind(&path).unwrap();
+    // Other files are left alone
+    let file = socket_path("regular");
+    std::fs::write(&file, "").unwrap();
+    assert!(EventSocket::bind(&file).is_err());
+}
+
+/// A running client's socket isn't taken over, nor removed on the way out
+#[test]
+fn test_event_socket_in_use() {
+    let path = socket_path("in-use");
+    let running = EventSocket::bind(&path).unwrap();
+    let e = EventSocket::bind(&path).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+    let mut reader = BufReader::new(UnixStream::connect(&path).unwrap());
+    let event = UpdateEvent::Rejected {
+        code: DyfiResponseCode::Abuse,
+    };
+    running.notify(&event);
+    assert_eq!(read_line(&mut reader), format!("{}\n", event.to_json()));
+}
+
+#[test]
+fn test_event_socket_streams_run() {
+    log_init();
+    let mut server = TestServer::new();
+    let path = socket_path("run");
+    let mut config = server.make_test_config();
+    config.event_socket = Some(path.to_path_buf());
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let mut reader = BufReader::new(UnixStream::connect(&path).unwrap());
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    get_ip.assert();
+    response.assert();
+    assert_eq!(
+        read_line(&mut reader),
+        "{\"event\":\"ip_detected\",\"ipv4\":\"192.0.2.1\",\"ipv6\":null}\n"
+    );
+    assert!(read_line(&mut reader).starts_with("{\"event\":\"updated\""));
+}
+
+#[test]
+fn test_event_socket_test() {
+    log_init();
+    let path = socket_path("test");
+    let socket = EventSocket::bind(&path).unwrap();
+    let event = UpdateEvent::Updated {
+        hostnames: vec!["mock.dy.fi".to_string()],
+        ip: MOCK_IP.parse().unwrap(),
+    };
+    assert_eq!(
+        socket.test(&event),
+        Err(format!("No consumer is connected to {}", path.display()))
+    );
+    let mut reader = BufReader::new(UnixStream::connect(&path).unwrap());
+    assert_eq!(
+        socket.test(&event),
+        Ok(format!(
+            "Sent the event to 1 consumer(s) of {}",
+            path.display()
+        ))
+    );
+    assert_eq!(read_line(&mut reader), format!("{}\n", event.to_json()));
+}
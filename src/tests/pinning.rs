@@ -0,0 +1,28 @@
+use crate::client::pinning::{spki_sha256, tls_config};
+use crate::util::parse_sha256_hex;
+use rustls::pki_types::CertificateDer;
+
+// A self-signed certificate and the hash of its key, as printed by
+// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | sha256sum`
+const CERT: &[u8] = include_bytes!("data/pinning-cert.der");
+const CERT_PIN: &str =
+    "431aa907c77ceebc594e203d970b36fd92c2a1eda4a03f9e9dfc672f9cab2c8a";
+
+#[test]
+fn test_spki_sha256_matches_openssl() {
+    let hash = spki_sha256(&CertificateDer::from(CERT)).unwrap();
+    assert_eq!(Some(hash), parse_sha256_hex(CERT_PIN));
+}
+
+#[test]
+fn test_spki_sha256_invalid_certificate() {
+    assert!(
+        spki_sha256(&CertificateDer::from(&b"not a certificate"[..])).is_err()
+    );
+}
+
+#[test]
+fn test_tls_config() {
+    let config = tls_config(parse_sha256_hex(CERT_PIN).unwrap()).unwrap();
+    assert!(config.alpn_protocols.contains(&b"http/1.1".to_vec()));
+}
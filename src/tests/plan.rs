@@ -0,0 +1,250 @@
+use super::resolve_status::{FakeClock, FakeResolver, HOSTS, OLD_IP};
+use super::{decide_check, log_init, TempPath, TestServer, MOCK_IP};
+use crate::client::decide::{Reason, Verdict};
+use crate::client::plan::Plan;
+use crate::client::Dyfi;
+use crate::types::Config;
+use mockito::{Matcher, Mock};
+use std::time::{Duration, SystemTime};
+
+/// A client whose hostnames both resolve to `MOCK_IP`, which is also the
+/// detected address. Nothing may be sent to dy.fi.
+fn planner(
+    server: &mut TestServer,
+    configure: impl FnOnce(&mut Config),
+) -> (Dyfi, FakeClock, FakeResolver, Mock) {
+    let mut config = server.make_test_config();
+    configure(&mut config);
+    let nothing_sent = server
+        .dyfi_mock_base()
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+    let mut dyfi = Dyfi::from(config).unwrap();
+    let clock = FakeClock::new();
+    let resolver = FakeResolver::default();
+    dyfi.set_clock(Box::new(clock.clone()));
+    dyfi.set_resolver(Box::new(resolver.clone()));
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    for host in HOSTS {
+        resolver.set(host, &[MOCK_IP]);
+    }
+    (dyfi, clock, resolver, nothing_sent)
+}
+
+fn reasons(plan: &Plan) -> Vec<Reason> {
+    plan.hosts.iter().map(|host| host.reason.clone()).collect()
+}
+
+#[test]
+fn test_plan_current() {
+    log_init();
+    let mut server = TestServer::new();
+    let (mut dyfi, clock, _, nothing_sent) = planner(&mut server, |_| ());
+    dyfi.mark_updated();
+    clock.advance(Duration::from_hours(1));
+    let plan = dyfi.plan().unwrap();
+    assert_eq!(reasons(&plan), vec![Reason::Current, Reason::Current]);
+    assert!(!plan.would_update());
+    assert!(plan.to_string().ends_with("Nothing needs updating"));
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_forced_without_updates() {
+    log_init();
+    let mut server = TestServer::new();
+    let (mut dyfi, _, _, nothing_sent) = planner(&mut server, |_| ());
+    let plan = dyfi.plan().unwrap();
+    assert_eq!(reasons(&plan), vec![Reason::Forced, Reason::Forced]);
+    assert!(plan.would_update());
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_broken_resolution() {
+    log_init();
+    let mut server = TestServer::new();
+    let (mut dyfi, clock, resolver, nothing_sent) =
+        planner(&mut server, |_| ());
+    dyfi.mark_updated();
+    clock.advance(Duration::from_hours(1));
+    resolver.set(HOSTS[0], &["127.0.0.1"]);
+    let plan = dyfi.plan().unwrap();
+    assert_eq!(reasons(&plan), vec![Reason::Broken, Reason::Current]);
+    assert!(!plan.would_update());
+    assert!(plan.to_string().contains("resolution broken"));
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_stale_and_unresolved() {
+    log_init();
+    let mut server = TestServer::new();
+    let (mut dyfi, clock, resolver, nothing_sent) =
+        planner(&mut server, |_| ());
+    dyfi.mark_updated();
+    clock.advance(Duration::from_hours(1));
+    resolver.set(HOSTS[0], &[OLD_IP]);
+    resolver.fail(HOSTS[1]);
+    let plan = dyfi.plan().unwrap();
+    assert_eq!(
+        reasons(&plan),
+        vec![Reason::Stale(OLD_IP.parse().unwrap()), Reason::Unresolved]
+    );
+    assert!(plan.would_update());
+    let table = plan.to_string();
+    assert_eq!(
+        table,
+        format!(
+            "Current address: {MOCK_IP}\n\
+            HOSTNAME              RECORDS       ACTION  REASON\n\
+            mock-some-more.dy.fi  {OLD_IP}  update  stale ({OLD_IP})\n\
+            mock.dy.fi            no addresses  update  unresolved\n\
+            2 hostname(s) would be updated"
+        )
+    );
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_held_back_by_min_interval() {
+    log_init();
+    let mut server = TestServer::new();
+    let (mut dyfi, clock, resolver, nothing_sent) =
+        planner(&mut server, |config| config.min_update_interval = 7200);
+    dyfi.mark_updated();
+    clock.advance(Duration::from_hours(1));
+    resolver.set(HOSTS[0], &[OLD_IP]);
+    let plan = dyfi.plan().unwrap();
+    assert!(!plan.would_update());
+    assert!(plan.to_string().ends_with(
+        "Nothing would be sent for another 1h due to the minimum \
+        update interval"
+    ));
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_held_back_by_debounce() {
+    log_init();
+    let mut server = TestServer::new();
+    let (mut dyfi, clock, resolver, nothing_sent) =
+        planner(&mut server, |config| config.debounce = 60);
+    dyfi.mark_updated();
+    clock.advance(Duration::from_hours(1));
+    resolver.set(HOSTS[0], &[OLD_IP]);
+    let plan = dyfi.plan().unwrap();
+    assert!(!plan.would_update());
+    assert_eq!(
+        plan.held_back,
+        Some((
+            "the IP change debounce".to_string(),
+            Some(Duration::from_mins(1))
+        ))
+    );
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_waits_for_stable_ip() {
+    log_init();
+    let mut server = TestServer::new();
+    let (mut dyfi, clock, resolver, nothing_sent) =
+        planner(&mut server, |config| config.ip_stable_checks = 2);
+    dyfi.mark_updated();
+    clock.advance(Duration::from_hours(1));
+    resolver.set(HOSTS[0], &[OLD_IP]);
+    let plan = dyfi.plan().unwrap();
+    assert!(!plan.would_update());
+    assert!(plan.to_string().contains("2 consecutive checks"));
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_skip_ip_check() {
+    log_init();
+    let mut server = TestServer::new();
+    let (mut dyfi, clock, resolver, nothing_sent) =
+        planner(&mut server, |config| config.skip_ip_check = true);
+    dyfi.mark_updated();
+    clock.advance(Duration::from_hours(1));
+    // Without a detected address, an old record can't be told apart
+    resolver.set(HOSTS[0], &[OLD_IP]);
+    let plan = dyfi.plan().unwrap();
+    assert_eq!(plan.ip, None);
+    assert_eq!(reasons(&plan), vec![Reason::Current, Reason::Current]);
+    assert!(!plan.would_update());
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_restored_state() {
+    log_init();
+    let mut server = TestServer::new();
+    let state_file = TempPath::new("plan");
+    let save = |ip: &str| {
+        let mut state = crate::state::State {
+            last_update: Some(SystemTime::now()),
+            ..Default::default()
+        };
+        for host in HOSTS {
+            state
+                .previous_ips
+                .insert(host.to_string(), vec![ip.parse().unwrap()]);
+        }
+        state.save(&state_file).unwrap();
+    };
+
+    save(MOCK_IP);
+    let (mut dyfi, _, _, nothing_sent) = planner(&mut server, |config| {
+        config.state_file = Some(state_file.to_path_buf());
+    });
+    let plan = dyfi.plan().unwrap();
+    assert_eq!(plan.verdict, Verdict::Nop);
+    assert_eq!(reasons(&plan), vec![Reason::Current, Reason::Current]);
+    assert!(!plan.would_update());
+    nothing_sent.assert();
+
+    // The records are current, but the state file shows that the address
+    // has changed since the last run
+    save(OLD_IP);
+    let (mut dyfi, _, _, nothing_sent) = planner(&mut server, |config| {
+        config.state_file = Some(state_file.to_path_buf());
+    });
+    let plan = dyfi.plan().unwrap();
+    assert_eq!(
+        reasons(&plan),
+        vec![Reason::ChangedSinceLastRun, Reason::ChangedSinceLastRun]
+    );
+    assert!(plan.would_update());
+    nothing_sent.assert();
+}
+
+#[test]
+fn test_plan_stale_dns() {
+    log_init();
+    let mut server = TestServer::new();
+    let response = server.update_mock().expect(1).create();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let resolver = FakeResolver::default();
+    dyfi.set_resolver(Box::new(resolver.clone()));
+    dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    for host in HOSTS {
+        resolver.set(host, &[MOCK_IP]);
+    }
+    dyfi.run();
+    // dy.fi has accepted the address, but a lookup keeps giving the old one
+    let old_ip = OLD_IP.parse().unwrap();
+    resolver.set(HOSTS[0], &[OLD_IP]);
+    for _ in 0..2 {
+        decide_check(&mut dyfi, MOCK_IP, [OLD_IP, MOCK_IP]);
+    }
+    let plan = dyfi.plan().unwrap();
+    response.assert();
+    assert_eq!(
+        reasons(&plan),
+        vec![Reason::Lagging(old_ip), Reason::Current]
+    );
+    assert!(!plan.would_update());
+}
@@ -0,0 +1,446 @@
+//! Drives `resolve_status()` directly, with a fake clock and resolver, to
+//! check its decisions without sending anything to dy.fi. When an update is
+//! decided on, it goes to a server with no mocks, and its result is ignored.
+
+use super::{log_init, logging, TestServer, MOCK_IP};
+use crate::client::clock::Clock;
+use crate::client::resolver::Resolver;
+use crate::client::Dyfi;
+use crate::types::{Config, CurrentIps, LoopStatus};
+use crate::FORCE_UPDATE_INTERVAL;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+pub(super) const OLD_IP: &str = "198.51.100.1"; // RFC 5737
+pub(super) const HOSTS: [&str; 2] = ["mock-some-more.dy.fi", "mock.dy.fi"];
+
+/// A clock that only moves when told to
+#[derive(Clone)]
+pub(super) struct FakeClock {
+    start: Instant,
+    wall_start: SystemTime,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl FakeClock {
+    pub(super) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            wall_start: SystemTime::now(),
+            offset: Arc::default(),
+        }
+    }
+
+    pub(super) fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap() += by;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.wall_start + *self.offset.lock().unwrap()
+    }
+}
+
+/// Answers with the records it has been given. A host without records
+/// fails to resolve.
+#[derive(Clone, Default)]
+pub(super) struct FakeResolver {
+    records: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+}
+
+impl FakeResolver {
+    pub(super) fn set(&self, host: &str, ips: &[&str]) {
+        let ips = ips.iter().map(|ip| ip.parse().unwrap()).collect();
+        self.records.lock().unwrap().insert(host.to_string(), ips);
+    }
+
+    pub(super) fn fail(&self, host: &str) {
+        self.records.lock().unwrap().remove(host);
+    }
+}
+
+impl Resolver for FakeResolver {
+    fn lookup(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        self.records
+            .lock()
+            .unwrap()
+            .get(host)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such host"))
+    }
+}
+
+/// What `resolve_status()` decided, without the result of the update
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Nop,
+    Update,
+    Deferred(&'static str),
+}
+
+impl From<LoopStatus> for Decision {
+    fn from(status: LoopStatus) -> Self {
+        match status {
+            LoopStatus::Nop => Self::Nop,
+            LoopStatus::Action(_) => Self::Update,
+            LoopStatus::Deferred { reason, .. } => Self::Deferred(reason),
+        }
+    }
+}
+
+struct Harness {
+    dyfi: Dyfi,
+    clock: FakeClock,
+    resolver: FakeResolver,
+    // Kept alive for the update requests, which get no mocked response
+    _server: TestServer,
+}
+
+impl Harness {
+    /// A client whose hostnames both resolve to `MOCK_IP`, which is also
+    /// the detected address
+    fn new(configure: impl FnOnce(&mut Config)) -> Self {
+        let server = TestServer::new();
+        let mut config = server.make_test_config();
+        configure(&mut config);
+        let mut dyfi = Dyfi::from(config).unwrap();
+        let clock = FakeClock::new();
+        let resolver = FakeResolver::default();
+        dyfi.set_clock(Box::new(clock.clone()));
+        dyfi.set_resolver(Box::new(resolver.clone()));
+        for host in HOSTS {
+            dyfi.set_previous_ips(host, vec![MOCK_IP.parse().unwrap()]);
+            resolver.set(host, &[MOCK_IP]);
+        }
+        dyfi.set_detected_ip(Some(MOCK_IP.parse().unwrap()));
+        Self {
+            dyfi,
+            clock,
+            resolver,
+            _server: server,
+        }
+    }
+
+    /// Pretends that the last update went through `ago`
+    fn updated_ago(&mut self, ago: Duration) {
+        self.dyfi.mark_updated();
+        self.clock.advance(ago);
+    }
+
+    fn decide(&mut self) -> Decision {
+        self.dyfi.resolve_status().into()
+    }
+}
+
+/// One row of the scenario matrix
+struct Scenario {
+    name: &'static str,
+    /// How long ago the last update went through, if ever
+    last_update: Option<Duration>,
+    /// The records of the hostnames, both on the previous check and now, in
+    /// the order of `HOSTS`. `None` fails to resolve now.
+    records: [Option<&'static [&'static str]>; 2],
+    detected: Option<&'static str>,
+    min_update_interval: u64,
+    debounce: u64,
+    expected: Decision,
+}
+
+const HOUR: Duration = Duration::from_hours(1);
+const CURRENT: Option<&[&str]> = Some(&[MOCK_IP]);
+const OUTDATED: Option<&[&str]> = Some(&[OLD_IP]);
+
+impl Default for Scenario {
+    /// Updated an hour ago and nothing has changed since
+    fn default() -> Self {
+        Self {
+            name: "",
+            last_update: Some(HOUR),
+            records: [CURRENT, CURRENT],
+            detected: Some(MOCK_IP),
+            min_update_interval: 0,
+            debounce: 0,
+            expected: Decision::Nop,
+        }
+    }
+}
+
+// One row per scenario, however many there are
+#[allow(clippy::too_many_lines)]
+fn scenarios() -> Vec<Scenario> {
+    vec![
+        Scenario {
+            name: "records are current",
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "never updated",
+            last_update: None,
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "force interval has passed",
+            last_update: Some(Duration::from_secs(FORCE_UPDATE_INTERVAL + 1)),
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "force interval has nearly passed",
+            last_update: Some(Duration::from_secs(FORCE_UPDATE_INTERVAL - 1)),
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "one host is outdated",
+            records: [CURRENT, OUTDATED],
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "one host has no records",
+            records: [Some(&[]), CURRENT],
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "one host fails to resolve",
+            records: [CURRENT, None],
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "non-routable records are ignored",
+            records: [Some(&[MOCK_IP, "fd00::1"]), CURRENT],
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "loopback records aren't a reason to update",
+            records: [Some(&["127.0.0.1"]), Some(&["::1"])],
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "unspecified records aren't a reason to update",
+            records: [Some(&["0.0.0.0", "::"]), CURRENT],
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "loopback next to an outdated record",
+            records: [Some(&[OLD_IP, "127.0.0.1"]), CURRENT],
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "other family isn't compared",
+            records: [Some(&[MOCK_IP, "2001:db8::2"]), CURRENT],
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "outdated within the minimum interval",
+            records: [OUTDATED, OUTDATED],
+            min_update_interval: 7200,
+            expected: Decision::Deferred("minimum update interval"),
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "outdated after the minimum interval",
+            records: [OUTDATED, OUTDATED],
+            min_update_interval: 1800,
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "new address is debounced",
+            records: [OUTDATED, CURRENT],
+            debounce: 60,
+            expected: Decision::Deferred("IP change debounce"),
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "missing records aren't debounced",
+            records: [None, CURRENT],
+            debounce: 60,
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "no detected address, records differ",
+            records: [OUTDATED, OUTDATED],
+            detected: None,
+            ..Scenario::default()
+        },
+        Scenario {
+            name: "no detected address, host fails to resolve",
+            records: [CURRENT, None],
+            detected: None,
+            expected: Decision::Update,
+            ..Scenario::default()
+        },
+    ]
+}
+
+#[test]
+fn test_resolve_status_matrix() {
+    log_init();
+    for scenario in scenarios() {
+        let mut harness = Harness::new(|config| {
+            config.min_update_interval = scenario.min_update_interval;
+            config.debounce = scenario.debounce;
+        });
+        if let Some(ago) = scenario.last_update {
+            harness.updated_ago(ago);
+        }
+        for (host, records) in HOSTS.iter().zip(scenario.records) {
+            match records {
+                Some(ips) => {
+                    harness.resolver.set(host, ips);
+                    harness.dyfi.set_previous_ips(
+                        host,
+                        ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+                    );
+                }
+                None => harness.resolver.fail(host),
+            }
+        }
+        harness.dyfi.set_detected_ip(
+            scenario
+                .detected
+                .map(|ip| ip.parse::<CurrentIps>().unwrap()),
+        );
+        assert_eq!(harness.decide(), scenario.expected, "{}", scenario.name);
+    }
+}
+
+#[test]
+fn test_resolve_status_debounce_settles() {
+    log_init();
+    let mut harness = Harness::new(|config| config.debounce = 60);
+    harness.updated_ago(HOUR);
+    harness.resolver.set(HOSTS[0], &[OLD_IP]);
+    assert_eq!(harness.decide(), Decision::Deferred("IP change debounce"));
+    harness.clock.advance(Duration::from_secs(30));
+    assert_eq!(harness.decide(), Decision::Deferred("IP change debounce"));
+    harness.clock.advance(Duration::from_secs(30));
+    assert_eq!(harness.decide(), Decision::Update);
+}
+
+/// Checks every hour for a week without anything changing. The only update
+/// must be the forced one, right after the force interval has passed.
+#[test]
+fn test_resolve_status_forced_update_over_a_week() {
+    log_init();
+    let mut harness = Harness::new(|_| ());
+    harness.updated_ago(Duration::ZERO);
+    let force_hours = FORCE_UPDATE_INTERVAL / 3600;
+    let mut updates = vec![];
+    for hour in 1..=24 * 7 {
+        harness.clock.advance(HOUR);
+        if harness.decide() == Decision::Update {
+            updates.push(hour);
+            // Pretend that dy.fi accepted it
+            harness.dyfi.mark_updated();
+        }
+    }
+    assert_eq!(updates, vec![force_hours]);
+}
+
+/// A hostname that turns out to resolve to `127.0.0.1` keeps its last known
+/// records, instead of looking like it has none
+#[test]
+fn test_resolve_status_broken_resolution() {
+    log_init();
+    let mut harness = Harness::new(|_| ());
+    harness.updated_ago(HOUR);
+    harness.resolver.set(HOSTS[0], &["127.0.0.1"]);
+    assert_eq!(harness.decide(), Decision::Nop);
+    assert_eq!(harness.decide(), Decision::Nop);
+    logging::assert_logged(
+        "WARN mock-some-more.dy.fi resolves only to 127.0.0.1, hostname \
+        resolution appears to be broken, not updating it on that basis",
+    );
+    harness.resolver.set(HOSTS[0], &[OLD_IP]);
+    assert_eq!(harness.decide(), Decision::Update);
+}
+
+/// Without any earlier records, broken resolution still doesn't force an
+/// update
+#[test]
+fn test_resolve_status_broken_resolution_without_records() {
+    log_init();
+    let mut harness = Harness::new(|_| ());
+    harness.updated_ago(HOUR);
+    harness.dyfi.set_previous_ips(HOSTS[0], vec![]);
+    harness.resolver.set(HOSTS[0], &["127.0.0.1"]);
+    assert_eq!(harness.decide(), Decision::Nop);
+}
+
+#[test]
+fn test_resolve_status_confirm_delay() {
+    log_init();
+    let mut harness = Harness::new(|config| config.confirm_delay = 30);
+    harness.updated_ago(HOUR);
+    harness.resolver.set(HOSTS[0], &[OLD_IP]);
+    harness.dyfi.set_fake_ip(MOCK_IP.parse().unwrap());
+    assert_eq!(harness.decide(), Decision::Update);
+    logging::assert_logged(&format!("Confirming the new address {MOCK_IP}"));
+}
+
+#[test]
+fn test_resolve_status_confirm_delay_changed_again() {
+    log_init();
+    let mut harness = Harness::new(|config| config.confirm_delay = 30);
+    harness.updated_ago(HOUR);
+    harness.resolver.set(HOSTS[0], &[OLD_IP]);
+    harness.dyfi.set_fake_ip("203.0.113.7".parse().unwrap());
+    assert_eq!(harness.decide(), Decision::Nop);
+    logging::assert_logged("The address changed again to 203.0.113.7");
+}
+
+/// Only a change is confirmed, not an update for other reasons
+#[test]
+fn test_resolve_status_confirm_delay_no_change() {
+    log_init();
+    let mut harness = Harness::new(|config| config.confirm_delay = 30);
+    harness.updated_ago(HOUR);
+    // Would fail the confirmation
+    harness.dyfi.set_fake_ip("203.0.113.7".parse().unwrap());
+    harness.dyfi.set_previous_ips(HOSTS[0], vec![]);
+    assert_eq!(harness.decide(), Decision::Update);
+    logging::assert_not_logged("Confirming");
+}
+
+#[test]
+fn test_resolve_status_manual_update() {
+    log_init();
+    let mut harness = Harness::new(|config| config.min_update_interval = 7200);
+    harness.updated_ago(HOUR);
+    assert_eq!(harness.decide(), Decision::Nop);
+    harness.dyfi.loop_signal().force_update();
+    assert_eq!(harness.decide(), Decision::Update);
+    logging::assert_logged("Forcing an update of all hostnames as requested");
+    assert_eq!(harness.decide(), Decision::Nop);
+}
+
+/// A forced update still counts towards the daily request limit, and waits
+/// for it instead of being forgotten
+#[test]
+fn test_resolve_status_manual_update_daily_limit() {
+    log_init();
+    let mut harness = Harness::new(|config| config.max_daily_requests = 1);
+    harness.updated_ago(HOUR);
+    harness.resolver.set(HOSTS[0], &[OLD_IP]);
+    assert_eq!(harness.decide(), Decision::Update);
+    harness.resolver.set(HOSTS[0], &[MOCK_IP]);
+    harness.dyfi.mark_updated();
+    harness.dyfi.loop_signal().force_update();
+    assert_eq!(harness.decide(), Decision::Deferred("daily request limit"));
+    harness.clock.advance(24 * HOUR);
+    assert_eq!(harness.decide(), Decision::Update);
+}
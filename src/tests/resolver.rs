@@ -0,0 +1,99 @@
+use super::resolve_status::{FakeResolver, HOSTS};
+use super::{log_init, logging, TestServer, MOCK_IP};
+use crate::client::resolver::{Resolver, TimeoutResolver};
+use crate::client::Dyfi;
+use crate::types::DyfiResponseCode;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Answers like `FakeResolver`, but only after `delay`
+struct SlowResolver {
+    inner: FakeResolver,
+    delay: Duration,
+    lookups: Arc<AtomicUsize>,
+}
+
+impl Resolver for SlowResolver {
+    fn lookup(&self, host: &str) -> std::io::Result<Vec<std::net::IpAddr>> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(self.delay);
+        self.inner.lookup(host)
+    }
+}
+
+fn slow(delay: Duration) -> SlowResolver {
+    let inner = FakeResolver::default();
+    for host in HOSTS {
+        inner.set(host, &[MOCK_IP]);
+    }
+    SlowResolver {
+        inner,
+        delay,
+        lookups: Arc::default(),
+    }
+}
+
+#[test]
+fn test_timeout_resolver_answers_in_time() {
+    let resolver =
+        TimeoutResolver::new(slow(Duration::ZERO), Duration::from_secs(5));
+    let ips = resolver.lookup(HOSTS[0]).unwrap();
+    assert_eq!(ips, vec![MOCK_IP.parse::<std::net::IpAddr>().unwrap()]);
+    let e = resolver.lookup("unknown.dy.fi").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn test_timeout_resolver_gives_up() {
+    let resolver = TimeoutResolver::new(
+        slow(Duration::from_secs(5)),
+        Duration::from_millis(50),
+    );
+    let start = Instant::now();
+    let e = resolver.lookup(HOSTS[0]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+/// A resolver that doesn't answer doesn't keep the first check from
+/// happening, and isn't retried
+#[test]
+fn test_startup_resolve_timeout() {
+    log_init();
+    let mut server = TestServer::new();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let resolver = slow(Duration::from_secs(5));
+    let lookups = Arc::clone(&resolver.lookups);
+    dyfi.set_resolver(Box::new(TimeoutResolver::new(
+        resolver,
+        Duration::from_millis(50),
+    )));
+    let get_ip = server.get_ip_mock();
+    let response = server.update_mock().create();
+    let start = Instant::now();
+    assert_eq!(dyfi.run(), DyfiResponseCode::Ok);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    get_ip.assert();
+    response.assert();
+    logging::assert_logged("mock.dy.fi currently resolves to []");
+    // Without retries. Unresolved hostnames are updated without being
+    // looked up again.
+    assert_eq!(lookups.load(Ordering::SeqCst), HOSTS.len());
+}
+
+/// `--query` looks up the nameserver through the client's resolver too
+#[test]
+fn test_report_records_uses_resolver() {
+    log_init();
+    let server = TestServer::new();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let resolver = slow(Duration::ZERO);
+    let lookups = Arc::clone(&resolver.lookups);
+    dyfi.set_resolver(Box::new(resolver));
+    // The fake resolver doesn't know the nameserver
+    assert_eq!(dyfi.report_records(), DyfiResponseCode::DNSErr);
+    assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    logging::assert_logged("ERROR Unable to resolve nameserver");
+}
@@ -0,0 +1,141 @@
+use super::TempPath;
+use crate::settings::{Settings, KNOWN_SETTINGS};
+use crate::{configured_hostnames, read_config};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// The environment of the tests: a user and password, and some settings
+/// that the command line may override
+fn fake_env(name: &str) -> Option<String> {
+    let value = match name {
+        "DYFI_USER" => "envuser",
+        "DYFI_PASSWORD" => "envpassword",
+        "DYFI_DEBOUNCE" => "20",
+        "DYFI_RETRY_BASE" => "5",
+        _ => return None,
+    };
+    Some(value.to_string())
+}
+
+fn set(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+        .collect()
+}
+
+#[test]
+fn test_settings_precedence() {
+    let settings = Settings::new(
+        set(&[("DYFI_DEBOUNCE", "30"), ("DYFI_USER", "cliuser")]),
+        fake_env,
+    );
+    let config = read_config(&settings, vec!["a.dy.fi".to_string()]);
+    // The command line beats the environment
+    assert_eq!(config.debounce, 30);
+    assert_eq!(config.user, "cliuser");
+    // The environment beats the defaults
+    assert_eq!(config.retry_base, 5);
+    assert_eq!(config.password.expose(), "envpassword");
+    // Defaults are used for what is set nowhere
+    assert_eq!(config.retry_cap, 3600);
+    assert!(settings.unknown_overrides().is_empty());
+}
+
+/// Like `dotenvy::dotenv()` without touching the environment of the tests:
+/// the .env file is read beneath the fake environment
+#[test]
+fn test_settings_file_is_beneath_environment() {
+    let path = TempPath::new("env");
+    std::fs::write(&path, "DYFI_STATE_FILE=file\nDYFI_USER=fileuser\n")
+        .unwrap();
+    let file: HashMap<String, String> = dotenvy::from_path_iter(&path)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let settings =
+        Settings::new(set(&[("DYFI_DEBOUNCE", "30")]), move |name| {
+            fake_env(name).or_else(|| file.get(name).cloned())
+        });
+    assert_eq!(settings.var("DYFI_STATE_FILE").as_deref(), Some("file"));
+    // The .env file doesn't replace what the environment already has
+    assert_eq!(settings.var("DYFI_USER").as_deref(), Some("envuser"));
+    assert_eq!(settings.var("DYFI_DEBOUNCE").as_deref(), Some("30"));
+}
+
+#[test]
+fn test_settings_unknown_overrides() {
+    let settings = Settings::new(
+        set(&[("DYFI_DEBOUNCE", "30"), ("DYFI_DEBOUNSE", "30")]),
+        fake_env,
+    );
+    read_config(&settings, vec!["a.dy.fi".to_string()]);
+    assert_eq!(settings.unknown_overrides(), vec!["DYFI_DEBOUNSE"]);
+}
+
+#[test]
+fn test_settings_hostnames_from_set_and_command_line() {
+    let settings =
+        Settings::new(set(&[("DYFI_HOSTNAMES", "set.dy.fi")]), fake_env);
+    // --hostname beats --set, which is still not an unknown setting
+    let hostnames = configured_hostnames(&settings, &["cli.dy.fi".to_string()]);
+    assert_eq!(hostnames, vec!["cli.dy.fi"]);
+    assert!(settings.unknown_overrides().is_empty());
+    assert_eq!(configured_hostnames(&settings, &[]), vec!["set.dy.fi"]);
+}
+
+thread_local! {
+    static LOOKED_UP: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+#[test]
+fn test_settings_read_are_known() {
+    let settings = Settings::new(vec![], |name| {
+        LOOKED_UP.with(|names| names.borrow_mut().push(name.to_string()));
+        fake_env(name)
+    });
+    read_config(&settings, vec!["a.dy.fi".to_string()]);
+    LOOKED_UP.with(|names| {
+        assert!(!names.borrow().is_empty());
+        for name in names.borrow().iter() {
+            assert!(KNOWN_SETTINGS.contains(&name.as_str()), "{name}");
+        }
+    });
+}
+
+#[test]
+fn test_settings_parse() {
+    let settings = Settings::new(
+        set(&[("DYFI_FLAG", "Yes"), ("DYFI_NUMBER", " 7 ")]),
+        fake_env,
+    );
+    assert!(settings.flag("DYFI_FLAG"));
+    assert!(!settings.flag("DYFI_UNSET"));
+    assert_eq!(settings.parse("DYFI_NUMBER", 1), 7);
+    assert_eq!(settings.parse("DYFI_UNSET", 1), 1);
+    assert_eq!(settings.parse_opt::<u32>("DYFI_UNSET"), None);
+}
+
+#[test]
+#[should_panic(expected = "DYFI_NUMBER has an invalid value: 'seven'")]
+fn test_settings_parse_invalid() {
+    let settings = Settings::new(set(&[("DYFI_NUMBER", "seven")]), fake_env);
+    settings.parse("DYFI_NUMBER", 1);
+}
+
+#[test]
+fn test_abuse_lockout_file_default() {
+    let settings = Settings::new(vec![], fake_env);
+    let config = read_config(&settings, vec!["a.dy.fi".to_string()]);
+    assert_eq!(config.abuse_lockout_file, None);
+
+    let settings = Settings::new(
+        set(&[("DYFI_STATE_FILE", "/var/lib/dyfi/state")]),
+        fake_env,
+    );
+    let config = read_config(&settings, vec!["a.dy.fi".to_string()]);
+    assert_eq!(
+        config.abuse_lockout_file.as_deref(),
+        Some(std::path::Path::new("/var/lib/dyfi/dyfi-client.abuse"))
+    );
+}
@@ -0,0 +1,97 @@
+use super::resolve_status::{FakeClock, HOSTS, OLD_IP};
+use super::{log_init, TestServer, MOCK_IP};
+use crate::client::Dyfi;
+use std::time::Duration;
+
+fn client() -> (Dyfi, FakeClock, TestServer) {
+    let server = TestServer::new();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    let clock = FakeClock::new();
+    dyfi.set_clock(Box::new(clock.clone()));
+    (dyfi, clock, server)
+}
+
+#[test]
+fn test_snapshot_fresh_client() {
+    log_init();
+    let (dyfi, _clock, _server) = client();
+    let snapshot = dyfi.state_snapshot();
+    assert_eq!(snapshot.config.hostnames, HOSTS);
+    assert_eq!(snapshot.my_ip, None);
+    assert!(snapshot.previous_ips.is_empty());
+    assert_eq!(snapshot.since_last_update, None);
+    #[cfg(feature = "serde")]
+    {
+        let json = snapshot.to_json();
+        assert!(
+            json.starts_with(r#"{"config":{"user":"mockuser","#),
+            "{json}"
+        );
+        assert!(json.contains(r#""my_ip":null"#), "{json}");
+        assert!(json.contains(r#""previous_ips":{}"#), "{json}");
+        assert!(json.contains(r#""since_last_update":null"#), "{json}");
+        assert!(json.contains(r#""ip_family":"both""#), "{json}");
+    }
+}
+
+#[test]
+fn test_snapshot_after_update() {
+    log_init();
+    let (mut dyfi, clock, _server) = client();
+    dyfi.set_detected_ip(Some(MOCK_IP.parse().unwrap()));
+    dyfi.set_previous_ips(HOSTS[1], vec![OLD_IP.parse().unwrap()]);
+    dyfi.mark_updated();
+    clock.advance(Duration::from_mins(5));
+    let snapshot = dyfi.state_snapshot();
+    assert_eq!(snapshot.since_last_update, Some(Duration::from_mins(5)));
+    assert!(snapshot.last_update_wall.is_some());
+    #[cfg(feature = "serde")]
+    {
+        let json = snapshot.to_json();
+        assert!(
+            json.contains(&format!(
+                r#""my_ip":{{"ipv4":"{MOCK_IP}","ipv6":null}}"#
+            )),
+            "{json}"
+        );
+        assert!(
+            json.contains(&format!(
+                r#""previous_ips":{{"{}":["{OLD_IP}"]}}"#,
+                HOSTS[1]
+            )),
+            "{json}"
+        );
+        assert!(json.contains(r#""since_last_update":300"#), "{json}");
+    }
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn test_snapshot_host_stats() {
+    log_init();
+    let mut server = TestServer::new();
+    let get_ip = server.get_ip_mock();
+    let response = server.dyfi_mock_base().with_body("badauth").create();
+    let mut dyfi = Dyfi::from(server.make_test_config()).unwrap();
+    dyfi.run();
+    get_ip.assert();
+    response.assert();
+    let json = dyfi.state_snapshot().to_json();
+    assert!(
+        json.contains(&format!(
+            r#""{}":{{"attempts":1,"successes":0,"last_error":"badauth"}}"#,
+            HOSTS[0]
+        )),
+        "{json}"
+    );
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn test_snapshot_leaves_out_password() {
+    log_init();
+    let (dyfi, _clock, _server) = client();
+    let json = dyfi.state_snapshot().to_json();
+    assert!(!json.contains("mockpassword"), "{json}");
+    assert!(!json.contains("password"), "{json}");
+}
@@ -0,0 +1,122 @@
+use super::TempPath;
+use crate::state::{HostStats, State};
+use std::net::IpAddr;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn v1_state() -> State {
+    let mut state = State {
+        last_update: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+        ..State::default()
+    };
+    state.previous_ips.insert(
+        "a.dy.fi".to_string(),
+        vec![
+            "192.0.2.1".parse::<IpAddr>().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        ],
+    );
+    state.previous_ips.insert("b.dy.fi".to_string(), vec![]);
+    state.requests = vec![UNIX_EPOCH + Duration::from_secs(1_699_990_000)];
+    state
+}
+
+#[test]
+fn test_state_round_trip() {
+    let path = TempPath::new("roundtrip");
+    let state = v1_state();
+    state.save(&path).unwrap();
+    let loaded = State::load(&path);
+    assert_eq!(loaded, Some(state));
+}
+
+#[test]
+fn test_state_parse_v1() {
+    let contents = "\
+        # written by dyfi-client\n\
+        version = 1\n\
+        last_update = 1700000000\n\
+        host = a.dy.fi 192.0.2.1 2001:db8::1\n\
+        host = b.dy.fi\n\
+        requests = 1699990000\n\
+        end\n";
+    assert_eq!(State::parse(contents), Ok(v1_state()));
+}
+
+#[test]
+fn test_state_host_stats() {
+    let contents = "\
+        version = 1\n\
+        stats = a.dy.fi 12 11 unrecognized response 'x = y'\n\
+        stats = b.dy.fi 3 3\n\
+        end\n";
+    let state = State::parse(contents).unwrap();
+    assert_eq!(
+        state.host_stats["a.dy.fi"],
+        HostStats {
+            attempts: 12,
+            successes: 11,
+            last_error: Some("unrecognized response 'x = y'".to_string()),
+        }
+    );
+    assert_eq!(state.host_stats["b.dy.fi"].last_error, None);
+    assert_eq!(State::parse(&state.serialize()), Ok(state));
+    assert!(State::parse("version = 1\nstats = a.dy.fi 1\nend\n").is_err());
+    assert!(State::parse("version = 1\nstats = a.dy.fi x 1\nend\n").is_err());
+}
+
+#[test]
+fn test_state_unknown_fields_ignored() {
+    let contents = "version = 1\nfuture_field = 42\nend\n";
+    assert_eq!(State::parse(contents), Ok(State::default()));
+}
+
+#[test]
+fn test_state_incompatible_version() {
+    assert!(State::parse("version = 2\nend\n").is_err());
+    assert!(State::parse("version = x\nend\n").is_err());
+    assert!(State::parse("last_update = 1700000000\nend\n").is_err());
+}
+
+#[test]
+fn test_state_truncated() {
+    let contents = v1_state().serialize();
+    for len in 0..contents.len() - 1 {
+        assert!(State::parse(&contents[..len]).is_err(), "{len}");
+    }
+}
+
+#[test]
+fn test_state_corrupt_file_is_no_state() {
+    let path = TempPath::new("corrupt");
+    std::fs::write(&path, b"version = 1\nlast_update = \xff\xfe\n").unwrap();
+    let loaded = State::load(&path);
+    std::fs::remove_file(&path).ok();
+    assert_eq!(loaded, None);
+    assert_eq!(State::load(&path), None);
+}
+
+#[test]
+fn test_state_discard_future_times() {
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let mut state = State {
+        last_update: Some(now + Duration::from_hours(24)),
+        requests: vec![
+            now - Duration::from_mins(1),
+            now,
+            now + Duration::from_secs(1),
+        ],
+        ..Default::default()
+    };
+    state.discard_future_times(now);
+    assert_eq!(state.last_update, None);
+    assert_eq!(state.requests, vec![now - Duration::from_mins(1), now]);
+}
+
+#[test]
+fn test_state_discard_future_times_keeps_past() {
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let mut state = v1_state();
+    let before = state.serialize();
+    state.discard_future_times(now + Duration::from_hours(1));
+    assert_eq!(state.serialize(), before);
+}
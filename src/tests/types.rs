@@ -0,0 +1,431 @@
+use super::{log_init, logging};
+use crate::types::{
+    CheckOutcome, CurrentIps, DyfiError, DyfiResponse, DyfiResponseCode,
+    HostnameStyle, IpFamily, IpRange, IpRanges, LoopSignal,
+    OtherResponseLogging, RepeatedError, ResponseMap, SuccessCodes,
+};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_parse_current_ips_single() {
+    let ips: CurrentIps = "192.0.2.1\n".parse().unwrap();
+    assert_eq!(ips.v4, Some("192.0.2.1".parse().unwrap()));
+    assert_eq!(ips.v6, None);
+}
+
+#[test]
+fn test_parse_current_ips_dual() {
+    let ips: CurrentIps = "2001:db8::1\n192.0.2.1\n".parse().unwrap();
+    assert_eq!(ips.v4, Some("192.0.2.1".parse().unwrap()));
+    assert_eq!(ips.v6, Some("2001:db8::1".parse().unwrap()));
+    assert_eq!(ips.to_string(), "192.0.2.1, 2001:db8::1");
+}
+
+#[test]
+fn test_parse_current_ips_with_port() {
+    let ips: CurrentIps = "192.0.2.1:443".parse().unwrap();
+    assert_eq!(ips.v4, Some("192.0.2.1".parse().unwrap()));
+    let ips: CurrentIps = "[2001:db8::1]:443".parse().unwrap();
+    assert_eq!(ips.v6, Some("2001:db8::1".parse().unwrap()));
+}
+
+#[test]
+fn test_parse_current_ips_quoted() {
+    let ips: CurrentIps = "\"192.0.2.1\"\n".parse().unwrap();
+    assert_eq!(ips.v4, Some("192.0.2.1".parse().unwrap()));
+    let ips: CurrentIps = "  ' 2001:db8::1 '  ".parse().unwrap();
+    assert_eq!(ips.v6, Some("2001:db8::1".parse().unwrap()));
+    let ips: CurrentIps = "\"192.0.2.1:443\"".parse().unwrap();
+    assert_eq!(ips.v4, Some("192.0.2.1".parse().unwrap()));
+}
+
+#[test]
+fn test_parse_current_ips_plain_and_quoted_agree() {
+    let plain: CurrentIps = "192.0.2.1".parse().unwrap();
+    let quoted: CurrentIps = "\"192.0.2.1\"".parse().unwrap();
+    assert_eq!(plain, quoted);
+}
+
+#[test]
+fn test_parse_current_ips_quotes_only() {
+    assert!("\"\"".parse::<CurrentIps>().is_err());
+    assert!("\"not an address\"".parse::<CurrentIps>().is_err());
+}
+
+#[test]
+fn test_parse_current_ips_empty() {
+    assert!("\n".parse::<CurrentIps>().is_err());
+}
+
+#[test]
+fn test_loop_signal_wait_timeout() {
+    let signal = LoopSignal::default();
+    let start = Instant::now();
+    signal.wait(Duration::from_millis(50));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(!signal.is_shutdown());
+}
+
+#[test]
+fn test_loop_signal_wake() {
+    let signal = LoopSignal::default();
+    let waker = signal.clone();
+    let start = Instant::now();
+    let handle = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        waker.wake();
+    });
+    signal.wait(Duration::from_secs(30));
+    handle.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(30));
+    assert!(!signal.is_shutdown());
+}
+
+#[test]
+fn test_loop_signal_shutdown() {
+    let signal = LoopSignal::default();
+    signal.clone().shutdown();
+    let start = Instant::now();
+    signal.wait(Duration::from_secs(30));
+    assert!(start.elapsed() < Duration::from_secs(30));
+    assert!(signal.is_shutdown());
+}
+
+#[test]
+fn test_response_known_keywords() {
+    assert!(matches!(
+        "badauth".parse::<DyfiResponse>().unwrap(),
+        DyfiResponse::BadAuth
+    ));
+    assert!(matches!(
+        "good\n".parse::<DyfiResponse>().unwrap(),
+        DyfiResponse::Good(None)
+    ));
+    assert!(matches!(
+        "nochg".parse::<DyfiResponse>().unwrap(),
+        DyfiResponse::NoChg
+    ));
+}
+
+#[test]
+fn test_response_extended_tokens() {
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    assert!(matches!(
+        "good 192.0.2.1 ttl=60".parse::<DyfiResponse>().unwrap(),
+        DyfiResponse::Good(Some(x)) if x == ip
+    ));
+    assert!(matches!(
+        "badip 192.0.2.1 reason".parse::<DyfiResponse>().unwrap(),
+        DyfiResponse::BadIP(x) if x == ip
+    ));
+    assert!(matches!(
+        "nochg 192.0.2.1".parse::<DyfiResponse>().unwrap(),
+        DyfiResponse::NoChg
+    ));
+    assert!(matches!(
+        "abuse until tomorrow".parse::<DyfiResponse>().unwrap(),
+        DyfiResponse::Abuse
+    ));
+}
+
+#[test]
+fn test_response_extra_tokens_logged() {
+    log_init();
+    "good 192.0.2.1 ttl=60".parse::<DyfiResponse>().unwrap();
+    logging::assert_logged(r#"after 'good' in dy.fi response: ["ttl=60"]"#);
+    "nochg 192.0.2.1".parse::<DyfiResponse>().unwrap();
+    logging::assert_logged(r#"after 'nochg' in dy.fi response: ["192.0.2.1"]"#);
+    "good 192.0.2.1".parse::<DyfiResponse>().unwrap();
+    "mystery 192.0.2.1".parse::<DyfiResponse>().unwrap();
+    assert_eq!(
+        logging::captured()
+            .iter()
+            .filter(|line| line.contains("Ignoring extra tokens"))
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn test_response_unknown() {
+    assert!(matches!(
+        "911".parse::<DyfiResponse>().unwrap(),
+        DyfiResponse::Other(_)
+    ));
+}
+
+#[test]
+fn test_response_mapped() {
+    let map: ResponseMap =
+        "911=dnserr, !donator=BadAuth,ok=good".parse().unwrap();
+    assert!(matches!(
+        DyfiResponse::parse_with("911", &map).unwrap(),
+        DyfiResponse::DNSErr
+    ));
+    assert!(matches!(
+        DyfiResponse::parse_with("!donator", &map).unwrap(),
+        DyfiResponse::BadAuth
+    ));
+    // A mapped keyword takes an address like the one it stands for
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    assert!(matches!(
+        DyfiResponse::parse_with("ok 192.0.2.1", &map).unwrap(),
+        DyfiResponse::Good(Some(x)) if x == ip
+    ));
+    assert!(DyfiResponse::parse_with("ok not-an-ip", &map).is_err());
+    // Known keywords and unmapped ones are unaffected
+    assert!(matches!(
+        DyfiResponse::parse_with("nochg", &map).unwrap(),
+        DyfiResponse::NoChg
+    ));
+    assert!(matches!(
+        DyfiResponse::parse_with("912", &map).unwrap(),
+        DyfiResponse::Other(_)
+    ));
+}
+
+#[test]
+fn test_response_map_invalid() {
+    assert_eq!("".parse::<ResponseMap>(), Ok(ResponseMap::default()));
+    assert!("911".parse::<ResponseMap>().is_err());
+    assert!("=dnserr".parse::<ResponseMap>().is_err());
+    assert!("911=fine".parse::<ResponseMap>().is_err());
+    // dy.fi's own keywords keep their meaning
+    assert!("abuse=dnserr".parse::<ResponseMap>().is_err());
+}
+
+#[test]
+fn test_response_invalid() {
+    assert!("good not-an-ip".parse::<DyfiResponse>().is_err());
+    assert!("badip".parse::<DyfiResponse>().is_err());
+    assert!("".parse::<DyfiResponse>().is_err());
+    assert!(" \n".parse::<DyfiResponse>().is_err());
+}
+
+#[test]
+fn test_repeated_error() {
+    let mut errors = RepeatedError::default();
+    assert!(errors.record("connection refused"));
+    assert!(!errors.record("connection refused"));
+    assert!(!errors.record("connection refused"));
+    assert!(errors.record("timed out"));
+    assert!(!errors.record("timed out"));
+    errors.clear();
+    assert!(errors.record("timed out"));
+}
+
+#[test]
+fn test_response_code() {
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    let cases = [
+        (DyfiResponse::BadAuth, DyfiResponseCode::BadAuth),
+        (DyfiResponse::NoHost, DyfiResponseCode::NoHost),
+        (DyfiResponse::NotFQDN, DyfiResponseCode::NotFQDN),
+        (DyfiResponse::BadIP(ip), DyfiResponseCode::BadIP),
+        (DyfiResponse::NoChg, DyfiResponseCode::Ok),
+        (DyfiResponse::Good(Some(ip)), DyfiResponseCode::Ok),
+        (DyfiResponse::Good(None), DyfiResponseCode::Ok),
+        (DyfiResponse::DNSErr, DyfiResponseCode::DNSErr),
+        (DyfiResponse::Abuse, DyfiResponseCode::Abuse),
+        (DyfiResponse::Other("?".to_string()), DyfiResponseCode::Ok),
+    ];
+    for (response, code) in cases {
+        assert_eq!(response.code(), code, "{response:?}");
+        assert_eq!(DyfiResponseCode::from(response), code);
+    }
+}
+
+#[test]
+fn test_current_ips_changed_from() {
+    let current = CurrentIps {
+        v4: Some("192.0.2.1".parse().unwrap()),
+        v6: Some("2001:db8::2".parse().unwrap()),
+    };
+    let resolved: [IpAddr; 2] =
+        ["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+    // Only the IPv6 address has changed
+    assert_eq!(
+        current.changed_from(&resolved),
+        CurrentIps {
+            v4: None,
+            v6: current.v6
+        }
+    );
+    assert_eq!(current.changed_from(&[]), CurrentIps::default());
+    assert_eq!(
+        current.changed_from(&resolved[1..]).union(CurrentIps {
+            v4: current.v4,
+            v6: None
+        }),
+        current
+    );
+}
+
+#[test]
+fn test_error_is_transient() {
+    assert!(DyfiError::Transient("timed out".to_string()).is_transient());
+    assert!(!DyfiError::Config("no hostnames".to_string()).is_transient());
+    assert!(!DyfiError::Http("404 Not Found".to_string()).is_transient());
+    let parse_error = "not an address".parse::<CurrentIps>().unwrap_err();
+    assert!(!parse_error.is_transient(), "{parse_error}");
+}
+
+#[test]
+fn test_other_response_logging() {
+    let long = format!("<html>{}</html>", "x".repeat(500));
+    assert_eq!(
+        OtherResponseLogging::Full.describe(&long),
+        format!("'{long}'")
+    );
+    let truncated = OtherResponseLogging::Truncated.describe(&long);
+    assert!(truncated.len() < 250, "{truncated}");
+    assert!(truncated.starts_with("'<html>xxx"), "{truncated}");
+    let hidden = OtherResponseLogging::Hidden.describe(&long);
+    assert!(hidden.starts_with("513 bytes, hash "), "{hidden}");
+    assert!(!hidden.contains("html"));
+    assert_eq!(hidden, OtherResponseLogging::Hidden.describe(&long));
+    assert_eq!("hide".parse(), Ok(OtherResponseLogging::Hidden));
+    assert!("verbose".parse::<OtherResponseLogging>().is_err());
+}
+
+#[test]
+fn test_ip_range_contains() {
+    let range: IpRange = "192.0.2.0/24".parse().unwrap();
+    assert!(range.contains("192.0.2.1".parse().unwrap()));
+    assert!(range.contains("192.0.2.255".parse().unwrap()));
+    assert!(!range.contains("192.0.3.1".parse().unwrap()));
+    assert!(!range.contains("2001:db8::1".parse().unwrap()));
+    let range: IpRange = "2001:db8::/32".parse().unwrap();
+    assert!(range.contains("2001:db8:ffff::1".parse().unwrap()));
+    assert!(!range.contains("2001:db9::1".parse().unwrap()));
+    let everything: IpRange = "0.0.0.0/0".parse().unwrap();
+    assert!(everything.contains("203.0.113.7".parse().unwrap()));
+    let single: IpRange = "192.0.2.1".parse().unwrap();
+    assert!(single.contains("192.0.2.1".parse().unwrap()));
+    assert!(!single.contains("192.0.2.2".parse().unwrap()));
+}
+
+#[test]
+fn test_ip_range_invalid() {
+    for range in ["", "192.0.2.0/33", "2001:db8::/129", "192.0.2/24", "x/8"] {
+        assert!(range.parse::<IpRange>().is_err(), "{range}");
+    }
+}
+
+#[test]
+fn test_ip_ranges_allows() {
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    let none = IpRanges::default();
+    assert!(none.allows(ip("192.0.2.1")));
+    let v4_only: IpRanges = "192.0.2.0/24,198.51.100.0/24,".parse().unwrap();
+    assert_eq!(v4_only.0.len(), 2);
+    assert!(v4_only.allows(ip("198.51.100.9")));
+    assert!(!v4_only.allows(ip("203.0.113.1")));
+    // No IPv6 ranges, so IPv6 isn't restricted
+    assert!(v4_only.allows(ip("2001:db8::1")));
+    let both: IpRanges = "192.0.2.0/24, 2001:db8::/32".parse().unwrap();
+    assert!(!both.allows(ip("2001:db9::1")));
+    assert!("192.0.2.0/24,nonsense".parse::<IpRanges>().is_err());
+}
+
+#[test]
+fn test_hostname_style() {
+    assert_eq!(
+        "Repeated".parse::<HostnameStyle>().unwrap(),
+        HostnameStyle::Repeated
+    );
+    assert_eq!(
+        "joined".parse::<HostnameStyle>().unwrap(),
+        HostnameStyle::Joined
+    );
+    assert!("comma".parse::<HostnameStyle>().is_err());
+}
+
+#[test]
+fn test_ip_family() {
+    assert_eq!("v4".parse::<IpFamily>().unwrap(), IpFamily::V4);
+    assert_eq!("IPv6".parse::<IpFamily>().unwrap(), IpFamily::V6);
+    assert_eq!("both".parse::<IpFamily>().unwrap(), IpFamily::Both);
+    assert!("v5".parse::<IpFamily>().is_err());
+    let ips: CurrentIps = "192.0.2.1\n2001:db8::1\n".parse().unwrap();
+    assert_eq!(IpFamily::Both.filter(ips), ips);
+    assert_eq!(IpFamily::V4.filter(ips).v6, None);
+    assert_eq!(IpFamily::V6.filter(ips).v4, None);
+    assert!(IpFamily::V6.filter(ips).v6.is_some());
+}
+
+#[test]
+fn test_ip_family_for_connectivity() {
+    let both = IpFamily::Both;
+    assert_eq!(both.for_connectivity(true, true), IpFamily::Both);
+    assert_eq!(both.for_connectivity(false, true), IpFamily::V6);
+    assert_eq!(both.for_connectivity(true, false), IpFamily::V4);
+    // Without any route there's nothing to go by
+    assert_eq!(both.for_connectivity(false, false), IpFamily::Both);
+    // A family set explicitly is kept
+    assert_eq!(IpFamily::V4.for_connectivity(false, true), IpFamily::V4);
+    assert_eq!(IpFamily::V6.for_connectivity(true, false), IpFamily::V6);
+}
+
+#[test]
+fn test_changed_from_records() {
+    let current: CurrentIps = "192.0.2.1\n2001:db8::1\n".parse().unwrap();
+    let both: Vec<IpAddr> = vec![
+        "198.51.100.1".parse().unwrap(),
+        "192.0.2.1".parse().unwrap(),
+        "2001:db8::1".parse().unwrap(),
+    ];
+    // With a single record expected, the extra one is out of date
+    assert_eq!(
+        current.changed_from_records(&both, 1),
+        CurrentIps {
+            v4: current.v4,
+            v6: None
+        }
+    );
+    // With two, the current address being among them is enough
+    assert_eq!(
+        current.changed_from_records(&both, 2),
+        CurrentIps::default()
+    );
+    let other: Vec<IpAddr> = vec![
+        "198.51.100.1".parse().unwrap(),
+        "198.51.100.2".parse().unwrap(),
+    ];
+    assert_eq!(current.changed_from_records(&other, 2).v4, current.v4);
+    // A family without records isn't out of date
+    assert_eq!(current.changed_from_records(&other, 2).v6, None);
+}
+
+#[test]
+fn test_success_codes_default() {
+    let success = SuccessCodes::default();
+    assert_eq!(success.exit_status(DyfiResponseCode::Ok as i32), 0);
+    assert_eq!(success.exit_status(CheckOutcome::Updated.exit_code()), 0);
+    assert_eq!(success.exit_status(CheckOutcome::Unchanged.exit_code()), 12);
+    assert_eq!(success.exit_status(CheckOutcome::Deferred.exit_code()), 15);
+    assert_eq!(success.exit_status(DyfiResponseCode::BadAuth as i32), 1);
+}
+
+#[test]
+fn test_success_codes_configured() {
+    let success: SuccessCodes = "ok, unchanged,DNSErr".parse().unwrap();
+    assert_eq!(success.exit_status(CheckOutcome::Unchanged.exit_code()), 0);
+    assert_eq!(success.exit_status(DyfiResponseCode::DNSErr as i32), 0);
+    assert_eq!(success.exit_status(DyfiResponseCode::Abuse as i32), 6);
+    assert_eq!(success.exit_status(DyfiResponseCode::NoIp as i32), 13);
+    let success: SuccessCodes = "deferred, paused,noaddress".parse().unwrap();
+    assert_eq!(success.exit_status(CheckOutcome::NoAddress.exit_code()), 0);
+    assert_eq!(success.exit_status(CheckOutcome::Deferred.exit_code()), 0);
+    assert_eq!(success.exit_status(CheckOutcome::Paused.exit_code()), 0);
+    let success: SuccessCodes = "noip,nonetwork".parse().unwrap();
+    assert_eq!(success.exit_status(DyfiResponseCode::NoIp as i32), 0);
+    assert_eq!(success.exit_status(DyfiResponseCode::NoNetwork as i32), 0);
+    // Without ok, an update still exits with its own status, which is 0
+    let success: SuccessCodes = "badauth".parse().unwrap();
+    assert_eq!(success.exit_status(DyfiResponseCode::BadAuth as i32), 0);
+    assert_eq!(success.exit_status(DyfiResponseCode::Ok as i32), 0);
+    assert_eq!(success.exit_status(CheckOutcome::Unchanged.exit_code()), 12);
+    assert!("ok,nochange".parse::<SuccessCodes>().is_err());
+}
@@ -1,8 +1,235 @@
+use super::TempPath;
 use crate::types::Hostname;
-use crate::util::{self, split_to_sorted_vec};
+use crate::util::{
+    addresses_diverge, format_duration, has_route, is_routable,
+    is_temporary_resolve_error, map_concurrently, normalize_url,
+    parse_sha256_hex, sanitize_snippet, split_hostnames, split_to_sorted_vec,
+    write_atomic,
+};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
 #[test]
 fn test_split_empty_str() {
     let t: Vec<Hostname> = split_to_sorted_vec("");
     assert_eq!(t, Vec::<Hostname>::new());
 }
+
+#[test]
+fn test_split_commas() {
+    assert_eq!(
+        split_to_sorted_vec("b.dy.fi,a.dy.fi,,b.dy.fi"),
+        vec!["a.dy.fi", "b.dy.fi"]
+    );
+}
+
+#[test]
+fn test_split_spaces() {
+    assert_eq!(
+        split_to_sorted_vec("  b.dy.fi a.dy.fi\tc.dy.fi\n"),
+        vec!["a.dy.fi", "b.dy.fi", "c.dy.fi"]
+    );
+}
+
+#[test]
+fn test_split_mixed() {
+    assert_eq!(
+        split_to_sorted_vec("b.dy.fi, a.dy.fi ,c.dy.fi  a.dy.fi"),
+        vec!["a.dy.fi", "b.dy.fi", "c.dy.fi"]
+    );
+}
+
+#[test]
+fn test_split_hostnames_trailing_dot() {
+    assert_eq!(split_hostnames("a.dy.fi,a.dy.fi."), vec!["a.dy.fi"]);
+    assert_eq!(split_hostnames("a.dy.fi. a.dy.fi"), vec!["a.dy.fi"]);
+}
+
+#[test]
+fn test_split_hostnames_case() {
+    assert_eq!(
+        split_hostnames("b.dy.fi. A.DY.FI, a.dy.fi.,B.Dy.Fi"),
+        vec!["a.dy.fi", "b.dy.fi"]
+    );
+}
+
+#[test]
+fn test_split_hostnames_lone_dot() {
+    assert_eq!(split_hostnames(". a.dy.fi"), vec!["a.dy.fi"]);
+}
+
+#[test]
+fn test_format_duration_zero() {
+    assert_eq!(format_duration(0), "0s");
+}
+
+#[test]
+fn test_format_duration_sub_minute() {
+    assert_eq!(format_duration(45), "45s");
+}
+
+#[test]
+fn test_format_duration_exact_hour() {
+    assert_eq!(format_duration(3600), "1h");
+    assert_eq!(format_duration(5400), "1h 30m");
+}
+
+#[test]
+fn test_format_duration_multi_day() {
+    assert_eq!(format_duration(3600 * 24 * 5), "5d");
+    assert_eq!(
+        format_duration(3600 * 24 * 2 + 3600 * 3 + 61),
+        "2d 3h 1m 1s"
+    );
+}
+
+#[test]
+fn test_sanitize_snippet() {
+    assert_eq!(sanitize_snippet("  192.0.2.1\n", 100), "192.0.2.1");
+    assert_eq!(sanitize_snippet("a\nb\tc\x1bd", 100), "a b c d");
+    assert_eq!(sanitize_snippet("abcdef", 3), "abc...");
+}
+
+#[test]
+fn test_is_routable_mixed() {
+    let resolved: Vec<IpAddr> = [
+        "192.0.2.1",
+        "2001:db8::1",
+        "fe80::1",
+        "fd00::1",
+        "169.254.1.1",
+        "10.0.0.1",
+        "127.0.0.1",
+        "::1",
+    ]
+    .iter()
+    .map(|ip| ip.parse().unwrap())
+    .collect();
+    let routable: Vec<IpAddr> =
+        resolved.into_iter().filter(is_routable).collect();
+    assert_eq!(
+        routable,
+        vec![
+            "192.0.2.1".parse::<IpAddr>().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn test_write_atomic() {
+    let path = TempPath::new("atomic");
+    write_atomic(&path, b"first").unwrap();
+    write_atomic(&path, b"second").unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(contents, "second");
+}
+
+#[test]
+fn test_addresses_diverge() {
+    let a: [IpAddr; 2] =
+        ["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+    let b: [IpAddr; 2] = [a[1], a[0]];
+    let c: [IpAddr; 1] = ["192.0.2.2".parse().unwrap()];
+    assert!(!addresses_diverge(Vec::<&[IpAddr]>::new()));
+    assert!(!addresses_diverge([&a[..], &b[..]]));
+    // Hostnames with no addresses don't count
+    assert!(!addresses_diverge([&a[..], &[][..]]));
+    assert!(addresses_diverge([&a[..], &c[..]]));
+    assert!(addresses_diverge([&a[..], &a[..1]]));
+}
+
+#[test]
+fn test_is_temporary_resolve_error() {
+    use std::io::{Error, ErrorKind};
+    let temporary = Error::other(
+        "failed to lookup address information: \
+        Temporary failure in name resolution",
+    );
+    let missing = Error::other(
+        "failed to lookup address information: Name or service not known",
+    );
+    assert!(is_temporary_resolve_error(&temporary));
+    assert!(is_temporary_resolve_error(&Error::from(
+        ErrorKind::TimedOut
+    )));
+    assert!(!is_temporary_resolve_error(&missing));
+}
+
+#[test]
+fn test_map_concurrently() {
+    let hosts: Vec<Hostname> = (0..30).map(|i| format!("h{i}.dy.fi")).collect();
+    let running = AtomicUsize::new(0);
+    let most_running = AtomicUsize::new(0);
+    let seen = Mutex::new(Vec::new());
+    let results = map_concurrently(&hosts, 8, |host| {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        most_running.fetch_max(now, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(10));
+        seen.lock().unwrap().push(host.clone());
+        running.fetch_sub(1, Ordering::SeqCst);
+        format!("{host} resolved")
+    });
+    // Every host is handled once and the results keep the input order
+    let mut seen = seen.into_inner().unwrap();
+    seen.sort();
+    let mut expected = hosts.clone();
+    expected.sort();
+    assert_eq!(seen, expected);
+    let expected: Vec<_> = hosts
+        .iter()
+        .map(|host| format!("{host} resolved"))
+        .collect();
+    assert_eq!(results, expected);
+    let most_running = most_running.into_inner();
+    assert!(most_running <= 8, "{most_running} ran at once");
+    assert!(most_running > 1, "nothing ran concurrently");
+}
+
+#[test]
+fn test_parse_sha256_hex() {
+    let hash = parse_sha256_hex(&"0f".repeat(32)).unwrap();
+    assert_eq!(hash, [0x0f; 32]);
+    assert_eq!(parse_sha256_hex(&"AB".repeat(32)), Some([0xab; 32]));
+    assert_eq!(parse_sha256_hex(&"0f".repeat(31)), None);
+    assert_eq!(parse_sha256_hex(&"zz".repeat(32)), None);
+    assert_eq!(parse_sha256_hex(&"ä".repeat(32)), None);
+}
+
+#[test]
+fn test_normalize_url() {
+    for url in [
+        "https://www.dy.fi",
+        "https://www.dy.fi/",
+        "https://www.dy.fi/nic/update",
+        "https://www.dy.fi/nic/update/",
+        "https://www.dy.fi/nic/update//",
+    ] {
+        assert_eq!(
+            normalize_url(url, "/nic/update"),
+            "https://www.dy.fi/nic/update",
+            "{url}"
+        );
+    }
+    assert_eq!(
+        normalize_url("http://example.com:8080//", "/"),
+        "http://example.com:8080/"
+    );
+    assert_eq!(
+        normalize_url("http://example.com/ip/?v=4", "/"),
+        "http://example.com/ip?v=4"
+    );
+    assert_eq!(normalize_url("not a url", "/"), "not a url");
+}
+
+#[test]
+fn test_has_route() {
+    let loopback = "127.0.0.1:9".parse().unwrap();
+    assert!(has_route(loopback, None));
+    assert!(has_route(loopback, Some("127.0.0.1".parse().unwrap())));
+    // A bind address of the other family can't reach it
+    assert!(!has_route(loopback, Some("::1".parse().unwrap())));
+}
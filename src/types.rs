@@ -14,22 +14,551 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::net::IpAddr;
+use crate::util::{format_duration, parse_sha256_hex, sanitize_snippet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 pub type Hostname = String;
 
 pub enum LoopStatus {
     Nop,
     Action(Result<DyfiResponse, DyfiError>),
+    /// An update is needed, but it is being held back, e.g. by the minimum
+    /// update interval. It may be attempted again after `retry_after`.
+    Deferred {
+        retry_after: Duration,
+        reason: &'static str,
+    },
 }
 
+/// The result of a single check with `Dyfi::check_once`
+#[derive(Debug, PartialEq)]
+pub enum CheckOutcome {
+    /// dy.fi has pointed the hostnames to a new address
+    Updated,
+    /// Nothing needed updating, or dy.fi already had the current address
+    Unchanged,
+    /// An update is needed, but it is being held back, e.g. by the minimum
+    /// update interval
+    Deferred,
+    /// The pause file exists, so nothing was checked
+    Paused,
+    /// There is no address of the managed family to update the hostnames
+    /// to, e.g. no IPv6 on an IPv4-only network
+    NoAddress,
+    /// The check or the update failed
+    Failed(DyfiResponseCode),
+}
+
+impl CheckOutcome {
+    /// The process exit status for `--once`. The outcomes without an update
+    /// have statuses of their own so that scripts can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Updated => 0,
+            Self::Unchanged => 12,
+            Self::Deferred => 15,
+            Self::Paused => 16,
+            Self::NoAddress => 17,
+            Self::Failed(code) => *code as i32,
+        }
+    }
+}
+
+/// The outcomes that make the process exit with status 0 instead of their
+/// own status
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuccessCodes(Vec<i32>);
+
+impl Default for SuccessCodes {
+    fn default() -> Self {
+        Self(vec![DyfiResponseCode::Ok as i32])
+    }
+}
+
+impl SuccessCodes {
+    /// The process exit status for an outcome with the status `code`
+    pub fn exit_status(&self, code: i32) -> i32 {
+        if self.0.contains(&code) {
+            0
+        } else {
+            code
+        }
+    }
+}
+
+impl FromStr for SuccessCodes {
+    type Err = String;
+
+    /// Parses comma-separated names of dy.fi responses, `error`, `noip` or,
+    /// for `--once`, `unchanged`, `deferred`, `paused` and `noaddress`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let code = match name.to_lowercase().as_str() {
+                    "ok" | "good" => DyfiResponseCode::Ok,
+                    "badauth" => DyfiResponseCode::BadAuth,
+                    "nohost" => DyfiResponseCode::NoHost,
+                    "notfqdn" => DyfiResponseCode::NotFQDN,
+                    "badip" => DyfiResponseCode::BadIP,
+                    "dnserr" => DyfiResponseCode::DNSErr,
+                    "abuse" => DyfiResponseCode::Abuse,
+                    "error" => DyfiResponseCode::Error,
+                    "noip" => DyfiResponseCode::NoIp,
+                    "nonetwork" => DyfiResponseCode::NoNetwork,
+                    "unchanged" => {
+                        return Ok(CheckOutcome::Unchanged.exit_code())
+                    }
+                    "deferred" => return Ok(CheckOutcome::Deferred.exit_code()),
+                    "paused" => return Ok(CheckOutcome::Paused.exit_code()),
+                    "noaddress" => {
+                        return Ok(CheckOutcome::NoAddress.exit_code())
+                    }
+                    _ => return Err(format!("unknown outcome '{name}'")),
+                };
+                Ok(code as i32)
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+#[derive(Default)]
+struct LoopSignalState {
+    shutdown: bool,
+    wake: bool,
+    force_update: bool,
+}
+
+/// Lets other threads, such as a signal handler, interrupt the sleep between
+/// loop iterations, either to run the next iteration right away or to exit
+#[derive(Clone, Default)]
+pub struct LoopSignal(Arc<(Mutex<LoopSignalState>, Condvar)>);
+
+impl LoopSignal {
+    /// Asks the loop to exit gracefully at the next opportunity
+    pub fn shutdown(&self) {
+        self.notify(|state| state.shutdown = true);
+    }
+
+    /// Cuts the current sleep short so that the next iteration starts now
+    pub fn wake(&self) {
+        self.notify(|state| state.wake = true);
+    }
+
+    /// Cuts the current sleep short and makes the next iteration update all
+    /// hostnames, whatever their records and the minimum update interval
+    pub fn force_update(&self) {
+        self.notify(|state| {
+            state.force_update = true;
+            state.wake = true;
+        });
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.0 .0.lock().unwrap().shutdown
+    }
+
+    /// Whether `force_update()` has been called since the last time this
+    /// returned true
+    pub fn take_force_update(&self) -> bool {
+        std::mem::take(&mut self.0 .0.lock().unwrap().force_update)
+    }
+
+    fn notify(&self, f: impl FnOnce(&mut LoopSignalState)) {
+        let (state, condvar) = &*self.0;
+        f(&mut state.lock().unwrap());
+        condvar.notify_all();
+    }
+
+    /// Blocks for `timeout`, or until `wake()` or `shutdown()` is called.
+    /// Spurious wakeups keep waiting for the remaining time.
+    pub fn wait(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let (state, condvar) = &*self.0;
+        let mut state = state.lock().unwrap();
+        while !state.shutdown && !state.wake {
+            let Some(remaining) =
+                deadline.checked_duration_since(Instant::now())
+            else {
+                break;
+            };
+            state = condvar.wait_timeout(state, remaining).unwrap().0;
+        }
+        state.wake = false;
+    }
+}
+
+/// A handle for stopping a running client, e.g. from elsewhere in a larger
+/// application. It can be cloned and sent to other threads freely.
+#[derive(Clone)]
+pub struct ShutdownHandle(LoopSignal);
+
+impl ShutdownHandle {
+    /// Makes the client exit gracefully at the next opportunity, releasing
+    /// the hostnames if configured to
+    pub fn shutdown(&self) {
+        self.0.shutdown();
+    }
+}
+
+impl From<LoopSignal> for ShutdownHandle {
+    fn from(signal: LoopSignal) -> Self {
+        Self(signal)
+    }
+}
+
+/// Keeps an error that keeps repeating, e.g. during an outage, from flooding
+/// the log: it is logged in full once, and the repeats are summarized when
+/// the error changes or clears.
+#[derive(Default)]
+pub struct RepeatedError {
+    /// The last error, how many times it has been seen and since when
+    last: Option<(String, u32, Instant)>,
+}
+
+impl RepeatedError {
+    /// Records an occurrence of `message` and tells whether it should be
+    /// logged, i.e. whether it differs from the previous error
+    pub fn record(&mut self, message: &str) -> bool {
+        if let Some((last, count, _)) = &mut self.last {
+            if last == message {
+                *count += 1;
+                debug!("Same error again ({count} times)");
+                return false;
+            }
+        }
+        self.clear();
+        self.last = Some((message.to_string(), 1, Instant::now()));
+        true
+    }
+
+    /// Forgets the last error, logging a summary if it was repeated
+    pub fn clear(&mut self) {
+        if let Some((last, count, since)) = self.last.take() {
+            if count > 1 {
+                info!(
+                    "Same error {count} times in the last {}: {last}",
+                    format_duration(since.elapsed().as_secs())
+                );
+            }
+        }
+    }
+}
+
+/// How much of an unparseable IP API response to include in error messages
+const IP_ERROR_SNIPPET_LEN: usize = 100;
+
+/// The current public address(es) of this host, at most one per IP family
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CurrentIps {
+    #[cfg_attr(feature = "serde", serde(rename = "ipv4"))]
+    pub v4: Option<Ipv4Addr>,
+    #[cfg_attr(feature = "serde", serde(rename = "ipv6"))]
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl CurrentIps {
+    pub fn iter(&self) -> impl Iterator<Item = IpAddr> {
+        self.v4
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(self.v6.map(IpAddr::V6))
+    }
+
+    /// Returns the current address of the same family as `ip`, if known
+    pub fn of_family(&self, ip: &IpAddr) -> Option<IpAddr> {
+        match ip {
+            IpAddr::V4(_) => self.v4.map(IpAddr::V4),
+            IpAddr::V6(_) => self.v6.map(IpAddr::V6),
+        }
+    }
+
+    /// Returns the current addresses that differ from an address of the same
+    /// family in `ips`, i.e. the families whose records are out of date
+    pub fn changed_from(&self, ips: &[IpAddr]) -> Self {
+        let outdated = |current: IpAddr| {
+            ips.iter()
+                .any(|ip| ip.is_ipv4() == current.is_ipv4() && *ip != current)
+        };
+        Self {
+            v4: self.v4.filter(|&ip| outdated(IpAddr::V4(ip))),
+            v6: self.v6.filter(|&ip| outdated(IpAddr::V6(ip))),
+        }
+    }
+
+    /// Like `changed_from`, for records of a hostname that may hold up to
+    /// `expected` addresses of a family. With more than one allowed, a family
+    /// is only out of date if none of its records is the current address.
+    pub fn changed_from_records(
+        &self,
+        ips: &[IpAddr],
+        expected: usize,
+    ) -> Self {
+        if expected <= 1 {
+            return self.changed_from(ips);
+        }
+        let outdated = |current: IpAddr| {
+            let mut same_family = ips
+                .iter()
+                .filter(|ip| ip.is_ipv4() == current.is_ipv4())
+                .peekable();
+            same_family.peek().is_some()
+                && !same_family.any(|ip| *ip == current)
+        };
+        Self {
+            v4: self.v4.filter(|&ip| outdated(IpAddr::V4(ip))),
+            v6: self.v6.filter(|&ip| outdated(IpAddr::V6(ip))),
+        }
+    }
+
+    /// Combines the families of `self` and `other`, preferring `self`
+    #[must_use]
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            v4: self.v4.or(other.v4),
+            v6: self.v6.or(other.v6),
+        }
+    }
+}
+
+impl From<IpAddr> for CurrentIps {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(v4) => Self {
+                v4: Some(v4),
+                v6: None,
+            },
+            IpAddr::V6(v6) => Self {
+                v4: None,
+                v6: Some(v6),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for CurrentIps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ips: Vec<String> = self.iter().map(|ip| ip.to_string()).collect();
+        write!(f, "{}", ips.join(", "))
+    }
+}
+
+impl FromStr for CurrentIps {
+    type Err = DyfiError;
+
+    /// Parses a response from a public IP API: one address, or an IPv4 and
+    /// an IPv6 address on separate lines
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut out = Self::default();
+        for line in s.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match parse_ip_maybe_with_port(line) {
+                Ok(IpAddr::V4(ip)) if out.v4.is_none() => out.v4 = Some(ip),
+                Ok(IpAddr::V6(ip)) if out.v6.is_none() => out.v6 = Some(ip),
+                Ok(ip) => debug!("Ignoring extra address {ip} from IP API"),
+                Err(e) => {
+                    return Err(DyfiError::Parse(format!(
+                        "Error parsing current IP: {e}: expected an IP \
+                        address but got '{}'",
+                        sanitize_snippet(s, IP_ERROR_SNIPPET_LEN)
+                    )))
+                }
+            }
+        }
+        if out.v4.is_none() && out.v6.is_none() {
+            return Err(DyfiError::Parse(
+                "Error parsing current IP: no address in response".to_string(),
+            ));
+        }
+        Ok(out)
+    }
+}
+
+/// Parses an IP address, tolerating a port after it, as in `192.0.2.1:443`
+/// or `[2001:db8::1]:443`, and quotes around it, as in `"192.0.2.1"`
+fn parse_ip_maybe_with_port(s: &str) -> Result<IpAddr, AddrParseError> {
+    parse_ip_or_socket_addr(s).or_else(|e| {
+        let unquoted = s.trim_matches(|c: char| {
+            c == '"' || c == '\'' || c.is_ascii_whitespace()
+        });
+        if unquoted == s {
+            return Err(e);
+        }
+        debug!("Stripped quotes from IP address '{s}'");
+        parse_ip_or_socket_addr(unquoted).map_err(|_| e)
+    })
+}
+
+fn parse_ip_or_socket_addr(s: &str) -> Result<IpAddr, AddrParseError> {
+    s.parse().or_else(|e| match s.parse::<SocketAddr>() {
+        Ok(addr) => {
+            debug!("Stripped port from IP address '{s}'");
+            Ok(addr.ip())
+        }
+        Err(_) => Err(e),
+    })
+}
+
+/// A password that is never revealed by `Debug` or `Display`, so that it
+/// can't end up in logs by accident
+#[derive(Clone, PartialEq)]
+pub struct Password(String);
+
+impl Password {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Password {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+// The flags are independent settings, not states
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Debug)]
 pub struct Config {
     pub dyfi_api: String,
-    pub public_ip_api: String,
+    /// Services responding with our public IP address, in order of
+    /// preference
+    pub public_ip_apis: Vec<String>,
+    /// Services responding with our public IPv6 address, asked in addition
+    /// to `public_ip_apis` when IPv6 records are managed
+    pub public_ip_apis_v6: Vec<String>,
+    /// Number of public IP APIs that must agree on the address. With 1 the
+    /// first one to answer is used.
+    pub ip_quorum: usize,
     pub user: String,
-    pub password: String,
+    pub password: Password,
     pub hostnames: Vec<Hostname>,
+    /// Minimum number of seconds between two updates sent to dy.fi
+    pub min_update_interval: u64,
+    /// Path to a Unix domain socket of an HTTP proxy that all requests should
+    /// be sent through. The HTTP library can't connect to one yet, so this is
+    /// rejected.
+    pub unix_proxy: Option<PathBuf>,
+    /// Local address to send all requests from
+    pub bind_address: Option<IpAddr>,
+    /// Update each hostname separately and drop hostnames rejected by dy.fi
+    /// instead of exiting
+    pub drop_rejected_hostnames: bool,
+    /// Seconds to wait for the system resolver to look up a hostname before
+    /// treating it as unresolved, 0 to wait as long as it takes
+    pub resolve_timeout: u64,
+    /// Nameserver holding dy.fi's authoritative records
+    pub authoritative_ns: String,
+    /// Release the hostnames with an `offline` request on graceful shutdown
+    pub offline_on_exit: bool,
+    /// Marker file recording an `abuse` reply from dy.fi
+    pub abuse_lockout_file: Option<PathBuf>,
+    /// Seconds to refuse to run after an `abuse` reply
+    pub abuse_lockout: u64,
+    /// Number of consecutive checks a new IP address must be seen on before
+    /// it is sent to dy.fi
+    pub ip_stable_checks: u32,
+    /// File to keep up to date with the current IP address
+    pub ip_output_file: Option<PathBuf>,
+    /// Unix socket to stream events to as JSON lines
+    pub event_socket: Option<PathBuf>,
+    /// Seconds between "alive" log lines while nothing changes. 0 disables.
+    pub heartbeat_interval: u64,
+    /// Seconds to wait after the first of consecutive errors. The wait is
+    /// doubled on each error, with random jitter.
+    pub retry_base: u64,
+    /// Maximum number of seconds to wait after an error
+    pub retry_cap: u64,
+    /// File for keeping state between runs
+    pub state_file: Option<PathBuf>,
+    /// No checks are made while this file exists
+    pub pause_file: Option<PathBuf>,
+    /// Reject responses that aren't `text/plain`
+    pub strict_content_type: bool,
+    /// Shell command to run before each update. The update is skipped if it
+    /// fails.
+    pub pre_update_cmd: Option<String>,
+    /// Seconds to let the pre-update command run before killing it
+    pub pre_update_timeout: u64,
+    /// How much of unrecognized dy.fi responses to log
+    pub other_response_logging: OtherResponseLogging,
+    /// Extra keywords of a compatible service and the dy.fi responses they
+    /// stand for
+    pub response_map: ResponseMap,
+    /// How the hostnames are given in update requests
+    pub hostname_style: HostnameStyle,
+    /// SHA-256 hash of the public key the public IP APIs' certificates must
+    /// have, as hex
+    pub ip_api_pin: Option<String>,
+    /// URL to send a GET request to after each successful check, and with
+    /// `/fail` appended when the client exits because of an error
+    pub heartbeat_url: Option<String>,
+    /// Don't ask the public IP APIs for the current address, but let dy.fi
+    /// use the address the request comes from. Updates are then only sent
+    /// when a hostname doesn't resolve and on the forced update interval.
+    pub skip_ip_check: bool,
+    /// The address families to send to dy.fi
+    pub ip_family: IpFamily,
+    /// Detected addresses outside of these aren't sent to dy.fi
+    pub allowed_ip_ranges: IpRanges,
+    /// Extra HTTP headers for all requests, as semicolon-separated
+    /// `Key: Value` pairs
+    pub extra_headers: Option<String>,
+    /// Whether to exit or keep retrying after an error response from dy.fi
+    pub on_error: ErrorPolicy,
+    /// Explain differences between the detected, registered and resolved
+    /// addresses after each update
+    pub ip_diagnostics: bool,
+    /// Send a temporary IPv6 address as is, instead of the stable address of
+    /// the same interface
+    pub track_temporary_ipv6: bool,
+    /// Seconds a newly detected address must persist before it is sent to
+    /// dy.fi, 0 to send it right away
+    pub debounce: u64,
+    /// Seconds to wait before asking for a newly detected address again,
+    /// and only updating if it's still the same, 0 to update right away
+    pub confirm_delay: u64,
+    /// Measure the time since the last update with the wall clock, which
+    /// keeps running while the machine is suspended, instead of the
+    /// monotonic clock
+    pub wall_clock_force_interval: bool,
+    /// Seconds between checks of the current address. Updates are still
+    /// limited by the minimum update interval.
+    pub check_interval: u64,
+    /// Maximum number of update requests in any 24 hours, 0 for no limit
+    pub max_daily_requests: usize,
+    /// Number of addresses of each family a hostname may have. With more
+    /// than one, a hostname is current as long as one of them is the current
+    /// address.
+    pub expected_records: usize,
+    /// Outcomes that make the process exit with status 0
+    pub success_codes: SuccessCodes,
+    /// Number of consecutive checks a hostname may keep resolving to an old
+    /// address after dy.fi accepted the new one, before it is put down to DNS
+    /// caching and not updated again. 0 to keep updating.
+    pub stale_dns_checks: u32,
+    /// Number of consecutive checks the current address may fail to be
+    /// detected on before the client exits, or with `ErrorPolicy::Continue`,
+    /// falls back to letting dy.fi detect it. 0 to keep trying.
+    pub max_ip_failures: u32,
+    /// Milliseconds a successful request to dy.fi or a public IP API may
+    /// take before a warning is logged, 0 to never warn
+    pub slow_request_warn_ms: u64,
 }
 
 #[derive(Debug)]
@@ -50,29 +579,535 @@ pub enum DyfiResponse {
     Other(String),
 }
 
+pub const PIN_NEEDS_RUSTLS: &str =
+    "Pinning the public IP API's key requires building with rustls-tls";
+
+pub const EVENT_SOCKET_NEEDS_SERDE: &str =
+    "DYFI_EVENT_SOCKET requires building with serde";
+
+pub const UNIX_PROXY_UNSUPPORTED: &str =
+    "DYFI_UNIX_PROXY is not supported yet, as the HTTP library can't connect \
+    to a Unix socket";
+
+impl Config {
+    /// Checks the configuration for problems, reporting all of them at once
+    pub fn validate(&self) -> Result<(), Vec<DyfiError>> {
+        let mut errors = Vec::new();
+        let mut problem = |msg: String| errors.push(DyfiError::Config(msg));
+        if self.hostnames.is_empty() {
+            problem("No hostnames configured".to_string());
+        }
+        let dyfi = self.targets_dyfi();
+        for host in &self.hostnames {
+            if dyfi && !is_dyfi_hostname(host) {
+                problem(format!("'{host}' is not a dy.fi hostname"));
+            } else if !is_hostname(host) {
+                problem(format!("'{host}' is not a valid hostname"));
+            }
+        }
+        if self.user.is_empty() {
+            problem("No dy.fi username configured".to_string());
+        }
+        if self.password.expose().is_empty() {
+            problem("No dy.fi password configured".to_string());
+        }
+        if let Err(e) = check_url(&self.dyfi_api) {
+            problem(format!("Invalid dy.fi API URL: {e}"));
+        }
+        if let Some(Err(e)) = self.heartbeat_url.as_deref().map(check_url) {
+            problem(format!("Invalid heartbeat URL: {e}"));
+        }
+        if self.public_ip_apis.is_empty() {
+            problem("No public IP APIs configured".to_string());
+        }
+        for api in self.public_ip_apis.iter().chain(&self.public_ip_apis_v6) {
+            if let Err(e) = check_url(api) {
+                problem(format!("Invalid public IP API URL: {e}"));
+            }
+        }
+        if let Some(pin) = &self.ip_api_pin {
+            if parse_sha256_hex(pin).is_none() {
+                problem(format!(
+                    "Invalid public key pin '{pin}', expected a SHA-256 hash \
+                    as 64 hexadecimal digits"
+                ));
+            }
+            if let Some(api) = self
+                .public_ip_apis
+                .iter()
+                .chain(&self.public_ip_apis_v6)
+                .find(|api| !api.starts_with("https://"))
+            {
+                problem(format!(
+                    "A public key pin needs HTTPS, but {api} doesn't use it"
+                ));
+            }
+            if cfg!(not(feature = "rustls-tls")) {
+                problem(PIN_NEEDS_RUSTLS.to_string());
+            }
+        }
+        if self.unix_proxy.is_some() {
+            problem(UNIX_PROXY_UNSUPPORTED.to_string());
+        }
+        if let Some(msg) = self.ip_quorum_problem() {
+            problem(msg);
+        }
+        if self.event_socket.is_some() && cfg!(not(unix)) {
+            problem(
+                "Event sockets are not supported on this platform".to_string(),
+            );
+        }
+        if self.event_socket.is_some() && cfg!(not(feature = "serde")) {
+            problem(EVENT_SOCKET_NEEDS_SERDE.to_string());
+        }
+        if self.expected_records == 0 {
+            problem("DYFI_EXPECTED_RECORDS must be at least 1".to_string());
+        }
+        if self.check_interval == 0 {
+            problem(
+                "The check interval must be at least one second".to_string(),
+            );
+        }
+        if self.drop_rejected_hostnames
+            && self.max_daily_requests != 0
+            && self.hostnames.len() > self.max_daily_requests
+        {
+            problem(format!(
+                "Daily request limit of {} is too low for updating {} \
+                hostnames one at a time",
+                self.max_daily_requests,
+                self.hostnames.len()
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// A quorum must be more than half of the public IP APIs, or two
+    /// different addresses could both reach it. A quorum of one takes the
+    /// first answer instead of counting them.
+    fn ip_quorum_problem(&self) -> Option<String> {
+        let apis = self.public_ip_apis.len();
+        if self.ip_quorum > apis {
+            Some(format!(
+                "IP quorum of {} is larger than the number of public IP APIs",
+                self.ip_quorum
+            ))
+        } else if self.ip_quorum > 1 && self.ip_quorum * 2 <= apis {
+            Some(format!(
+                "IP quorum of {} is not a majority of the {apis} public IP APIs",
+                self.ip_quorum
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Tells if the updates go to dy.fi itself rather than to a compatible
+    /// service, which is assumed when there is a response map or the API URL
+    /// points elsewhere. An invalid URL counts as dy.fi.
+    fn targets_dyfi(&self) -> bool {
+        self.response_map.is_empty()
+            && reqwest::Url::parse(&self.dyfi_api).map_or(true, |url| {
+                url.host_str().is_some_and(|host| {
+                    let host = host.to_lowercase();
+                    host == "dy.fi" || host.ends_with(".dy.fi")
+                })
+            })
+    }
+}
+
+/// A DNS name made of one or more non-empty labels
+fn is_hostname(host: &str) -> bool {
+    host.split('.').all(|label| {
+        !label.is_empty()
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// dy.fi only hands out names directly under `dy.fi`
+fn is_dyfi_hostname(host: &str) -> bool {
+    let host = host.to_lowercase();
+    host.strip_suffix(".dy.fi")
+        .is_some_and(|label| !label.is_empty() && !label.contains('.'))
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+        Ok(_) => Err(format!("'{url}' is not an HTTP(S) URL")),
+        Err(e) => Err(format!("'{url}': {e}")),
+    }
+}
+
+/// A network such as `192.0.2.0/24`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    /// A bare address is a network of that address only
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid IP range '{s}'");
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+/// The networks a detected address must belong to. An address is only
+/// checked against the ranges of its own family, so e.g. listing only IPv4
+/// ranges leaves IPv6 addresses unrestricted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpRanges(pub Vec<IpRange>);
+
+impl IpRanges {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let mut same_family = self
+            .0
+            .iter()
+            .filter(|range| range.network.is_ipv4() == ip.is_ipv4())
+            .peekable();
+        same_family.peek().is_none() || same_family.any(|r| r.contains(ip))
+    }
+}
+
+impl FromStr for IpRanges {
+    type Err = String;
+
+    /// Parses comma-separated ranges
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// What to do when dy.fi replies with an error such as `badauth`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ErrorPolicy {
+    /// Stop the client with the corresponding exit code
+    #[default]
+    Exit,
+    /// Log the error and try again after a backoff
+    Continue,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exit" => Ok(Self::Exit),
+            "continue" => Ok(Self::Continue),
+            _ => Err(format!("unknown error policy '{s}'")),
+        }
+    }
+}
+
+/// How several hostnames are put in an update request
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HostnameStyle {
+    /// One comma-separated `hostname` parameter, as dy.fi wants
+    #[default]
+    Joined,
+    /// A `hostname` parameter of its own for each hostname, for compatible
+    /// services that want that instead
+    Repeated,
+}
+
+impl FromStr for HostnameStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "joined" => Ok(Self::Joined),
+            "repeated" => Ok(Self::Repeated),
+            _ => Err(format!("unknown hostname style '{s}'")),
+        }
+    }
+}
+
+/// Which address families are detected and sent to dy.fi
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum IpFamily {
+    #[default]
+    Both,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// Leaves out the addresses of other families
+    pub fn filter(self, ips: CurrentIps) -> CurrentIps {
+        match self {
+            Self::Both => ips,
+            Self::V4 => CurrentIps { v6: None, ..ips },
+            Self::V6 => CurrentIps { v4: None, ..ips },
+        }
+    }
+
+    /// The family to manage on a host that has a route to the Internet
+    /// over IPv4 and/or IPv6. With both, only the family that can be reached
+    /// is managed, since an address of the other one could only have come
+    /// through a translator such as NAT64 and would never match the records.
+    /// A family that has been chosen explicitly is kept.
+    pub fn for_connectivity(self, v4: bool, v6: bool) -> Self {
+        match (self, v4, v6) {
+            (Self::Both, true, false) => Self::V4,
+            (Self::Both, false, true) => Self::V6,
+            (family, _, _) => family,
+        }
+    }
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Both => "IP",
+            Self::V4 => "IPv4",
+            Self::V6 => "IPv6",
+        })
+    }
+}
+
+impl FromStr for IpFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "both" => Ok(Self::Both),
+            "v4" | "ipv4" => Ok(Self::V4),
+            "v6" | "ipv6" => Ok(Self::V6),
+            _ => Err(format!("unknown IP family '{s}'")),
+        }
+    }
+}
+
+/// How much of a response that isn't recognized is logged
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OtherResponseLogging {
+    /// The whole response, as is
+    Full,
+    /// The beginning of the response
+    #[default]
+    Truncated,
+    /// Only the length and a hash of the response, which is enough to tell
+    /// whether two responses were the same
+    Hidden,
+}
+
+/// How much of an unrecognized response is logged when truncating
+const OTHER_RESPONSE_SNIPPET_LEN: usize = 200;
+
+impl OtherResponseLogging {
+    pub fn describe(self, response: &str) -> String {
+        match self {
+            Self::Full => format!("'{response}'"),
+            Self::Truncated => format!(
+                "'{}'",
+                sanitize_snippet(response, OTHER_RESPONSE_SNIPPET_LEN)
+            ),
+            Self::Hidden => {
+                let mut hasher = DefaultHasher::new();
+                response.hash(&mut hasher);
+                format!(
+                    "{} bytes, hash {:016x}",
+                    response.len(),
+                    hasher.finish()
+                )
+            }
+        }
+    }
+}
+
+impl FromStr for OtherResponseLogging {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(Self::Full),
+            "truncate" | "truncated" => Ok(Self::Truncated),
+            "hide" | "hidden" => Ok(Self::Hidden),
+            _ => Err(format!("unknown response logging mode '{s}'")),
+        }
+    }
+}
+
+/// The keywords dy.fi answers with
+const RESPONSE_KEYWORDS: [&str; 8] = [
+    "badauth", "nohost", "notfqdn", "badip", "nochg", "good", "dnserr", "abuse",
+];
+
+/// Keywords of a dy.fi compatible service that dy.fi doesn't use, each
+/// mapped to the dy.fi keyword that means the same, e.g. `911=dnserr`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResponseMap(Vec<(String, &'static str)>);
+
+impl ResponseMap {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The dy.fi keyword `keyword` stands for, or `keyword` itself
+    fn translate<'a>(&self, keyword: &'a str) -> &'a str {
+        self.0
+            .iter()
+            .find(|(token, _)| token == keyword)
+            .map_or(keyword, |(_, known)| known)
+    }
+}
+
+impl FromStr for ResponseMap {
+    type Err = String;
+
+    /// Parses comma-separated `token=keyword` pairs. The keywords dy.fi
+    /// itself uses can't be remapped.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (token, keyword) = pair
+                    .split_once('=')
+                    .map(|(t, k)| (t.trim(), k.trim().to_lowercase()))
+                    .filter(|(t, _)| !t.is_empty())
+                    .ok_or_else(|| format!("invalid mapping '{pair}'"))?;
+                if RESPONSE_KEYWORDS.contains(&token) {
+                    return Err(format!("'{token}' is a dy.fi response"));
+                }
+                let known = RESPONSE_KEYWORDS
+                    .iter()
+                    .find(|known| **known == keyword)
+                    .ok_or_else(|| format!("unknown response '{keyword}'"))?;
+                Ok((token.to_string(), *known))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl FromStr for DyfiResponse {
+    type Err = DyfiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(s, &ResponseMap::default())
+    }
+}
+
 impl DyfiResponse {
-    pub fn from(s: String) -> Self {
-        let result: Vec<&str> = s.split_whitespace().collect();
-        match result[..] {
-            ["badauth"] => Self::BadAuth,
-            ["nohost"] => Self::NoHost,
-            ["notfqdn"] => Self::NotFQDN,
-            ["badip", ip] => Self::BadIP(ip.parse().unwrap()),
-            ["nochg"] => Self::NoChg,
-            ["good", ip] => Self::Good(Some(ip.parse().unwrap())),
-            ["good"] => {
-                // The Good response with no IP address is sent to an `offline`
-                // command which releases the IP address from the service.
-                // This program does not support this command.
-                unimplemented!()
-            }
-            ["dnserr"] => Self::DNSErr,
-            ["abuse"] => Self::Abuse,
-            _ => Self::Other(s),
-        }
-    }
-
-    pub fn log(&self) {
+    /// Classifies a response by its first keyword, so that responses with
+    /// extra tokens added in the future are still recognized. A keyword in
+    /// `map` counts as the dy.fi keyword it is mapped to. Unknown keywords
+    /// are kept as `Other`, but an empty response or a known keyword with
+    /// an invalid address is an error.
+    pub fn parse_with(s: &str, map: &ResponseMap) -> Result<Self, DyfiError> {
+        let mut tokens = s.split_whitespace();
+        let Some(keyword) = tokens.next().map(|k| map.translate(k)) else {
+            return Err(DyfiError::Parse(
+                "Empty response from dy.fi".to_string(),
+            ));
+        };
+        let rest: Vec<&str> = tokens.collect();
+        // Only these are followed by an address
+        let (ip, extra) = match keyword {
+            "good" | "badip" => (
+                rest.first().map(|ip| ip.parse::<IpAddr>()),
+                rest.get(1..).unwrap_or_default(),
+            ),
+            _ => (None, &rest[..]),
+        };
+        // Unknown keywords keep the whole response
+        let known = matches!(
+            keyword,
+            "badauth"
+                | "nohost"
+                | "notfqdn"
+                | "badip"
+                | "nochg"
+                | "good"
+                | "dnserr"
+                | "abuse"
+        );
+        if known && !extra.is_empty() {
+            debug!(
+                "Ignoring extra tokens after '{keyword}' in dy.fi response: \
+                {extra:?}"
+            );
+        }
+        Ok(match (keyword, ip) {
+            ("badauth", _) => Self::BadAuth,
+            ("nohost", _) => Self::NoHost,
+            ("notfqdn", _) => Self::NotFQDN,
+            ("badip", Some(Ok(ip))) => Self::BadIP(ip),
+            ("nochg", _) => Self::NoChg,
+            ("good", Some(Ok(ip))) => Self::Good(Some(ip)),
+            // The Good response with no IP address is sent to an `offline`
+            // command which releases the IP address from the service.
+            ("good", None) => Self::Good(None),
+            ("good" | "badip", _) => {
+                return Err(DyfiError::Parse(format!(
+                    "Invalid address in dy.fi response '{}'",
+                    sanitize_snippet(s, OTHER_RESPONSE_SNIPPET_LEN)
+                )));
+            }
+            ("dnserr", _) => Self::DNSErr,
+            ("abuse", _) => Self::Abuse,
+            _ => Self::Other(s.to_string()),
+        })
+    }
+}
+
+impl DyfiResponse {
+    pub fn log(&self, other_logging: OtherResponseLogging) {
         match self {
             Self::BadAuth => error!("dy.fi replied: Authentication failed"),
             Self::NoHost => error!(concat!(
@@ -94,7 +1129,7 @@ impl DyfiResponse {
                 info!("dy.fi replied: Hostname(s) pointed at new address {ip}");
             }
             Self::Good(None) => {
-                unimplemented!()
+                info!("dy.fi replied: Hostname(s) released");
             }
             Self::DNSErr => {
                 error!(
@@ -102,12 +1137,33 @@ impl DyfiResponse {
                 );
             }
             Self::Abuse => error!("dy.fi replied: Request denied due to abuse"),
-            Self::Other(s) => error!("dy.fi replied with other message: '{s}'"),
+            Self::Other(s) => error!(
+                "dy.fi replied with other message: {}",
+                other_logging.describe(s)
+            ),
+        }
+    }
+
+    /// The exit code corresponding to this response, without consuming it
+    pub fn code(&self) -> DyfiResponseCode {
+        match self {
+            Self::BadAuth => DyfiResponseCode::BadAuth,
+            Self::NoHost => DyfiResponseCode::NoHost,
+            Self::NotFQDN => DyfiResponseCode::NotFQDN,
+            Self::BadIP(_) => DyfiResponseCode::BadIP,
+            Self::DNSErr => DyfiResponseCode::DNSErr,
+            Self::Abuse => DyfiResponseCode::Abuse,
+            Self::NoChg | Self::Good(_) | Self::Other(_) => {
+                DyfiResponseCode::Ok
+            }
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Serialized as the names that `DYFI_SUCCESS_CODES` also uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 #[rustfmt::skip]
 #[repr(i32)]
 pub enum DyfiResponseCode {
@@ -119,48 +1175,137 @@ pub enum DyfiResponseCode {
     Ok            = 0,
     DNSErr        = 5,
     Abuse         = 6,
-    // This one is not
+    // These are not
+    Error         = 11,
+    NoIp          = 13,
+    NoNetwork     = 14,
     #[cfg(test)]
     OtherNonFatal = 99,
 }
 
+impl DyfiResponseCode {
+    /// Whether the client stopped because of an error
+    pub fn is_failure(self) -> bool {
+        match self {
+            Self::Ok => false,
+            #[cfg(test)]
+            Self::OtherNonFatal => false,
+            _ => true,
+        }
+    }
+}
+
 impl From<DyfiResponse> for DyfiResponseCode {
     fn from(d: DyfiResponse) -> Self {
-        match d {
-            DyfiResponse::BadAuth => Self::BadAuth,
-            DyfiResponse::NoHost => Self::NoHost,
-            DyfiResponse::NotFQDN => Self::NotFQDN,
-            DyfiResponse::BadIP(_) => Self::BadIP,
-            DyfiResponse::DNSErr => Self::DNSErr,
-            DyfiResponse::Abuse => Self::Abuse,
-            _ => Self::Ok,
-        }
+        d.code()
     }
 }
 
 #[derive(Debug)]
-pub struct DyfiError(pub String);
+pub enum DyfiError {
+    /// The client is misconfigured
+    Config(String),
+    /// A request failed in a way that is likely to go away by itself, such
+    /// as a timeout, a connection error or a server error
+    Transient(String),
+    /// A request couldn't be sent because there is no network connection,
+    /// e.g. early at boot. This is also transient.
+    Network(String),
+    /// No address of the families being updated was detected, e.g. on an
+    /// IPv4-only network when only IPv6 is wanted. Nothing has failed, but
+    /// there is nothing to update on this check.
+    NoAddress(String),
+    /// A request failed for good, e.g. with a client error status
+    Http(String),
+    /// A response couldn't be understood
+    Parse(String),
+}
+
+impl DyfiError {
+    /// Whether retrying later is worthwhile. Anything else won't fix itself
+    /// and should stop the client.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Transient(_) | Self::Network(_) | Self::NoAddress(_)
+        )
+    }
+
+    /// The code to exit with when this error ends a check
+    pub fn code(&self) -> DyfiResponseCode {
+        match self {
+            Self::Network(_) => DyfiResponseCode::NoNetwork,
+            _ => DyfiResponseCode::Error,
+        }
+    }
+}
+
+/// The kind of the I/O error behind `e`, if it says that the network can't be
+/// reached at all
+fn network_unreachable(e: &reqwest::Error) -> Option<ErrorKind> {
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io.kind(),
+                ErrorKind::NetworkUnreachable
+                    | ErrorKind::NetworkDown
+                    | ErrorKind::HostUnreachable
+                    | ErrorKind::AddrNotAvailable
+                    | ErrorKind::ConnectionRefused
+            ) {
+                return Some(io.kind());
+            }
+        }
+        source = err.source();
+    }
+    None
+}
 
 impl From<dotenvy::Error> for DyfiError {
     fn from(e: dotenvy::Error) -> Self {
-        DyfiError(e.to_string())
+        DyfiError::Config(e.to_string())
     }
 }
 
 impl From<reqwest::Error> for DyfiError {
     fn from(e: reqwest::Error) -> Self {
-        DyfiError(e.to_string())
+        if let Some(kind) = network_unreachable(&e).filter(|_| e.is_connect()) {
+            DyfiError::Network(format!("Network unavailable ({kind}): {e}"))
+        } else if e.is_timeout()
+            || e.is_connect()
+            || e.is_request()
+            || e.is_body()
+            || e.status().is_some_and(is_transient_status)
+        {
+            DyfiError::Transient(e.to_string())
+        } else {
+            DyfiError::Http(e.to_string())
+        }
     }
 }
 
 impl From<std::net::AddrParseError> for DyfiError {
     fn from(e: std::net::AddrParseError) -> Self {
-        DyfiError(format!("Error parsing current IP address: {e}"))
+        DyfiError::Parse(format!("Error parsing current IP address: {e}"))
     }
 }
 
 impl std::fmt::Display for DyfiError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        match self {
+            Self::Config(s)
+            | Self::Transient(s)
+            | Self::Network(s)
+            | Self::NoAddress(s)
+            | Self::Http(s)
+            | Self::Parse(s) => write!(f, "{s}"),
+        }
     }
 }
+
+/// Server errors and rate limiting are worth retrying, other error statuses
+/// are not
+pub fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
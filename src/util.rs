@@ -14,7 +14,10 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::Path;
 
 // Joins a HashSet into a String without first collecting the set into a Vec
 // or using the itertools crate
@@ -32,12 +35,202 @@ fn _join_set(set: &HashSet<String>, separator: char) -> String {
     out
 }
 
+/// Splits a list separated by commas and/or whitespace into a sorted Vec
+/// without duplicates
 pub fn split_to_sorted_vec(s: &str) -> Vec<String> {
-    if s.is_empty() {
-        return vec![];
-    }
-    let mut out: Vec<_> =
-        s.split(',').map(std::string::ToString::to_string).collect();
+    let mut out: Vec<_> = s
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+        .map(std::string::ToString::to_string)
+        .collect();
     out.sort();
+    out.dedup();
+    out
+}
+
+/// Brings a hostname to its canonical form: lowercase, without the trailing
+/// dot of an absolute name
+pub fn normalize_hostname(host: &str) -> String {
+    host.strip_suffix('.').unwrap_or(host).to_lowercase()
+}
+
+/// Like `split_to_sorted_vec()`, but normalizes each hostname first so that
+/// e.g. `a.dy.fi` and `A.dy.fi.` count as the same name
+pub fn split_hostnames(s: &str) -> Vec<String> {
+    let mut out: Vec<_> = split_to_sorted_vec(s)
+        .iter()
+        .map(|host| normalize_hostname(host))
+        .filter(|host| !host.is_empty())
+        .collect();
+    out.sort();
+    out.dedup();
+    out
+}
+
+/// Renders a number of seconds in a human-friendly form, e.g. "5d" or
+/// "1h 30m"
+pub fn format_duration(secs: u64) -> String {
+    if secs == 0 {
+        return "0s".to_string();
+    }
+    let parts = [
+        (secs / 86400, "d"),
+        (secs % 86400 / 3600, "h"),
+        (secs % 3600 / 60, "m"),
+        (secs % 60, "s"),
+    ];
+    parts
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, unit)| format!("{n}{unit}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Shortens `text` to at most `max_chars` characters for inclusion in a log
+/// message, replacing control characters so that they can't mess up the log
+pub fn sanitize_snippet(text: &str, max_chars: usize) -> String {
+    let mut out: String = text
+        .trim()
+        .chars()
+        .take(max_chars)
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    if text.trim().chars().count() > max_chars {
+        out.push_str("...");
+    }
     out
 }
+
+/// Whether `ip` could be held by a public DNS record. Loopback, link-local
+/// and unique local addresses only ever come from the local network or
+/// resolver.
+pub fn is_routable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast())
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_unicast_link_local()
+                || ip.is_unique_local()
+                || ip.is_multicast())
+        }
+    }
+}
+
+/// Whether this host has a route to `target`, from `bind` if given.
+/// Connecting a UDP socket sends nothing, it only looks up the route.
+pub fn has_route(target: SocketAddr, bind: Option<IpAddr>) -> bool {
+    let local = match bind {
+        Some(ip) if ip.is_ipv4() != target.is_ipv4() => return false,
+        Some(ip) => ip,
+        None if target.is_ipv4() => Ipv4Addr::UNSPECIFIED.into(),
+        None => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    UdpSocket::bind((local, 0))
+        .and_then(|socket| socket.connect(target))
+        .is_ok()
+}
+
+/// Whether the given address lists don't all hold the same set of addresses.
+/// Empty lists, i.e. hostnames that don't resolve at all, are ignored.
+pub fn addresses_diverge<'a>(
+    lists: impl IntoIterator<Item = &'a [IpAddr]>,
+) -> bool {
+    let mut sets = lists
+        .into_iter()
+        .filter(|ips| !ips.is_empty())
+        .map(|ips| ips.iter().collect::<BTreeSet<_>>());
+    let Some(first) = sets.next() else {
+        return false;
+    };
+    sets.any(|set| set != first)
+}
+
+/// Whether a failed hostname lookup may succeed if retried, e.g. after a
+/// timeout or a SERVFAIL, as opposed to the name not existing at all. The
+/// system resolver only tells these apart in its error message.
+pub fn is_temporary_resolve_error(e: &std::io::Error) -> bool {
+    if matches!(
+        e.kind(),
+        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+    ) {
+        return true;
+    }
+    let message = e.to_string().to_lowercase();
+    message.contains("temporary failure") || message.contains("try again")
+}
+
+/// Parses a SHA-256 hash written as 64 hexadecimal digits
+pub fn parse_sha256_hex(s: &str) -> Option<[u8; 32]> {
+    let s = s.trim();
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Strips trailing slashes from the path of `url`, and gives a URL without a
+/// path `default_path`. A URL that doesn't parse is returned as is.
+pub fn normalize_url(url: &str, default_path: &str) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url.trim()) else {
+        return url.to_string();
+    };
+    let path = parsed.path().trim_end_matches('/').to_string();
+    if path.is_empty() {
+        parsed.set_path(default_path);
+    } else {
+        parsed.set_path(&path);
+    }
+    parsed.to_string()
+}
+
+/// Calls `f` on every item in its own thread, at most `limit` at a time, and
+/// returns the results in the same order as `items`
+pub fn map_concurrently<T: Sync, R: Send>(
+    items: &[T],
+    limit: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let f = &f;
+    items
+        .chunks(limit.max(1))
+        .flat_map(|chunk| {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = chunk
+                    .iter()
+                    .map(|item| scope.spawn(move || f(item)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle.join().expect("worker thread panicked")
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect()
+}
+
+/// Replaces the contents of `path` so that readers never see a partially
+/// written file: the data goes to a temporary file first, which is then
+/// renamed over the original.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}